@@ -0,0 +1,111 @@
+use crate::error::{Error, Result};
+use crate::strip_banner;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+/// Lines only in `after` and only in `before`, counting repeated lines, regardless of order.
+pub fn line_changes<'a>(before: &'a str, after: &'a str) -> (Vec<&'a str>, Vec<&'a str>) {
+    let mut counts: BTreeMap<&str, i64> = BTreeMap::new();
+    for line in after.lines() {
+        *counts.entry(line).or_default() += 1;
+    }
+    for line in before.lines() {
+        *counts.entry(line).or_default() -= 1;
+    }
+    let mut added = Vec::new();
+    let mut removed = Vec::new();
+    for (line, count) in counts {
+        for _ in 0..count.abs() {
+            if count > 0 {
+                added.push(line);
+            } else {
+                removed.push(line);
+            }
+        }
+    }
+    (added, removed)
+}
+
+/// What changed from `before` to `after` (both nginx confs as written) and what triggered it:
+/// `cli`, `run` or `rollback`.
+pub fn record(
+    time: &str,
+    nginx_conf: &Path,
+    trigger: &str,
+    sources: &[String],
+    before: Option<&str>,
+    after: &str,
+) -> serde_json::Value {
+    let before = before.map(strip_banner).unwrap_or("");
+    let after = strip_banner(after);
+    let (added, removed) = line_changes(before, after);
+    let locations = |lines: &[&str]| -> Vec<String> {
+        lines
+            .iter()
+            .map(|line| line.trim())
+            .filter(|line| line.starts_with("location ") || line.starts_with("server_name "))
+            .map(|line| line.trim_end_matches(['{', ';']).trim().to_owned())
+            .collect()
+    };
+    serde_json::json!({
+        "time": time,
+        "nginx_conf": nginx_conf.display().to_string(),
+        "trigger": trigger,
+        "sources": sources,
+        "sha256": crate::conf_sha256(after),
+        "lines_added": added.len(),
+        "lines_removed": removed.len(),
+        "added": locations(&added),
+        "removed": locations(&removed),
+    })
+}
+
+/// Appends `record` as a line to `path`, or prints it for `-`.
+pub fn append(path: &Path, record: &serde_json::Value) -> Result<()> {
+    let line = format!("{}\n", record);
+    if path == Path::new("-") {
+        print!("{}", line);
+        return Ok(());
+    }
+    fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut file| file.write_all(line.as_bytes()))
+        .map_err(|err: io::Error| Error::io(path.display(), err))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn record_summarizes_changes() {
+        let before = "# sha256: x\nserver {\n    location / {\n    }\n}\n";
+        let after = "server {\n    location / {\n    }\n    location /api {\n    }\n}\n";
+        let nginx_conf = Path::new("/tmp/default.conf");
+        let added = record("now", nginx_conf, "cli", &[], Some(before), after);
+        assert_eq!(2, added["lines_added"]);
+        assert_eq!(0, added["lines_removed"]);
+        assert_eq!(serde_json::json!(["location /api"]), added["added"]);
+        assert_eq!("cli", added["trigger"]);
+        let removed = record("now", nginx_conf, "rollback", &[], Some(after), before);
+        assert_eq!(2, removed["lines_removed"]);
+        assert_eq!(serde_json::json!(["location /api"]), removed["removed"]);
+        assert_eq!(
+            6,
+            record("now", nginx_conf, "cli", &[], None, after)["lines_added"]
+        );
+    }
+
+    #[test]
+    fn append_lines() {
+        let path = std::env::temp_dir().join(format!("rp-audit-test-{}.jsonl", std::process::id()));
+        let _ = fs::remove_file(&path);
+        append(&path, &serde_json::json!({ "n": 1 })).unwrap();
+        append(&path, &serde_json::json!({ "n": 2 })).unwrap();
+        assert_eq!("{\"n\":1}\n{\"n\":2}\n", fs::read_to_string(&path).unwrap());
+    }
+}
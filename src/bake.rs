@@ -0,0 +1,143 @@
+use crate::conf::AppConfig;
+use crate::error::{Error, Result};
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+/// Where the image has the nginx conf: `nginx_conf` if absolute, or else where nginx reads
+/// a file of that name.
+pub fn image_conf_path(app_config: &AppConfig) -> PathBuf {
+    if app_config.nginx_conf.is_absolute() {
+        return app_config.nginx_conf.clone();
+    }
+    if app_config.full_conf {
+        return PathBuf::from("/etc/nginx/nginx.conf");
+    }
+    let name = app_config
+        .nginx_conf
+        .file_name()
+        .unwrap_or_else(|| "default.conf".as_ref());
+    Path::new("/etc/nginx/conf.d").join(name)
+}
+
+/// `path` inside the image relative to `/`, relative paths being under the nginx prefix.
+fn image_relative(path: &Path) -> PathBuf {
+    let base = if path.is_absolute() {
+        PathBuf::new()
+    } else {
+        PathBuf::from("etc/nginx")
+    };
+    path.components()
+        .filter(|c| matches!(c, Component::Normal(_)))
+        .fold(base, |acc, c| acc.join(c))
+}
+
+fn dockerfile(conf_path: &Path, has_files: bool) -> String {
+    let mut dockerfile = String::from("FROM nginx:alpine\n");
+    if has_files {
+        dockerfile.push_str("COPY rootfs/ /\n");
+    }
+    let name = conf_path.file_name().unwrap_or_default().to_string_lossy();
+    dockerfile.push_str(&format!("COPY {} {}\n", name, conf_path.display()));
+    dockerfile
+}
+
+/// Writes into `out` the nginx conf, a `Dockerfile` building it into `nginx:alpine`,
+/// and `rootfs/` with copies of `files` at the paths the conf refers to them by.
+/// Existing files are kept unless `force`. Returns the files written.
+pub fn bake(
+    app_config: &AppConfig,
+    conf: &str,
+    files: &[PathBuf],
+    out: &Path,
+    force: bool,
+) -> Result<Vec<PathBuf>> {
+    let conf_path = image_conf_path(app_config);
+    let conf_file = out.join(conf_path.file_name().unwrap_or_default());
+    let dockerfile_path = out.join("Dockerfile");
+    if !force {
+        if let Some(path) = [&conf_file, &dockerfile_path]
+            .iter()
+            .find(|path| path.exists())
+        {
+            return Err(Error::io(
+                path.display(),
+                io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    "exists, use --force to overwrite",
+                ),
+            ));
+        }
+    }
+    fs::create_dir_all(out).map_err(|err| Error::io(out.display(), err))?;
+    let mut written = Vec::new();
+    for file in files {
+        let dest = out.join("rootfs").join(image_relative(file));
+        if let Some(dir) = dest.parent() {
+            fs::create_dir_all(dir).map_err(|err| Error::io(dir.display(), err))?;
+        }
+        fs::copy(file, &dest).map_err(|err| Error::io(file.display(), err))?;
+        written.push(dest);
+    }
+    fs::write(&conf_file, conf).map_err(|err| Error::io(conf_file.display(), err))?;
+    written.push(conf_file);
+    fs::write(&dockerfile_path, dockerfile(&conf_path, !files.is_empty()))
+        .map_err(|err| Error::io(dockerfile_path.display(), err))?;
+    written.push(dockerfile_path);
+    Ok(written)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn bake_copies_files_to_their_paths() {
+        let dir = std::env::temp_dir().join(format!("rp-bake-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let cert = dir.join("certs").join("app.pem");
+        fs::create_dir_all(cert.parent().unwrap()).unwrap();
+        fs::write(&cert, "cert").unwrap();
+        let out = dir.join("out");
+        let app_config = AppConfig::default();
+
+        let written = bake(
+            &app_config,
+            "server {\n}\n",
+            std::slice::from_ref(&cert),
+            &out,
+            false,
+        )
+        .unwrap();
+        assert_eq!(3, written.len());
+        let copied = out.join("rootfs").join(cert.strip_prefix("/").unwrap());
+        assert_eq!("cert", fs::read_to_string(copied).unwrap());
+        assert_eq!(
+            "FROM nginx:alpine\nCOPY rootfs/ /\nCOPY default.conf /etc/nginx/conf.d/default.conf\n",
+            fs::read_to_string(out.join("Dockerfile")).unwrap()
+        );
+        assert!(bake(&app_config, "", &[], &out, false).is_err());
+        bake(&app_config, "", &[], &out, true).unwrap();
+    }
+
+    #[test]
+    fn image_conf_path_of_relative_nginx_conf() {
+        let mut app_config = AppConfig {
+            nginx_conf: "./edge.conf".into(),
+            ..Default::default()
+        };
+        assert_eq!(
+            Path::new("/etc/nginx/conf.d/edge.conf"),
+            image_conf_path(&app_config)
+        );
+        app_config.full_conf = true;
+        assert_eq!(
+            Path::new("/etc/nginx/nginx.conf"),
+            image_conf_path(&app_config)
+        );
+        assert_eq!(
+            Path::new("etc/nginx/certs/app.pem"),
+            image_relative(Path::new("./certs/app.pem"))
+        );
+    }
+}
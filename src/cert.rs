@@ -0,0 +1,119 @@
+use crate::conf::CertificatePair;
+use crate::error::{Error, Result};
+use crate::output;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CertStatus {
+    pub path: PathBuf,
+    pub subject: String,
+    pub not_after: String,
+    pub days_left: i64,
+}
+
+/// Reads the first certificate of a PEM file.
+pub fn status(path: &Path) -> Result<CertStatus> {
+    let pem = fs::read(path).map_err(|err| Error::io(path.display(), err))?;
+    let (_, pem) = x509_parser::pem::parse_x509_pem(&pem).map_err(|err| {
+        Error::parse(
+            path.display().to_string(),
+            format!("Failed to parse as PEM ({:?})", err),
+        )
+    })?;
+    let cert = pem.parse_x509().map_err(|err| {
+        Error::parse(
+            path.display().to_string(),
+            format!("Failed to parse as X.509 certificate ({:?})", err),
+        )
+    })?;
+    let not_after = cert.validity().not_after;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|err| Error::Certificate(err.to_string()))?
+        .as_secs() as i64;
+    Ok(CertStatus {
+        path: path.to_owned(),
+        subject: cert.subject().to_string(),
+        not_after: not_after.to_string(),
+        days_left: (not_after.timestamp() - now).div_euclid(24 * 60 * 60),
+    })
+}
+
+/// `<cert_dir>/<domain>.crt` and `<cert_dir>/<domain>.key`, with `*` spelled as `_`.
+pub fn dev_cert_paths(domain: &str, cert_dir: &Path) -> CertificatePair {
+    let name = domain.replace('*', "_");
+    CertificatePair {
+        certificate: cert_dir.join(format!("{}.crt", name)),
+        certificate_key: cert_dir.join(format!("{}.key", name)),
+    }
+}
+
+/// Reuses an existing pair unless `force` is set.
+pub fn generate_dev_cert(domain: &str, cert_dir: &Path, force: bool) -> Result<CertificatePair> {
+    let tls = dev_cert_paths(domain, cert_dir);
+    if !force && tls.certificate.exists() && tls.certificate_key.exists() {
+        info!("use existing certificate: {}", tls.certificate.display());
+        return Ok(tls);
+    }
+
+    let certified_key =
+        rcgen::generate_simple_self_signed(vec![domain.to_owned()]).map_err(|err| {
+            Error::Certificate(format!(
+                "Failed to generate a certificate for {}: {}",
+                domain, err
+            ))
+        })?;
+    fs::create_dir_all(cert_dir).map_err(|err| Error::io(cert_dir.display(), err))?;
+    fs::write(&tls.certificate, certified_key.cert.pem())
+        .map_err(|err| Error::io(tls.certificate.display(), err))?;
+    let key = fs::File::create(&tls.certificate_key)
+        .map_err(|err| Error::io(tls.certificate_key.display(), err))?;
+    output::set_mode(&key, 0o600)?;
+    fs::write(
+        &tls.certificate_key,
+        certified_key.signing_key.serialize_pem(),
+    )
+    .map_err(|err| Error::io(tls.certificate_key.display(), err))?;
+    info!("generate certificate: {}", tls.certificate.display());
+    Ok(tls)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn dev_cert_paths_wildcard() {
+        let tls = dev_cert_paths("*.app.localhost", Path::new("/certs"));
+        assert_eq!(PathBuf::from("/certs/_.app.localhost.crt"), tls.certificate);
+        assert_eq!(
+            PathBuf::from("/certs/_.app.localhost.key"),
+            tls.certificate_key
+        );
+    }
+
+    #[test]
+    fn generate_dev_cert_reuses_existing() {
+        let cert_dir =
+            std::env::temp_dir().join(format!("rp-dev-cert-test-{}", std::process::id()));
+        let tls = generate_dev_cert("app.localhost", &cert_dir, true).unwrap();
+        let pem = fs::read_to_string(&tls.certificate).unwrap();
+        assert!(pem.starts_with("-----BEGIN CERTIFICATE-----"));
+        generate_dev_cert("app.localhost", &cert_dir, false).unwrap();
+        assert_eq!(pem, fs::read_to_string(&tls.certificate).unwrap());
+    }
+
+    #[test]
+    fn status_of_dev_cert() {
+        let cert_dir =
+            std::env::temp_dir().join(format!("rp-cert-status-test-{}", std::process::id()));
+        let tls = generate_dev_cert("status.localhost", &cert_dir, true).unwrap();
+        let status = status(&tls.certificate).unwrap();
+        assert_eq!(tls.certificate, status.path);
+        // rcgen issues certificates valid until 4096
+        assert!(status.days_left > 365 * 1000);
+    }
+}
@@ -0,0 +1,4006 @@
+use crate::diagnostics::{self, Category};
+use crate::error::{Error, Result};
+use glob::glob;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use structopt::StructOpt;
+use url::Url;
+
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab-case")]
+pub struct Args {
+    #[structopt(
+        short,
+        long,
+        help = "listen address, such as 0.0.0.0, 127.0.0.1, 192.168.1.2"
+    )]
+    pub host: Option<String>,
+    #[structopt(short, long, help = "listen port")]
+    pub port: Option<u16>,
+    #[structopt(short, long, help = "virtual host. eg. localhost, example.com")]
+    pub domain: Option<String>,
+    #[structopt(
+        long,
+        help = "server_name without --domain, localhost by default. _ catches every host as default_server"
+    )]
+    pub default_domain: Option<String>,
+    #[structopt(
+        short = "r",
+        long,
+        raw(number_of_values = "1"),
+        parse(try_from_str = "parse_reverse_proxy_mapping"),
+        help = "eg. /path/to:http://localhost:3000/path/to, /ws:http://app:3000?websocket&strip_prefix&timeout=300"
+    )]
+    pub reverse_proxy: Vec<ReverseProxyMapping>,
+    #[structopt(
+        long,
+        help = "read more mappings from stdin, one per line, same as `-r -`"
+    )]
+    pub stdin_mappings: bool,
+    #[structopt(
+        long,
+        raw(number_of_values = "1"),
+        parse(try_from_str = "parse_subdomain_mapping"),
+        help = "a server block of its own, eg. api.example.com->http://api:3000, *.example.com->http://web:3000"
+    )]
+    pub subdomain: Vec<SubdomainMapping>,
+    #[structopt(
+        long,
+        help = "a URL to which requests matching no path are proxied, unless a mapping of / exists"
+    )]
+    pub default_backend: Option<Url>,
+    #[structopt(
+        long,
+        parse(from_os_str),
+        help = "a nginx conf file path to which this will write out"
+    )]
+    pub nginx_conf: Option<PathBuf>,
+    #[structopt(
+        long,
+        default_value = "/conf",
+        parse(from_str = "parse_path_without_trailing_slash")
+    )]
+    pub config_dir: PathBuf,
+    #[structopt(
+        long,
+        raw(number_of_values = "1"),
+        parse(from_os_str),
+        help = "a config file loaded after the files in --config-dir, overriding them. repeatable"
+    )]
+    pub config_file: Vec<PathBuf>,
+    #[structopt(
+        long,
+        help = "a profile whose `profiles.<name>` section in config files overrides the rest"
+    )]
+    pub profile: Option<String>,
+    #[structopt(
+        long,
+        help = "a command to run after writing out, with RP_CONF_PATH and RP_CONF_STATUS (changed or unchanged)"
+    )]
+    pub on_change: Option<String>,
+    #[structopt(
+        long,
+        help = "an http URL which must answer 2xx or 3xx after --on-change reloaded nginx, or else the previous nginx conf is restored"
+    )]
+    pub reload_check: Option<Url>,
+    #[structopt(
+        long,
+        help = "keeps the last n nginx confs written in <nginx-conf>.history/ for rollback"
+    )]
+    pub history: Option<usize>,
+    #[structopt(
+        long,
+        parse(from_os_str),
+        help = "a file to which a JSON line is appended whenever the nginx conf changes, - for stdout"
+    )]
+    pub audit_log: Option<PathBuf>,
+    #[structopt(
+        long,
+        help = "a webhook to which what changed and how the reload went is POSTed whenever the nginx conf changes"
+    )]
+    pub notify_url: Option<Url>,
+    #[structopt(
+        long,
+        raw(possible_values = r#"&["json", "slack"]"#),
+        help = "the payload for --notify-url: json, or slack for an incoming webhook [default: json]"
+    )]
+    pub notify_format: Option<NotifyFormat>,
+    #[structopt(
+        long,
+        default_value = "10",
+        help = "seconds to wait for another run writing the same nginx conf, 0 to fail immediately"
+    )]
+    pub lock_timeout: u64,
+    #[structopt(
+        long,
+        help = "exit with this code instead of 0 when the nginx conf was changed. eg. 100"
+    )]
+    pub changed_exit_code: Option<i32>,
+    #[structopt(
+        long,
+        help = "fail on warnings, such as upstreams of localhost or missing certificate files"
+    )]
+    pub strict: bool,
+    #[structopt(
+        long,
+        parse(try_from_str = "parse_conf_mode"),
+        help = "octal permission bits of the nginx conf. eg. 0640"
+    )]
+    pub conf_mode: Option<u32>,
+    #[structopt(long, help = "owner of the nginx conf, a user name or uid")]
+    pub conf_owner: Option<String>,
+    #[structopt(long, help = "group of the nginx conf, a group name or gid")]
+    pub conf_group: Option<String>,
+    #[structopt(
+        long,
+        help = "proxy to host.docker.internal instead of localhost, which is the nginx container itself"
+    )]
+    pub rewrite_localhost: bool,
+    #[structopt(
+        long,
+        help = "the container of nginx, warned of if not attached to the networks of upstreams"
+    )]
+    pub nginx_container: Option<String>,
+    #[structopt(
+        long,
+        help = "nginx is OpenResty, allowing access_by_lua and content_by_lua in mappings"
+    )]
+    pub openresty: bool,
+    #[structopt(
+        long,
+        help = "write out a whole nginx.conf with events and http blocks, instead of a conf.d file"
+    )]
+    pub full_conf: bool,
+    #[structopt(
+        long,
+        help = "rewrite only between `# BEGIN docker-rp` and `# END docker-rp` of the nginx conf, keeping the rest"
+    )]
+    pub managed_region: bool,
+    #[structopt(
+        long,
+        help = "also write out <nginx conf>.map.json telling where each server block and location came from"
+    )]
+    pub source_map: bool,
+    #[structopt(
+        long,
+        help = "create the directories of the nginx conf if they don't exist, eg. in a fresh volume"
+    )]
+    pub mkdirs: bool,
+    #[structopt(
+        long,
+        parse(from_os_str),
+        help = "also write out the servers, locations and upstreams to be rendered, as YAML for .yaml/.yml or else JSON"
+    )]
+    pub emit_model: Option<PathBuf>,
+    #[structopt(
+        long,
+        help = "copy the nginx conf by scp when it changed, eg. ssh://deploy@edge-1:/etc/nginx/conf.d/default.conf"
+    )]
+    pub deploy: Option<crate::deploy::SshTarget>,
+    #[structopt(
+        long,
+        default_value = "nginx -t && nginx -s reload",
+        help = "a command run over ssh after --deploy copied the nginx conf"
+    )]
+    pub deploy_reload: String,
+    #[structopt(
+        long,
+        default_value = "nginx",
+        raw(possible_values = r#"&["nginx", "k8s-configmap"]"#),
+        help = "nginx, or k8s-configmap to write out a ConfigMap manifest holding the nginx conf"
+    )]
+    pub format: OutputFormat,
+    #[structopt(long, default_value = "nginx-conf", help = "the name of the ConfigMap")]
+    pub configmap_name: String,
+    #[structopt(long, help = "the namespace of the ConfigMap")]
+    pub configmap_namespace: Option<String>,
+    #[structopt(
+        long,
+        default_value = "text",
+        raw(possible_values = r#"&["text", "json"]"#),
+        help = "format of the logs of this command: text, or json with one object per line"
+    )]
+    pub log_format: crate::logging::LogFormat,
+    #[structopt(flatten)]
+    pub verbose: clap_verbosity_flag::Verbosity,
+    #[structopt(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab-case")]
+pub enum Command {
+    #[structopt(
+        name = "dev-cert",
+        about = "Generates a self-signed certificate for local development and writes out the nginx conf using it"
+    )]
+    DevCert {
+        #[structopt(help = "eg. app.localhost, *.app.localhost")]
+        domain: String,
+        #[structopt(
+            long,
+            default_value = "/certs",
+            parse(from_os_str),
+            help = "a directory to which the certificate and key will be written"
+        )]
+        cert_dir: PathBuf,
+        #[structopt(long, help = "regenerate the certificate even if it already exists")]
+        force: bool,
+    },
+    #[structopt(name = "cert", about = "Inspects the configured certificates")]
+    Cert {
+        #[structopt(subcommand)]
+        command: CertCommand,
+    },
+    #[structopt(
+        name = "import",
+        about = "Converts the proxying locations of an existing nginx conf into a config file in --config-dir"
+    )]
+    Import {
+        #[structopt(parse(from_os_str))]
+        nginx_conf: PathBuf,
+        #[structopt(
+            long,
+            default_value = "imported.yaml",
+            help = "the file name in --config-dir"
+        )]
+        name: String,
+        #[structopt(long, help = "overwrite the file if it exists")]
+        force: bool,
+    },
+    #[structopt(
+        name = "auth",
+        about = "Manages users of an htpasswd file for basic_auth"
+    )]
+    Auth {
+        #[structopt(subcommand)]
+        command: AuthCommand,
+    },
+    #[structopt(
+        name = "tf-external",
+        about = "Renders for a Terraform external data source, reading the query from stdin and printing the conf and its sha256"
+    )]
+    TfExternal,
+    #[structopt(
+        name = "verify",
+        about = "Renders without writing and fails if the nginx conf differs from a golden file"
+    )]
+    Verify {
+        #[structopt(long, parse(from_os_str), help = "the golden nginx conf")]
+        against: PathBuf,
+    },
+    #[structopt(
+        name = "list",
+        about = "Prints the domain, path, upstream, options and source of every location"
+    )]
+    List {
+        #[structopt(
+            long,
+            default_value = "table",
+            raw(possible_values = r#"&["table", "json"]"#),
+            help = "an aligned table, or json"
+        )]
+        output: String,
+    },
+    #[structopt(
+        name = "compose",
+        about = "Prints a docker-compose file running nginx with this as a sidecar writing its conf"
+    )]
+    Compose,
+    #[structopt(
+        name = "bake",
+        about = "Writes the nginx conf, the certificates it refers to and a Dockerfile building them into nginx:alpine"
+    )]
+    Bake {
+        #[structopt(long, default_value = "./bake", parse(from_os_str))]
+        out: PathBuf,
+        #[structopt(long, help = "overwrite the files if they exist")]
+        force: bool,
+    },
+    #[structopt(
+        name = "run",
+        about = "Writes the nginx conf and runs nginx in the foreground, reloading it when the config files change, to be PID 1 of a container"
+    )]
+    Run {
+        #[structopt(long, default_value = "nginx", help = "the nginx binary")]
+        nginx: String,
+        #[structopt(
+            long,
+            help = "an address such as 127.0.0.1:9113 on which /metrics is served"
+        )]
+        admin: Option<String>,
+        #[structopt(
+            long,
+            default_value = "2",
+            help = "seconds between checks of the config files for changes"
+        )]
+        interval: u64,
+        #[structopt(
+            long,
+            help = "seconds after which the config is loaded again even if unchanged, to look up srv:// and resolve: docker again"
+        )]
+        refresh: Option<u64>,
+        #[structopt(
+            long,
+            parse(try_from_str = "humantime::parse_duration"),
+            help = "how long the config files must stay unchanged before they are loaded, such as 500ms, so that a burst of changes gives one reload"
+        )]
+        debounce: Option<Duration>,
+    },
+    #[structopt(
+        name = "rollback",
+        about = "Writes back a nginx conf kept by --history, the one before the last unless --to, and runs --on-change"
+    )]
+    Rollback {
+        #[structopt(long, help = "the id of the kept nginx conf, see --list")]
+        to: Option<u64>,
+        #[structopt(long, help = "print the kept nginx confs instead")]
+        list: bool,
+    },
+    #[structopt(
+        name = "add",
+        about = "Adds a mapping to a config file, or replaces the one of the same path, and writes out the nginx conf"
+    )]
+    Add {
+        path: String,
+        url: String,
+        #[structopt(long)]
+        websocket: bool,
+        #[structopt(long)]
+        strip_prefix: bool,
+        #[structopt(
+            short = "o",
+            long = "option",
+            raw(number_of_values = "1"),
+            help = "a mapping option, eg. timeout=300. repeatable"
+        )]
+        options: Vec<String>,
+        #[structopt(
+            long,
+            parse(from_os_str),
+            help = "the config file, default: <config dir>/sites/managed.yaml"
+        )]
+        file: Option<PathBuf>,
+    },
+    #[structopt(
+        name = "remove",
+        about = "Removes the mapping of a path from a config file and writes out the nginx conf"
+    )]
+    Remove {
+        path: String,
+        #[structopt(
+            long,
+            parse(from_os_str),
+            help = "the config file, default: <config dir>/sites/managed.yaml"
+        )]
+        file: Option<PathBuf>,
+    },
+    #[structopt(
+        name = "init",
+        about = "Creates --config-dir with commented example config files"
+    )]
+    Init {
+        #[structopt(long, help = "write TOML instead of YAML")]
+        toml: bool,
+        #[structopt(
+            long,
+            help = "also print a docker-compose service mounting --config-dir"
+        )]
+        compose: bool,
+        #[structopt(long, help = "overwrite the files if they exist")]
+        force: bool,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab-case")]
+pub enum AuthCommand {
+    #[structopt(
+        name = "add-user",
+        about = "Adds a user, or changes their password, with the password read from stdin"
+    )]
+    AddUser {
+        user: String,
+        #[structopt(long, parse(from_os_str), help = "htpasswd file, created if missing")]
+        file: PathBuf,
+    },
+    #[structopt(name = "remove-user", about = "Removes a user")]
+    RemoveUser {
+        user: String,
+        #[structopt(long, parse(from_os_str), help = "htpasswd file")]
+        file: PathBuf,
+    },
+    #[structopt(name = "list-users", about = "Prints the users")]
+    ListUsers {
+        #[structopt(long, parse(from_os_str), help = "htpasswd file")]
+        file: PathBuf,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab-case")]
+pub enum CertCommand {
+    #[structopt(
+        name = "status",
+        about = "Prints the expiry of the configured certificates, failing if any expires soon"
+    )]
+    Status {
+        #[structopt(
+            long,
+            default_value = "30",
+            help = "fail if a certificate expires within this many days"
+        )]
+        warn_days: i64,
+    },
+}
+
+impl Args {
+    pub fn from_args() -> Args {
+        <Args as StructOpt>::from_args()
+    }
+
+    /// Overrides the options by the query of a Terraform external data source,
+    /// whose values are all strings. `reverse_proxy` has one mapping per line.
+    pub fn apply_query(&mut self, query: BTreeMap<String, String>) -> Result<()> {
+        for (key, value) in query {
+            match key.as_str() {
+                "config_dir" => self.config_dir = parse_path_without_trailing_slash(&value),
+                "config_file" => self.config_file.push(PathBuf::from(value)),
+                "profile" => self.profile = Some(value),
+                "host" => self.host = Some(value),
+                "port" => {
+                    self.port = Some(value.parse().map_err(|err| {
+                        Error::parse(&value, format!("Failed to parse as port ({})", err))
+                    })?)
+                }
+                "domain" => self.domain = Some(value),
+                "reverse_proxy" => {
+                    for line in value.lines().filter(|line| !line.trim().is_empty()) {
+                        self.reverse_proxy
+                            .push(parse_reverse_proxy_mapping(line.trim())?);
+                    }
+                }
+                _ => return Err(Error::parse(key, "unknown query key")),
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether `-r -` or `--stdin-mappings` is given.
+    pub fn reads_stdin(&self) -> bool {
+        self.stdin_mappings
+            || self
+                .reverse_proxy
+                .iter()
+                .any(|rp| rp.is_stdin_placeholder())
+    }
+
+    /// Replaces `-r -` by the mappings in `reader`, one per line, skipping blank lines and
+    /// `#` comments. Does nothing unless `-r -` or `--stdin-mappings` is given.
+    pub fn read_stdin_mappings<R: io::BufRead>(&mut self, reader: R) -> Result<()> {
+        let given = self.reverse_proxy.len();
+        self.reverse_proxy.retain(|rp| !rp.is_stdin_placeholder());
+        if !self.stdin_mappings && given == self.reverse_proxy.len() {
+            return Ok(());
+        }
+        for line in reader.lines() {
+            let line = line.map_err(|err| Error::io("stdin", err))?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            self.reverse_proxy.push(ReverseProxyMapping::parse(line)?);
+        }
+        Ok(())
+    }
+}
+
+pub fn parse_reverse_proxy_mapping(s: &str) -> Result<ReverseProxyMapping> {
+    if s == "-" {
+        // replaced by `Args::read_stdin_mappings`
+        return Ok(ReverseProxyMapping::new(
+            "-".into(),
+            Url::parse("stdin:-").expect("valid URL"),
+        ));
+    }
+    ReverseProxyMapping::parse(s)
+}
+
+pub fn parse_subdomain_mapping(s: &str) -> Result<SubdomainMapping> {
+    let rp = ReverseProxyMapping::parse(s)?;
+    Ok(SubdomainMapping {
+        domain: rp.path.clone(),
+        mapping: ReverseProxyMapping {
+            path: "/".into(),
+            ..rp
+        },
+    })
+}
+
+pub fn parse_conf_mode(s: &str) -> Result<u32> {
+    let mode = u32::from_str_radix(s.trim_start_matches("0o"), 8)
+        .map_err(|err| Error::parse(s, format!("Failed to parse as octal mode ({})", err)))?;
+    if mode > 0o7777 {
+        return Err(Error::parse(s, "mode out of range"));
+    }
+    Ok(mode)
+}
+
+pub fn parse_path_without_trailing_slash(s: &str) -> PathBuf {
+    PathBuf::from(s.trim_end_matches("/"))
+}
+
+#[derive(Debug, Clone, Ord, PartialOrd, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ReverseProxyMapping {
+    #[serde(default)]
+    pub path: String,
+    /// More paths proxied to the same `url` with the same options, see `expand_paths`
+    #[serde(default)]
+    pub paths: Vec<String>,
+    #[serde(with = "url_serde")]
+    pub url: Url,
+    /// A client certificate presented to the upstream
+    pub proxy_ssl_certificate: Option<PathBuf>,
+    pub proxy_ssl_certificate_key: Option<PathBuf>,
+    /// CA certificates to verify the upstream certificate with
+    pub proxy_ssl_trusted_certificate: Option<PathBuf>,
+    pub proxy_ssl_verify: Option<bool>,
+    #[serde(default)]
+    pub proxy_ssl_protocols: Vec<String>,
+    /// Rejects requests without a verified client certificate with 403
+    #[serde(default)]
+    pub require_client_certificate: bool,
+    /// Server-sent events: no buffering and long read timeouts
+    #[serde(default)]
+    pub sse: bool,
+    /// Passes `Upgrade`/`Connection` for WebSocket upgrades
+    #[serde(default)]
+    pub websocket: bool,
+    /// Removes `path` from the request URI before appending it to the path of `url`
+    #[serde(default)]
+    pub strip_prefix: bool,
+    /// `proxy_read_timeout`/`proxy_send_timeout` in seconds
+    pub timeout: Option<u64>,
+    /// Body size, timeout and buffering directives for a common pattern, see `Preset`
+    pub preset: Option<Preset>,
+    /// `Host` sent to the upstream instead of the one of the request
+    pub upstream_host: Option<String>,
+    /// How the request path is joined to `url`
+    pub pass_mode: Option<PassMode>,
+    /// `proxy_next_upstream_tries`
+    pub retries: Option<u32>,
+    /// `proxy_next_upstream` conditions, eg. `error`, `timeout`, `http_502`
+    #[serde(default)]
+    pub retry_on: Vec<String>,
+    /// `proxy_next_upstream_timeout` in seconds
+    pub retry_timeout: Option<u64>,
+    /// Sends a share of clients to another upstream, chosen by `split_clients`
+    pub canary: Option<Canary>,
+    /// Takes requests with this header from the mapping of the same path without one,
+    /// eg. `X-Tenant: acme`; `~` starts a regular expression of the value
+    pub match_header: Option<String>,
+    /// As `match_header` for a cookie, eg. `version=v2`
+    pub match_cookie: Option<String>,
+    /// The mappings of the same path with `match_header` or `match_cookie`, checked in order
+    /// before this one, folded into it while loading
+    #[serde(skip)]
+    pub routes: Vec<ReverseProxyMapping>,
+    /// `proxy_set_header`s of this location, besides the default ones
+    #[serde(default)]
+    pub proxy_headers: BTreeMap<String, String>,
+    /// `add_header ... always` to the responses of this location
+    #[serde(default)]
+    pub add_headers: BTreeMap<String, String>,
+    /// Replacements in response bodies, eg. of absolute URLs when mounted under a prefix
+    #[serde(default)]
+    pub sub_filters: Vec<SubFilter>,
+    /// MIME types `sub_filters` apply to besides `text/html`
+    #[serde(default)]
+    pub sub_filter_types: Vec<String>,
+    /// `proxy_redirect`s rewriting `Location` and `Refresh` headers of responses
+    #[serde(default)]
+    pub redirect_rewrites: Vec<Rewrite>,
+    /// `proxy_cookie_path`s rewriting the path of `Set-Cookie` headers
+    #[serde(default)]
+    pub cookie_paths: Vec<Rewrite>,
+    /// `proxy_cookie_domain`s rewriting the domain of `Set-Cookie` headers
+    #[serde(default)]
+    pub cookie_domains: Vec<Rewrite>,
+    /// Files under this root are served first, falling back to `url`, eg. collected assets
+    #[serde(default)]
+    pub root: Option<PathBuf>,
+    /// `false` turns off the access log, eg. for health checks of load balancers
+    #[serde(default = "default_access_log")]
+    pub access_log: bool,
+    /// A name of `log.formats` the location is access logged in, instead of `log.format`
+    #[serde(default)]
+    pub log_format: Option<String>,
+    /// An htpasswd file required by `auth_basic`, see the `auth` subcommand
+    #[serde(default)]
+    pub basic_auth: Option<PathBuf>,
+    /// A header with a token requests need: 401 without it, 403 with another value
+    #[serde(default)]
+    pub require_header: Option<RequireHeader>,
+    /// Other methods get 405; `GET` allows `HEAD` too
+    #[serde(default)]
+    pub allowed_methods: Vec<String>,
+    /// Lua run in `access_by_lua_block`, needs `openresty`
+    #[serde(default)]
+    pub access_by_lua: Option<String>,
+    /// Lua serving the location instead of `url`, needs `openresty`
+    #[serde(default)]
+    pub content_by_lua: Option<String>,
+    /// njs function serving the location instead of `url`, eg. `main.hello`
+    #[serde(default)]
+    pub js_content: Option<String>,
+    /// njs function for `js_header_filter`
+    #[serde(default)]
+    pub js_header_filter: Option<String>,
+    /// A copy of each request is sent here and its response discarded
+    #[serde(default, with = "url_serde")]
+    pub mirror_url: Option<Url>,
+    /// Replaces the host of `url` with an address looked up when loading the config
+    pub resolve: Option<Resolve>,
+    /// The docker network the upstream is reached on, whose address `resolve: docker` takes
+    pub network: Option<String>,
+    /// Where the mapping was defined, commented in the nginx conf
+    #[serde(skip)]
+    pub source: Option<String>,
+}
+
+impl ReverseProxyMapping {
+    /// The options differing from the defaults, by their config keys.
+    pub fn changed_options(&self) -> serde_json::Map<String, serde_json::Value> {
+        let defaults = serde_json::to_value(ReverseProxyMapping::new(
+            self.path.clone(),
+            self.url.clone(),
+        ))
+        .unwrap_or_default();
+        match serde_json::to_value(self) {
+            Ok(serde_json::Value::Object(options)) => options
+                .into_iter()
+                .filter(|(key, value)| defaults.get(key) != Some(value))
+                .collect(),
+            _ => serde_json::Map::new(),
+        }
+    }
+
+    fn is_stdin_placeholder(&self) -> bool {
+        self.path == "-" && self.url.scheme() == "stdin"
+    }
+
+    pub fn new(path: String, url: Url) -> ReverseProxyMapping {
+        ReverseProxyMapping {
+            path,
+            paths: Vec::new(),
+            url,
+            proxy_ssl_certificate: None,
+            proxy_ssl_certificate_key: None,
+            proxy_ssl_trusted_certificate: None,
+            proxy_ssl_verify: None,
+            proxy_ssl_protocols: Vec::new(),
+            require_client_certificate: false,
+            sse: false,
+            websocket: false,
+            strip_prefix: false,
+            timeout: None,
+            upstream_host: None,
+            preset: None,
+            pass_mode: None,
+            retries: None,
+            retry_on: Vec::new(),
+            retry_timeout: None,
+            canary: None,
+            match_header: None,
+            match_cookie: None,
+            routes: Vec::new(),
+            proxy_headers: BTreeMap::new(),
+            add_headers: BTreeMap::new(),
+            sub_filters: Vec::new(),
+            sub_filter_types: Vec::new(),
+            redirect_rewrites: Vec::new(),
+            cookie_paths: Vec::new(),
+            cookie_domains: Vec::new(),
+            root: None,
+            access_log: true,
+            log_format: None,
+            basic_auth: None,
+            require_header: None,
+            allowed_methods: Vec::new(),
+            access_by_lua: None,
+            content_by_lua: None,
+            js_content: None,
+            js_header_filter: None,
+            mirror_url: None,
+            resolve: None,
+            network: None,
+            source: None,
+        }
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.path.is_empty() {
+            return Err(Error::Render(format!(
+                "path or paths is required: {}",
+                self.url
+            )));
+        }
+        if !self.path.starts_with('/') || self.path.contains(breaks_directive) {
+            return Err(Error::parse(
+                    self.path.as_str(),
+                    format!(
+                        "a path must start with / and have no whitespace, quotes, semicolons or braces, in {}",
+                        self.source.as_deref().unwrap_or("a mapping")
+                    ),
+                ));
+        }
+        check_scheme(&self.url)?;
+        check_literal("url", self.url.as_str())?;
+        if let Some(ref host) = self.upstream_host {
+            check_literal("upstream_host", host)?;
+        }
+        for name in self.proxy_headers.keys().chain(self.add_headers.keys()) {
+            check_literal("header name", name)?;
+        }
+        for protocol in &self.proxy_ssl_protocols {
+            check_literal("proxy_ssl_protocols", protocol)?;
+        }
+        for mime_type in &self.sub_filter_types {
+            check_literal("sub_filter_types", mime_type)?;
+        }
+        for function in self.js_content.iter().chain(&self.js_header_filter) {
+            check_literal("njs function", function)?;
+        }
+        for path in self
+            .root
+            .iter()
+            .chain(&self.basic_auth)
+            .chain(&self.proxy_ssl_certificate)
+            .chain(&self.proxy_ssl_certificate_key)
+            .chain(&self.proxy_ssl_trusted_certificate)
+        {
+            check_literal("file path", &path.to_string_lossy())?;
+        }
+        if let Some(ref canary) = self.canary {
+            check_literal("canary url", canary.url.as_str())?;
+        }
+        if let Some(ref mirror_url) = self.mirror_url {
+            check_literal("mirror_url", mirror_url.as_str())?;
+        }
+        if let Some(ref require_header) = self.require_header {
+            if require_header.name.is_empty()
+                || !require_header
+                    .name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            {
+                return Err(Error::parse(
+                    require_header.name.as_str(),
+                    "require_header needs a header name, eg. X-Webhook-Token",
+                ));
+            }
+            if require_header.value.as_deref() == Some("")
+                || require_header.value.is_none() && require_header.secret_file.is_none()
+            {
+                return Err(Error::parse(
+                    require_header.name.as_str(),
+                    "require_header needs a non-empty value or secret_file",
+                ));
+            }
+        }
+        if self.match_header.is_some() || self.match_cookie.is_some() {
+            let (variable, value) = self
+                .route_match()
+                .filter(|(variable, value)| {
+                    !value.is_empty()
+                        && variable[1..]
+                            .chars()
+                            .all(|c| c.is_ascii_alphanumeric() || c == '_')
+                })
+                .ok_or_else(|| {
+                    Error::parse(
+                        self.match_header
+                            .as_deref()
+                            .or(self.match_cookie.as_deref())
+                            .unwrap_or_default(),
+                        "match_header is like `X-Tenant: acme` and match_cookie like `version=v2`",
+                    )
+                })?;
+            check_literal("match value", &value)?;
+            if self.match_header.is_some() && self.match_cookie.is_some() {
+                return Err(Error::parse(
+                    variable.as_str(),
+                    "match_header and match_cookie can't be both in one mapping",
+                ));
+            }
+        }
+        if (!self.routes.is_empty() || self.match_header.is_some() || self.match_cookie.is_some())
+            && self.canary.is_some()
+        {
+            return Err(Error::Render(format!(
+                "canary can't be with match_header or match_cookie: {}",
+                self.path
+            )));
+        }
+        for route in &self.routes {
+            route.validate()?;
+        }
+        if let Some(ref canary) = self.canary {
+            if !(1..=100).contains(&canary.percentage) {
+                return Err(Error::Render(format!(
+                    "canary percentage must be 1 to 100: {}",
+                    self.path
+                )));
+            }
+            if canary.url.scheme() != self.url.scheme() || canary.url.path() != self.url.path() {
+                return Err(Error::Render(format!(
+                    "canary url must have the same scheme and path as url: {} -> {}",
+                    self.path, canary.url
+                )));
+            }
+        }
+        if let Some(method) = self
+            .allowed_methods
+            .iter()
+            .find(|method| method.is_empty() || !method.chars().all(|c| c.is_ascii_uppercase()))
+        {
+            return Err(Error::parse(
+                method.as_str(),
+                format!("invalid method in allowed_methods of {}", self.path),
+            ));
+        }
+        if self
+            .sub_filters
+            .iter()
+            .any(|filter| filter.pattern.is_empty())
+        {
+            return Err(Error::parse(
+                self.path.as_str(),
+                "sub_filters need a non-empty pattern",
+            ));
+        }
+        if self.root.is_some()
+            && (self.basic_auth.is_some()
+                || self.require_client_certificate
+                || !self.allowed_methods.is_empty())
+        {
+            // they would guard the backend but not the files
+            return Err(Error::Render(format!(
+                    "root can't be used with basic_auth, require_client_certificate or allowed_methods: {}",
+                    self.path
+                )));
+        }
+        if let Some(ref mirror_url) = self.mirror_url {
+            if mirror_url.path() != "/" || mirror_url.query().is_some() {
+                return Err(Error::Render(format!(
+                    "mirror_url takes no path, the request URI is sent as it is: {} -> {}",
+                    self.path, mirror_url
+                )));
+            }
+        }
+        if let Some(condition) = self
+            .retry_on
+            .iter()
+            .find(|condition| !RETRY_CONDITIONS.contains(&condition.as_str()))
+        {
+            return Err(Error::parse(
+                condition.as_str(),
+                format!("unknown retry_on condition in {}", self.path),
+            ));
+        }
+        if self.proxy_ssl_certificate.is_some() != self.proxy_ssl_certificate_key.is_some() {
+            return Err(Error::Render(format!(
+                "proxy_ssl_certificate and proxy_ssl_certificate_key must be set together: {}",
+                self.path
+            )));
+        }
+        if self.url.scheme() != "https"
+            && (self.proxy_ssl_certificate.is_some()
+                || self.proxy_ssl_trusted_certificate.is_some()
+                || self.proxy_ssl_verify.is_some()
+                || !self.proxy_ssl_protocols.is_empty())
+        {
+            diagnostics::warn(
+                Category::Mapping,
+                format!(
+                    "proxy_ssl_* options have no effect on a non-https upstream: {} -> {}",
+                    self.path, self.url
+                ),
+            );
+        }
+        match self.pass_mode {
+            _ if self.strip_prefix && self.pass_mode.is_some() => diagnostics::warn(
+                Category::Mapping,
+                format!(
+                    "pass_mode is ignored with strip_prefix: {} -> {}",
+                    self.path, self.url
+                ),
+            ),
+            Some(PassMode::KeepPath) if self.url.path() != "/" => diagnostics::warn(
+                Category::Mapping,
+                format!(
+                    "the path of url is ignored with pass_mode keep-path: {} -> {}",
+                    self.path, self.url
+                ),
+            ),
+            Some(PassMode::ReplacePath) | None
+                if !self.strip_prefix
+                    && self.path.ends_with('/') != self.url.path().ends_with('/') =>
+            {
+                diagnostics::warn(
+                    Category::Mapping,
+                    format!(
+                        "only one of path and url ends with '/', so {}x is proxied to {}x; \
+                             consider pass_mode append-slash: {} -> {}",
+                        self.path,
+                        self.url.path(),
+                        self.path,
+                        self.url
+                    ),
+                )
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    pub fn parse(s: &str) -> Result<ReverseProxyMapping> {
+        let (path, url) = split_mapping(s)?;
+        let (url, options) = match url.find('?') {
+            Some(i) => (&url[..i], Some(&url[i + 1..])),
+            None => (url, None),
+        };
+        let mut rp = ReverseProxyMapping::new(
+            path.into(),
+            Url::parse(url)
+                .map_err(|err| Error::parse(url, format!("Failed to parse as URL ({})", err)))?,
+        );
+        for option in options.into_iter().flat_map(|options| options.split('&')) {
+            if option.is_empty() {
+                continue;
+            }
+            let (key, value) = match option.find('=') {
+                Some(i) => (&option[..i], Some(&option[i + 1..])),
+                None => (option, None),
+            };
+            rp.set_option(key, value).map_err(|err| {
+                Error::parse(s, format!("Invalid mapping option '{}' ({})", option, err))
+            })?;
+        }
+        Ok(rp)
+    }
+
+    /// One mapping per path of `path` and `paths`, each rendered as its own location.
+    pub fn expand_paths(self) -> Vec<ReverseProxyMapping> {
+        if self.paths.is_empty() {
+            return vec![self];
+        }
+        let paths = if self.path.is_empty() {
+            self.paths.clone()
+        } else {
+            std::iter::once(self.path.clone())
+                .chain(self.paths.iter().cloned())
+                .collect()
+        };
+        paths
+            .into_iter()
+            .map(|path| ReverseProxyMapping {
+                path,
+                paths: Vec::new(),
+                ..self.clone()
+            })
+            .collect()
+    }
+
+    /// The nginx variable and the value of `match_header` or `match_cookie`,
+    /// eg. `$http_x_tenant` and `acme`.
+    pub fn route_match(&self) -> Option<(String, String)> {
+        if let Some(ref header) = self.match_header {
+            let (name, value) = header.split_once(':')?;
+            let variable = name.trim().to_ascii_lowercase().replace('-', "_");
+            return Some((format!("$http_{}", variable), value.trim().to_owned()));
+        }
+        let (name, value) = self.match_cookie.as_ref()?.split_once('=')?;
+        Some((format!("$cookie_{}", name.trim()), value.trim().to_owned()))
+    }
+
+    fn require_header_mut(&mut self) -> &mut RequireHeader {
+        self.require_header
+            .get_or_insert_with(RequireHeader::default)
+    }
+
+    fn canary_mut(&mut self) -> &mut Canary {
+        let url = &self.url;
+        self.canary.get_or_insert_with(|| Canary {
+            url: url.clone(),
+            percentage: 0,
+        })
+    }
+
+    /// Sets a field from a `-r` option. Flags without a value mean `true`.
+    pub fn set_option(&mut self, key: &str, value: Option<&str>) -> Result<()> {
+        fn flag(value: Option<&str>) -> Result<bool> {
+            match value {
+                None | Some("true") | Some("on") => Ok(true),
+                Some("false") | Some("off") => Ok(false),
+                Some(value) => Err(Error::parse(value, "expected true or false")),
+            }
+        }
+        fn required<'a>(key: &str, value: Option<&'a str>) -> Result<&'a str> {
+            value
+                .filter(|value| !value.is_empty())
+                .ok_or_else(|| Error::parse(key, "missing value"))
+        }
+
+        match key {
+            "websocket" => self.websocket = flag(value)?,
+            "strip_prefix" => self.strip_prefix = flag(value)?,
+            "sse" => self.sse = flag(value)?,
+            "require_client_certificate" => self.require_client_certificate = flag(value)?,
+            "timeout" => {
+                let value = required(key, value)?;
+                self.timeout = Some(value.parse().map_err(|err| Error::parse(value, err))?)
+            }
+            "proxy_ssl_verify" => self.proxy_ssl_verify = Some(flag(value)?),
+            "upstream_host" => self.upstream_host = Some(required(key, value)?.into()),
+            "pass_mode" => self.pass_mode = Some(required(key, value)?.parse()?),
+            "preset" => self.preset = Some(required(key, value)?.parse()?),
+            "retries" => {
+                let value = required(key, value)?;
+                self.retries = Some(value.parse().map_err(|err| Error::parse(value, err))?)
+            }
+            "retry_on" => {
+                self.retry_on = required(key, value)?.split(',').map(String::from).collect()
+            }
+            "retry_timeout" => {
+                let value = required(key, value)?;
+                self.retry_timeout = Some(value.parse().map_err(|err| Error::parse(value, err))?)
+            }
+            "require_header" => self.require_header_mut().name = required(key, value)?.into(),
+            "require_header_value" => {
+                self.require_header_mut().value = Some(required(key, value)?.into())
+            }
+            "require_header_file" => {
+                self.require_header_mut().secret_file = Some(required(key, value)?.into())
+            }
+            "canary_url" => {
+                let value = required(key, value)?;
+                let url = Url::parse(value).map_err(|err| Error::parse(value, err))?;
+                self.canary_mut().url = url;
+            }
+            "sub_filter_types" => {
+                self.sub_filter_types = required(key, value)?.split(',').map(String::from).collect()
+            }
+            "access_log" => self.access_log = flag(value)?,
+            "log_format" => self.log_format = Some(required(key, value)?.into()),
+            "root" => self.root = Some(PathBuf::from(required(key, value)?)),
+            "basic_auth" => self.basic_auth = Some(PathBuf::from(required(key, value)?)),
+            "allowed_methods" => {
+                self.allowed_methods = required(key, value)?
+                    .split(',')
+                    .map(|method| method.to_ascii_uppercase())
+                    .collect()
+            }
+            "mirror_url" => {
+                let value = required(key, value)?;
+                self.mirror_url = Some(Url::parse(value).map_err(|err| Error::parse(value, err))?);
+            }
+            "match_header" => self.match_header = Some(required(key, value)?.into()),
+            "match_cookie" => self.match_cookie = Some(required(key, value)?.into()),
+            "resolve" => self.resolve = Some(required(key, value)?.parse()?),
+            "network" => self.network = Some(required(key, value)?.into()),
+            "canary_percentage" => {
+                let value = required(key, value)?;
+                self.canary_mut().percentage =
+                    value.parse().map_err(|err| Error::parse(value, err))?;
+            }
+            "paths" => self.paths = required(key, value)?.split(',').map(String::from).collect(),
+            "proxy_ssl_certificate" => {
+                self.proxy_ssl_certificate = Some(PathBuf::from(required(key, value)?))
+            }
+            "proxy_ssl_certificate_key" => {
+                self.proxy_ssl_certificate_key = Some(PathBuf::from(required(key, value)?))
+            }
+            "proxy_ssl_trusted_certificate" => {
+                self.proxy_ssl_trusted_certificate = Some(PathBuf::from(required(key, value)?))
+            }
+            "proxy_ssl_protocols" => {
+                self.proxy_ssl_protocols =
+                    required(key, value)?.split(',').map(String::from).collect()
+            }
+            _ => return Err(Error::parse(key, "unknown option")),
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Ord, PartialOrd, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Canary {
+    /// Differs from the `url` of the mapping only in the host and port
+    #[serde(with = "url_serde")]
+    pub url: Url,
+    /// Share of clients proxied to `url`, by a hash of the address and user agent, 1 to 100
+    pub percentage: u8,
+}
+
+#[derive(Debug, Clone, Default, Ord, PartialOrd, Eq, PartialEq, Serialize, Deserialize)]
+pub struct RequireHeader {
+    /// eg. `X-Webhook-Token`
+    pub name: String,
+    /// The token, kept out of the routing table and the model
+    #[serde(default, skip_serializing)]
+    pub value: Option<String>,
+    /// A file the token is read from while loading, eg. a docker secret
+    #[serde(default)]
+    pub secret_file: Option<PathBuf>,
+}
+
+fn default_access_log() -> bool {
+    true
+}
+
+/// A `sub_filter`, replacing every occurrence of `pattern` in responses
+#[derive(Debug, Clone, Ord, PartialOrd, Eq, PartialEq, Serialize, Deserialize)]
+pub struct SubFilter {
+    pub pattern: String,
+    pub replacement: String,
+}
+
+/// Replaces `from` with `to`, eg. in `proxy_redirect`
+#[derive(Debug, Clone, Ord, PartialOrd, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Rewrite {
+    pub from: String,
+    pub to: String,
+}
+
+/// An `upstream` block; mappings refer to it by name, eg. `url: http://<name>/`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Upstream {
+    pub name: String,
+    pub servers: Vec<UpstreamServer>,
+    /// Keeps a client on the same server
+    #[serde(default)]
+    pub sticky: Option<Sticky>,
+    /// The cookie `sticky: cookie` hashes without `nginx_plus`, eg. a session cookie of the backend
+    #[serde(default)]
+    pub sticky_cookie: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Sticky {
+    /// `sticky cookie` with nginx plus, or else `hash` of `sticky_cookie`
+    Cookie,
+    IpHash,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UpstreamServer {
+    /// `host:port`
+    pub address: String,
+    /// Share of requests relative to the other servers, 1 by default
+    pub weight: Option<u32>,
+}
+
+/// `map <source> <variable> { <pattern> <value>; ... }`, eg. to allow some CORS origins.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Map {
+    /// eg. `$http_origin`
+    pub source: String,
+    /// eg. `$cors_origin`
+    pub variable: String,
+    pub default: Option<String>,
+    /// Checked in order; `~` starts a regular expression as in nginx
+    #[serde(default)]
+    pub values: Vec<MapValue>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MapValue {
+    pub pattern: String,
+    pub value: String,
+}
+
+/// The conditions `proxy_next_upstream` accepts
+pub const RETRY_CONDITIONS: &[&str] = &[
+    "error",
+    "timeout",
+    "invalid_header",
+    "http_500",
+    "http_502",
+    "http_503",
+    "http_504",
+    "http_403",
+    "http_404",
+    "http_429",
+    "non_idempotent",
+    "off",
+];
+
+/// Files served from a directory, in the server block of `domain`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StaticMapping {
+    pub path: String,
+    /// Files are looked up at `root` + the request path, as nginx's `root`
+    pub root: PathBuf,
+    /// Serves `<file>.gz` if it exists and the client accepts gzip
+    #[serde(default)]
+    pub gzip_static: bool,
+    /// Serves `<file>.br` likewise, needs the brotli module
+    #[serde(default)]
+    pub brotli_static: bool,
+    /// Serves `index.html` for paths with no file, for client-side routing
+    #[serde(default)]
+    pub spa: bool,
+    /// Prefixes under `path` that get 404 instead of `index.html`, eg. `/api`
+    #[serde(default)]
+    pub spa_exclude: Vec<String>,
+}
+
+/// A fixed response without a backend, eg. `/robots.txt`, for requests to exactly `path`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Response {
+    pub path: String,
+    #[serde(default = "default_response_status")]
+    pub status: u16,
+    #[serde(default)]
+    pub content_type: Option<String>,
+    /// The text, or the URL for redirects
+    #[serde(default)]
+    pub body: Option<String>,
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+}
+
+fn default_response_status() -> u16 {
+    200
+}
+
+/// A server block of its own for `domain`, which may be a wildcard such as `*.example.com`.
+/// `path` defaults to `/`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SubdomainMapping {
+    pub domain: String,
+    #[serde(flatten)]
+    pub mapping: ReverseProxyMapping,
+}
+
+/// Splits `<path>-><url>`, or `<path>:<url>` at the first `:` followed by `<scheme>://`
+/// so that colons in the path or an IPv6 host don't matter.
+fn split_mapping(s: &str) -> Result<(&str, &str)> {
+    if let Some(i) = s.find("->") {
+        return Ok((s[..i].trim(), s[i + 2..].trim()));
+    }
+    s.match_indices(':')
+        .map(|(i, _)| i)
+        .find(|&i| starts_with_scheme(&s[i + 1..]))
+        .map(|i| (&s[..i], &s[i + 1..]))
+        .ok_or_else(|| {
+            Error::parse(
+                s,
+                "missing separator: expected <path>:<scheme>://... or <path>-><url>",
+            )
+        })
+}
+
+fn starts_with_scheme(s: &str) -> bool {
+    match s.find("://") {
+        Some(i) if i > 0 => {
+            let scheme = &s[..i];
+            scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+                && scheme
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '-' || c == '.')
+        }
+        _ => false,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CertificatePair {
+    pub certificate: PathBuf,
+    pub certificate_key: PathBuf,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DomainCertificate {
+    pub domain: String,
+    pub certificate: PathBuf,
+    pub certificate_key: PathBuf,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct TlsConfig {
+    pub certificate: Option<PathBuf>,
+    pub certificate_key: Option<PathBuf>,
+    /// Looked up as `<cert_dir>/<domain>/fullchain.pem` and `privkey.pem`
+    pub cert_dir: Option<PathBuf>,
+    #[serde(default)]
+    pub certificates: Vec<DomainCertificate>,
+    /// A CA bundle to verify client certificates with
+    pub client_certificate: Option<PathBuf>,
+    pub verify_client: Option<VerifyClient>,
+    /// `true` or a table overriding some of the defaults
+    #[serde(default, deserialize_with = "deserialize_tls_hardening")]
+    pub hardening: Option<TlsHardening>,
+}
+
+impl TlsConfig {
+    pub fn new(pair: CertificatePair) -> TlsConfig {
+        TlsConfig {
+            certificate: Some(pair.certificate),
+            certificate_key: Some(pair.certificate_key),
+            ..Default::default()
+        }
+    }
+
+    /// `certificates` entries win over `certificate`/`certificate_key`, which win over `cert_dir`.
+    pub fn certificate_for(&self, domain: Option<&str>) -> Option<CertificatePair> {
+        if let Some(domain) = domain {
+            if let Some(dc) = self.certificates.iter().find(|dc| dc.domain == domain) {
+                return Some(CertificatePair {
+                    certificate: dc.certificate.clone(),
+                    certificate_key: dc.certificate_key.clone(),
+                });
+            }
+        }
+        if let (Some(certificate), Some(certificate_key)) =
+            (&self.certificate, &self.certificate_key)
+        {
+            return Some(CertificatePair {
+                certificate: certificate.clone(),
+                certificate_key: certificate_key.clone(),
+            });
+        }
+        match (&self.cert_dir, domain) {
+            (Some(cert_dir), Some(domain)) => Some(CertificatePair {
+                certificate: cert_dir.join(domain).join("fullchain.pem"),
+                certificate_key: cert_dir.join(domain).join("privkey.pem"),
+            }),
+            _ => None,
+        }
+    }
+
+    /// The certificate for `domain` followed by the other `certificates` entries
+    pub fn certificate_paths(&self, domain: Option<&str>) -> Vec<PathBuf> {
+        let mut paths: Vec<PathBuf> = self
+            .certificate_for(domain)
+            .map(|pair| pair.certificate)
+            .into_iter()
+            .collect();
+        for dc in &self.certificates {
+            if !paths.contains(&dc.certificate) {
+                paths.push(dc.certificate.clone());
+            }
+        }
+        paths
+    }
+}
+
+/// Defaults follow the Mozilla "intermediate" configuration.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TlsHardening {
+    pub protocols: Vec<String>,
+    pub ciphers: String,
+    pub session_cache: String,
+    pub session_timeout: String,
+    pub ocsp_stapling: bool,
+    /// `Strict-Transport-Security` max-age in seconds, 0 to omit the header
+    pub hsts_max_age: u64,
+    pub hsts_include_subdomains: bool,
+}
+
+impl Default for TlsHardening {
+    fn default() -> TlsHardening {
+        TlsHardening {
+            protocols: vec!["TLSv1.2".into(), "TLSv1.3".into()],
+            ciphers: "ECDHE-ECDSA-AES128-GCM-SHA256:ECDHE-RSA-AES128-GCM-SHA256:\
+                          ECDHE-ECDSA-AES256-GCM-SHA384:ECDHE-RSA-AES256-GCM-SHA384:\
+                          ECDHE-ECDSA-CHACHA20-POLY1305:ECDHE-RSA-CHACHA20-POLY1305:\
+                          DHE-RSA-AES128-GCM-SHA256:DHE-RSA-AES256-GCM-SHA384:\
+                          DHE-RSA-CHACHA20-POLY1305"
+                .into(),
+            session_cache: "shared:MozSSL:10m".into(),
+            session_timeout: "1d".into(),
+            ocsp_stapling: true,
+            hsts_max_age: 63_072_000,
+            hsts_include_subdomains: false,
+        }
+    }
+}
+
+fn deserialize_tls_hardening<'de, D>(
+    deserializer: D,
+) -> std::result::Result<Option<TlsHardening>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Hardening {
+        Switch(bool),
+        Settings(TlsHardening),
+    }
+
+    Ok(
+        match <Option<Hardening> as serde::Deserialize>::deserialize(deserializer)? {
+            Some(Hardening::Switch(true)) => Some(TlsHardening::default()),
+            Some(Hardening::Switch(false)) | None => None,
+            Some(Hardening::Settings(hardening)) => Some(hardening),
+        },
+    )
+}
+
+#[derive(Debug, Clone, Copy, Ord, PartialOrd, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PassMode {
+    /// `proxy_pass` without a URI: the request path is passed as it is
+    KeepPath,
+    /// `proxy_pass` with the URI of `url`, which replaces the matched `path`
+    ReplacePath,
+    /// As `replace-path`, with a trailing `/` on both `path` and the URI of `url`
+    AppendSlash,
+}
+
+impl std::str::FromStr for PassMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<PassMode> {
+        match s {
+            "keep-path" => Ok(PassMode::KeepPath),
+            "replace-path" => Ok(PassMode::ReplacePath),
+            "append-slash" => Ok(PassMode::AppendSlash),
+            _ => Err(Error::parse(
+                s,
+                "expected keep-path, replace-path or append-slash",
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Ord, PartialOrd, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Preset {
+    /// Large request bodies streamed to the upstream
+    Uploads,
+    /// Responses held open until an event, sent as they come
+    LongPoll,
+    /// Long-lived streams both ways, eg. gRPC-Web or HTTP streaming APIs
+    GrpcStream,
+}
+
+impl Preset {
+    /// The directives of the preset; ones the mapping sets itself, eg. by `timeout`, win.
+    pub fn directives(self) -> &'static [&'static str] {
+        match self {
+            Preset::Uploads => &[
+                "client_max_body_size 1g;",
+                "client_body_timeout 300s;",
+                "proxy_request_buffering off;",
+                "proxy_read_timeout 300s;",
+                "proxy_send_timeout 300s;",
+            ],
+            Preset::LongPoll => &[
+                "proxy_buffering off;",
+                "proxy_read_timeout 1h;",
+                "proxy_send_timeout 1h;",
+            ],
+            Preset::GrpcStream => &[
+                "client_max_body_size 0;",
+                "proxy_http_version 1.1;",
+                "proxy_buffering off;",
+                "proxy_request_buffering off;",
+                "proxy_read_timeout 1h;",
+                "proxy_send_timeout 1h;",
+            ],
+        }
+    }
+}
+
+impl std::str::FromStr for Preset {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Preset> {
+        match s {
+            "uploads" => Ok(Preset::Uploads),
+            "long-poll" => Ok(Preset::LongPoll),
+            "grpc-stream" => Ok(Preset::GrpcStream),
+            _ => Err(Error::parse(
+                s,
+                "expected uploads, long-poll or grpc-stream",
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Ord, PartialOrd, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Resolve {
+    /// The address of the container named by the host of `url`, for nginx on the host
+    /// network which can't use the DNS of docker networks
+    Docker,
+}
+
+impl std::str::FromStr for Resolve {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Resolve> {
+        match s {
+            "docker" => Ok(Resolve::Docker),
+            _ => Err(Error::parse(s, "expected docker")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    Nginx,
+    K8sConfigMap,
+}
+
+impl std::str::FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<OutputFormat, String> {
+        match s {
+            "nginx" => Ok(OutputFormat::Nginx),
+            "k8s-configmap" => Ok(OutputFormat::K8sConfigMap),
+            _ => Err(format!("unknown format: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NotifyFormat {
+    Json,
+    /// `{"text": ...}` for Slack and compatible incoming webhooks
+    Slack,
+}
+
+impl std::str::FromStr for NotifyFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<NotifyFormat, String> {
+        match s {
+            "json" => Ok(NotifyFormat::Json),
+            "slack" => Ok(NotifyFormat::Slack),
+            _ => Err(format!("unknown notify format: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VerifyClient {
+    On,
+    Optional,
+    OptionalNoCa,
+}
+
+impl VerifyClient {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            VerifyClient::On => "on",
+            VerifyClient::Optional => "optional",
+            VerifyClient::OptionalNoCa => "optional_no_ca",
+        }
+    }
+}
+
+/// Files found recursively under `--config-dir`. Files under `sites/` only add
+/// `reverse_proxy` mappings; any other file is a global setting.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ConfigSources {
+    pub globals: Vec<PathBuf>,
+    pub sites: Vec<PathBuf>,
+}
+
+impl ConfigSources {
+    pub fn from_config_dir(config_dir: &Path) -> Result<ConfigSources> {
+        fn files(pattern: &str) -> Result<Vec<PathBuf>> {
+            debug!("config_dir: {}", pattern);
+            let mut files = Vec::new();
+            for path in glob(pattern)? {
+                let path = path?;
+                if path.is_file() {
+                    files.push(path);
+                }
+            }
+            Ok(files)
+        }
+
+        let sites = files(&format!("{}/sites/**/*", config_dir.display()))?;
+        let globals = files(&format!("{}/**/*", config_dir.display()))?
+            .into_iter()
+            .filter(|path| !sites.contains(path))
+            .collect();
+        Ok(ConfigSources { globals, sites })
+    }
+}
+
+/// Sets every leaf of `table` under `prefix`, so nested tables are merged rather than replaced.
+fn apply_overlay(
+    settings: &mut config::Config,
+    prefix: &str,
+    table: HashMap<String, config::Value>,
+) -> Result<()> {
+    for (key, value) in table {
+        let key = if prefix.is_empty() {
+            key
+        } else {
+            format!("{}.{}", prefix, key)
+        };
+        match value.clone().into_table() {
+            Ok(table) => apply_overlay(settings, &key, table)?,
+            Err(_) => {
+                settings.set(&key, value)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Reads a config file with `${VAR}` references replaced by environment variables.
+pub fn load_config_file(path: &Path) -> Result<config::File<config::FileSourceString>> {
+    let format = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("toml") => config::FileFormat::Toml,
+        Some("json") => config::FileFormat::Json,
+        Some("yaml") | Some("yml") => config::FileFormat::Yaml,
+        Some("hjson") => config::FileFormat::Hjson,
+        Some("ini") => config::FileFormat::Ini,
+        _ => {
+            return Err(Error::ConfigLoad(format!(
+                "Unsupported config file format: {}",
+                path.display()
+            )))
+        }
+    };
+    let content = fs::read_to_string(path).map_err(|err| Error::io(path.display(), err))?;
+    let content = interpolate_env(&content, env_or_secret_file)
+        .map_err(|err| Error::ConfigLoad(format!("{}: {}", path.display(), err)))?;
+    Ok(config::File::from_str(&content, format))
+}
+
+/// `$NAME`, or else the content of the file named by `$NAME_FILE` as with docker secrets.
+/// A single trailing newline of the file is dropped.
+pub fn env_or_secret_file(name: &str) -> Result<Option<String>> {
+    if let Ok(value) = env::var(name) {
+        return Ok(Some(value));
+    }
+    match env::var(format!("{}_FILE", name)) {
+        Ok(path) => {
+            let value = fs::read_to_string(&path)
+                .map_err(|err| Error::io(format!("{}_FILE={}", name, path), err))?;
+            let value = value.strip_suffix('\n').unwrap_or(&value);
+            Ok(Some(value.strip_suffix('\r').unwrap_or(value).to_owned()))
+        }
+        Err(_) => Ok(None),
+    }
+}
+
+/// Replaces `${VAR}`, `${VAR:-default}` (unset or empty) and `${VAR-default}` (unset).
+/// `$${` is a literal `${`, and nginx variables such as `$host` are left as they are,
+/// as is `${VAR}` while unset, eg. `${remote_addr}`.
+pub fn interpolate_env<F>(s: &str, lookup: F) -> Result<String>
+where
+    F: Fn(&str) -> Result<Option<String>>,
+{
+    let mut buf = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(i) = rest.find("${") {
+        if rest[..i].ends_with('$') {
+            buf.push_str(&rest[..i]);
+            buf.push('{');
+            rest = &rest[i + 2..];
+            continue;
+        }
+        buf.push_str(&rest[..i]);
+        let end = rest[i..]
+            .find('}')
+            .ok_or_else(|| Error::parse(&rest[i..], "unterminated ${"))?;
+        let expr = &rest[i + 2..i + end];
+        let value = if let Some(j) = expr.find(":-") {
+            lookup(&expr[..j])?
+                .filter(|value| !value.is_empty())
+                .unwrap_or_else(|| expr[j + 2..].to_owned())
+        } else if let Some(j) = expr.find('-') {
+            lookup(&expr[..j])?.unwrap_or_else(|| expr[j + 1..].to_owned())
+        } else {
+            lookup(expr)?.unwrap_or_else(|| format!("${{{}}}", expr))
+        };
+        buf.push_str(&value);
+        rest = &rest[i + end + 1..];
+    }
+    buf.push_str(rest);
+    Ok(buf)
+}
+
+/// The host which a container reaches the docker host with
+pub const DOCKER_HOST_INTERNAL: &str = "host.docker.internal";
+
+/// Inside the nginx container, `localhost` is the container itself and such upstreams get 502.
+/// Warns about them, or points them to the docker host if `rewrite` is set.
+pub fn check_localhost(rp: &mut ReverseProxyMapping, rewrite: bool) {
+    let is_localhost = match rp.url.host() {
+        Some(url::Host::Domain(domain)) => domain == "localhost",
+        Some(url::Host::Ipv4(addr)) => addr.is_loopback(),
+        Some(url::Host::Ipv6(addr)) => addr.is_loopback(),
+        None => false,
+    };
+    if !is_localhost {
+        return;
+    }
+    if rewrite {
+        info!(
+            "proxy to {} instead of {}: {} (on Linux, run nginx with --add-host={}:host-gateway \
+                 or extra_hosts: [\"{}:host-gateway\"])",
+            DOCKER_HOST_INTERNAL,
+            rp.url.host_str().unwrap_or_default(),
+            rp.path,
+            DOCKER_HOST_INTERNAL,
+            DOCKER_HOST_INTERNAL
+        );
+        rp.url
+            .set_host(Some(DOCKER_HOST_INTERNAL))
+            .expect("a valid host");
+    } else {
+        diagnostics::warn(
+            Category::Upstream,
+            format!(
+                "{} -> {} is the nginx container itself unless it runs with --network host; \
+                     use {} or --rewrite-localhost",
+                rp.path, rp.url, DOCKER_HOST_INTERNAL
+            ),
+        );
+    }
+}
+
+/// Reads the token of `require_header` of `rp` from its `secret_file`.
+pub fn read_required_header(rp: &mut ReverseProxyMapping) -> Result<()> {
+    if let Some(ref mut require_header) = rp.require_header {
+        if let Some(ref secret_file) = require_header.secret_file {
+            let token = fs::read_to_string(secret_file)
+                .map_err(|err| Error::io(secret_file.display().to_string(), err))?;
+            let token = token.strip_suffix('\n').unwrap_or(&token);
+            require_header.value = Some(token.strip_suffix('\r').unwrap_or(token).to_owned());
+        }
+    }
+    Ok(())
+}
+
+/// Applies `resolve` of `rp`, replacing the host of its URL with the looked up address.
+/// Returns the docker network the upstream is reached on, if known.
+pub fn resolve_address(rp: &mut ReverseProxyMapping) -> Result<Option<String>> {
+    match rp.resolve {
+        Some(Resolve::Docker) => {
+            let container = rp
+                .url
+                .host_str()
+                .ok_or_else(|| Error::parse(rp.url.as_str(), "resolve needs a host"))?
+                .to_owned();
+            let networks = crate::container::docker_networks(&container)?;
+            let (network, address) =
+                crate::container::pick_address(&networks, &container, rp.network.as_deref())?;
+            info!(
+                "resolve {} to {} on {}: {}",
+                container, address, network, rp.path
+            );
+            rp.url
+                .set_ip_host(address)
+                .map_err(|_| Error::parse(rp.url.as_str(), "can't have an address"))?;
+            Ok(Some(network))
+        }
+        None => Ok(rp.network.clone()),
+    }
+}
+
+/// The host of a `srv://` or `env://` URL, which names where its servers come from.
+fn discovery_name(rp: &ReverseProxyMapping, example: &str) -> Result<String> {
+    rp.url
+        .host_str()
+        .filter(|name| !name.is_empty())
+        .map(str::to_owned)
+        .ok_or_else(|| {
+            Error::parse(
+                rp.url.as_str(),
+                format!("{} needs a name, eg. {}", rp.url.scheme(), example),
+            )
+        })
+}
+
+/// An upstream of the comma separated URLs in the environment variable `var`,
+/// and their scheme.
+fn env_upstream(var: &str) -> Result<(Upstream, String)> {
+    let value = env::var(var)
+        .map_err(|_| Error::parse(format!("env://{}", var), format!("{} is not set", var)))?;
+    let mut scheme = None;
+    let mut servers = Vec::new();
+    for item in value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+    {
+        let url =
+            Url::parse(item).map_err(|err| Error::parse(item, format!("in {}: {}", var, err)))?;
+        if !["http", "https"].contains(&url.scheme()) {
+            return Err(Error::parse(item, format!("in {}: use http or https", var)));
+        }
+        if scheme.get_or_insert_with(|| url.scheme().to_owned()) != url.scheme() {
+            return Err(Error::parse(
+                value.as_str(),
+                format!("the URLs in {} mix http and https", var),
+            ));
+        }
+        servers.push(UpstreamServer {
+            address: format!(
+                "{}:{}",
+                url.host_str().unwrap_or_default(),
+                url.port_or_known_default().unwrap_or(80)
+            ),
+            weight: None,
+        });
+    }
+    let scheme = scheme
+        .ok_or_else(|| Error::parse(format!("env://{}", var), format!("{} has no URLs", var)))?;
+    let upstream = Upstream {
+        name: format!("env_{}", var.to_lowercase()),
+        servers,
+        sticky: None,
+        sticky_cookie: None,
+    };
+    Ok((upstream, scheme))
+}
+
+/// Replaces a `srv://<name>/path` URL with one to an upstream of the SRV records of `name`,
+/// and an `env://<VAR>/path` URL with one to an upstream of the URLs in `VAR`.
+/// The upstream is pushed to `upstreams` unless there already.
+fn discover_upstream(rp: &mut ReverseProxyMapping, upstreams: &mut Vec<Upstream>) -> Result<()> {
+    let (upstream, scheme) = match rp.url.scheme() {
+        "srv" => {
+            let name = discovery_name(rp, "srv://_http._tcp.api.service.consul")?;
+            let upstream = crate::srv::upstream(&name, &crate::srv::lookup(&name)?);
+            (upstream, "http".to_owned())
+        }
+        "env" => env_upstream(&discovery_name(rp, "env://BACKENDS")?)?,
+        _ => return Ok(()),
+    };
+    let mut url = Url::parse(&format!("{}://{}", scheme, upstream.name))
+        .map_err(|err| Error::parse(upstream.name.as_str(), err.to_string()))?;
+    url.set_path(rp.url.path());
+    url.set_query(rp.url.query());
+    info!(
+        "look up {}: {} servers for {}",
+        rp.url,
+        upstream.servers.len(),
+        rp.path
+    );
+    rp.url = url;
+    if !upstreams.iter().any(|u| u.name == upstream.name) {
+        upstreams.push(upstream);
+    }
+    Ok(())
+}
+
+/// Folds the mappings with `match_header` or `match_cookie` into `routes` of the one of
+/// the same path without, so that they share its location.
+fn fold_routes(mappings: Vec<ReverseProxyMapping>) -> Result<Vec<ReverseProxyMapping>> {
+    let (routes, mut folded): (Vec<_>, Vec<_>) = mappings
+        .into_iter()
+        .partition(|rp| rp.match_header.is_some() || rp.match_cookie.is_some());
+    for route in routes {
+        let default = folded
+            .iter_mut()
+            .find(|rp| rp.path == route.path)
+            .ok_or_else(|| {
+                Error::Render(format!(
+                    "{} with match_header or match_cookie needs a mapping of the same path without",
+                    route.path
+                ))
+            })?;
+        if default.url.scheme() != route.url.scheme() || default.url.path() != route.url.path() {
+            return Err(Error::Render(format!(
+                    "url must have the same scheme and path as the mapping of the same path without match_header or match_cookie: {} -> {}",
+                    route.path, route.url
+                )));
+        }
+        default.routes.push(route);
+    }
+    Ok(folded)
+}
+
+/// Warns of upstreams on docker networks which `nginx_container` isn't attached to,
+/// where they would answer 502.
+fn check_networks(nginx_container: &str, networks: &[(String, String)]) -> Result<()> {
+    if networks.is_empty() {
+        return Ok(());
+    }
+    let attached = crate::container::docker_networks(nginx_container)?;
+    if attached.contains_key("host") {
+        // the host reaches the addresses of every bridge network
+        return Ok(());
+    }
+    for (path, network) in networks {
+        if !attached.contains_key(network) {
+            diagnostics::warn(
+                Category::Upstream,
+                format!(
+                    "{} isn't attached to the network {} of {}; docker network connect {} {}",
+                    nginx_container, network, path, network, nginx_container
+                ),
+            );
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RawSiteConfig {
+    #[serde(default)]
+    reverse_proxy: Vec<ReverseProxyMapping>,
+}
+
+fn load_site(path: &Path) -> Result<Vec<ReverseProxyMapping>> {
+    info!("load site config file: {}", path.display());
+    let mut settings = config::Config::default();
+    settings.merge(load_config_file(path)?)?;
+    let RawSiteConfig { reverse_proxy } = settings
+        .try_into()
+        .map_err(|err| Error::ConfigLoad(format!("{}: {}", path.display(), err)))?;
+    Ok(reverse_proxy
+        .into_iter()
+        .map(|rp| ReverseProxyMapping {
+            source: Some(path.display().to_string()),
+            ..rp
+        })
+        .collect())
+}
+
+/// The name of the module imported by a `js_import` value, `main` for `main from a.js` and `a.js`.
+fn js_module_name(import: &str) -> &str {
+    match import.find(" from ") {
+        Some(i) => import[..i].trim(),
+        None => {
+            let file = import.rsplit('/').next().unwrap_or(import);
+            file.trim_end_matches(".js")
+        }
+    }
+}
+
+/// Rejects URLs which `proxy_pass` can't take, with a hint for the usual mistakes.
+/// `ws` and `wss` are taken as `http` and `https` with `websocket`, see `normalize_scheme`.
+fn check_scheme(url: &Url) -> Result<()> {
+    let hint = match url.scheme() {
+        "http" | "https" | "ws" | "wss" | "srv" | "env" => return Ok(()),
+        _ if url.cannot_be_a_base() => format!(
+            "the scheme is missing, eg. http://{}",
+            url.as_str().trim_end_matches('/')
+        ),
+        "tcp" | "udp" => "TCP and UDP are proxied by nginx's stream module, not by this".into(),
+        "grpc" | "grpcs" => "gRPC needs grpc_pass, which isn't supported".into(),
+        "unix" => "unix sockets aren't supported, publish a port instead".into(),
+        scheme => format!("unsupported scheme {}, use http, https, ws or wss", scheme),
+    };
+    Err(Error::parse(url.as_str(), hint))
+}
+
+/// `ws` and `wss` URLs as `http` and `https` with `websocket`.
+pub fn normalize_scheme(rp: &mut ReverseProxyMapping) {
+    let scheme = match rp.url.scheme() {
+        "ws" => "http",
+        "wss" => "https",
+        _ => return,
+    };
+    rp.url
+        .set_scheme(scheme)
+        .expect("ws and http are both special");
+    rp.websocket = true;
+}
+
+/// Whether `c` could end a directive or open a block if written into the nginx conf unquoted.
+fn breaks_directive(c: char) -> bool {
+    c.is_whitespace() || c.is_control() || "\"';{}\\".contains(c)
+}
+
+/// Rejects paths not starting with `/` or breaking the `location` they are written into.
+fn check_path(path: &str) -> Result<()> {
+    if !path.starts_with('/') || path.contains(breaks_directive) {
+        return Err(Error::parse(
+            path,
+            "a path must start with / and have no whitespace, quotes, semicolons or braces",
+        ));
+    }
+    Ok(())
+}
+
+/// Rejects values which are written unquoted, so that they can't inject directives.
+fn check_literal(what: &str, value: &str) -> Result<()> {
+    if value.is_empty() || value.contains(breaks_directive) {
+        return Err(Error::parse(
+            value,
+            format!(
+                "{} must be non-empty without whitespace, quotes, semicolons or braces",
+                what
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// `path` with runs of `/` collapsed into one, eg. `//api///v1` into `/api/v1`.
+pub fn normalize_path(path: &str) -> String {
+    let mut normalized = String::with_capacity(path.len());
+    for c in path.chars() {
+        if c != '/' || !normalized.ends_with('/') {
+            normalized.push(c);
+        }
+    }
+    normalized
+}
+
+/// A global config file, with whether it has the lists which replace those of earlier files.
+struct GlobalFile {
+    file: config::File<config::FileSourceString>,
+    defines_reverse_proxy: bool,
+    defines_subdomains: bool,
+}
+
+impl GlobalFile {
+    fn load(path: &Path) -> Result<GlobalFile> {
+        let file = load_config_file(path)?;
+        let mut settings = config::Config::default();
+        settings.merge(file.clone())?;
+        Ok(GlobalFile {
+            file,
+            defines_reverse_proxy: settings.get_array("reverse_proxy").is_ok(),
+            defines_subdomains: settings.get_array("subdomains").is_ok(),
+        })
+    }
+}
+
+/// `load` of every path, split over as many threads as CPUs, with the results in the order
+/// of `paths` so that merging them is deterministic. The first error in that order is returned.
+fn load_parallel<T, F>(paths: &[PathBuf], load: F) -> Result<Vec<T>>
+where
+    T: Send,
+    F: Fn(&Path) -> Result<T> + Sync,
+{
+    if paths.len() < 2 {
+        return paths.iter().map(|path| load(path)).collect();
+    }
+    let threads = std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1);
+    let chunk = paths.len().div_ceil(threads);
+    let load = &load;
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = paths
+            .chunks(chunk)
+            .map(|chunk| {
+                scope.spawn(move || {
+                    chunk
+                        .iter()
+                        .map(|path| load(path))
+                        .collect::<Vec<Result<T>>>()
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .flat_map(|handle| {
+                handle
+                    .join()
+                    .expect("a thread loading config files panicked")
+            })
+            .collect()
+    })
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RawAppConfig {
+    host: Option<String>,
+    port: Option<u16>,
+    domain: Option<String>,
+    default_domain: Option<String>,
+    #[serde(default)]
+    reverse_proxy: Vec<ReverseProxyMapping>,
+    #[serde(default)]
+    subdomains: Vec<SubdomainMapping>,
+    #[serde(default, with = "url_serde")]
+    default_backend: Option<Url>,
+    #[serde(default)]
+    upstreams: Vec<Upstream>,
+    #[serde(default)]
+    maps: Vec<Map>,
+    #[serde(default)]
+    statics: Vec<StaticMapping>,
+    #[serde(default)]
+    responses: Vec<Response>,
+    healthz: Option<String>,
+    nginx_conf: Option<PathBuf>,
+    on_change: Option<String>,
+    #[serde(default, with = "url_serde")]
+    reload_check: Option<Url>,
+    history: Option<usize>,
+    audit_log: Option<PathBuf>,
+    #[serde(default, with = "url_serde")]
+    notify_url: Option<Url>,
+    notify_format: Option<NotifyFormat>,
+    conf_mode: Option<String>,
+    conf_owner: Option<String>,
+    conf_group: Option<String>,
+    rewrite_localhost: Option<bool>,
+    nginx_container: Option<String>,
+    openresty: Option<bool>,
+    nginx_plus: Option<bool>,
+    #[serde(default)]
+    js_import: Vec<String>,
+    tls: Option<TlsConfig>,
+    default_headers: Option<DefaultHeaders>,
+    tracing: Option<bool>,
+    proxy_protocol: Option<bool>,
+    #[serde(default)]
+    real_ip_from: Vec<String>,
+    listen: Option<ListenOptions>,
+    full_conf: Option<bool>,
+    workers: Option<RawWorkers>,
+    managed_region: Option<bool>,
+    source_map: Option<bool>,
+    mkdirs: Option<bool>,
+    hide_upstream_headers: Option<HideHeaders>,
+    server_tokens: Option<bool>,
+    timeouts: Option<ServerTimeouts>,
+    performance: Option<bool>,
+    charset: Option<String>,
+    default_type: Option<String>,
+    deny: Option<DenyPaths>,
+    log: Option<LogConfig>,
+}
+
+/// Options of the listening socket. nginx takes them once per address, so they are
+/// rendered in the first server block only.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ListenOptions {
+    /// Length of the queue of pending connections
+    #[serde(default)]
+    pub backlog: Option<u32>,
+    /// A socket per worker, so that the kernel spreads connections
+    #[serde(default)]
+    pub reuseport: bool,
+    /// `on`, `off` or `<idle>:<interval>:<count>`, eg. `30m::10`
+    #[serde(default)]
+    pub so_keepalive: Option<String>,
+}
+
+/// Timeouts of client connections in every server block, as nginx times, eg. `75s` or `1m`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ServerTimeouts {
+    #[serde(default)]
+    pub keepalive_timeout: Option<String>,
+    #[serde(default)]
+    pub client_header_timeout: Option<String>,
+    #[serde(default)]
+    pub client_body_timeout: Option<String>,
+    #[serde(default)]
+    pub send_timeout: Option<String>,
+}
+
+impl ServerTimeouts {
+    /// The directives set, by their names.
+    pub fn directives(&self) -> Vec<(&'static str, &str)> {
+        [
+            ("keepalive_timeout", &self.keepalive_timeout),
+            ("client_header_timeout", &self.client_header_timeout),
+            ("client_body_timeout", &self.client_body_timeout),
+            ("send_timeout", &self.send_timeout),
+        ]
+        .iter()
+        .filter_map(|(name, value)| value.as_deref().map(|value| (*name, value)))
+        .collect()
+    }
+}
+
+/// Paths denied in every server block, before any mapping can serve them
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct DenyPaths {
+    /// Files and directories starting with `.`, except `.well-known`
+    #[serde(default)]
+    pub hidden: bool,
+    /// `.git` directories
+    #[serde(default)]
+    pub git: bool,
+    /// `.env` files, eg. `.env.production`
+    #[serde(default)]
+    pub env: bool,
+    /// Backups of editors and the like: `~`, `.bak`, `.old`, `.orig`, `.save` and `.swp`
+    #[serde(default)]
+    pub backups: bool,
+}
+
+/// Logs of every server block. A target is a file, or `syslog:server=<address>[,<parameter>...]`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct LogConfig {
+    #[serde(default)]
+    pub access_log: Option<String>,
+    #[serde(default)]
+    pub error_log: Option<String>,
+    /// The least severe level of `error_log`, eg. `warn`
+    #[serde(default)]
+    pub error_log_level: Option<String>,
+    /// Statuses not access logged, eg. `2xx` or `404`, unless `slower_than`
+    #[serde(default)]
+    pub skip_status: Vec<String>,
+    /// Only requests taking this many seconds or more are access logged, unless logged by
+    /// `skip_status`
+    #[serde(default)]
+    pub slower_than: Option<f64>,
+    /// `log_format`s by their names
+    #[serde(default)]
+    pub formats: BTreeMap<String, LogFormat>,
+    /// The name of the format of the access log, `combined` by default
+    #[serde(default)]
+    pub format: Option<String>,
+}
+
+/// Variables joined by spaces, eg. `[$remote_addr, $status, $request_time]`, or a format string
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum LogFormat {
+    Variables(Vec<String>),
+    Raw(String),
+}
+
+impl LogFormat {
+    pub fn format_string(&self) -> String {
+        match self {
+            LogFormat::Variables(variables) => variables
+                .iter()
+                .map(|variable| {
+                    if variable.starts_with('$') {
+                        variable.clone()
+                    } else {
+                        format!("${}", variable)
+                    }
+                })
+                .collect::<Vec<_>>()
+                .join(" "),
+            LogFormat::Raw(format) => format.clone(),
+        }
+    }
+}
+
+impl LogConfig {
+    /// Whether `skip_status` or `slower_than` leave some requests out of the access log.
+    pub fn is_conditional(&self) -> bool {
+        !self.skip_status.is_empty() || self.slower_than.is_some()
+    }
+
+    /// The `access_log` directive in `format`, or `format` of this, if any differs from the
+    /// default one of nginx.
+    pub fn access_log_directive(&self, format: Option<&str>) -> Option<String> {
+        let format = format.or(self.format.as_deref());
+        if !self.is_conditional() && format.is_none() {
+            return self
+                .access_log
+                .as_ref()
+                .map(|target| format!("access_log {};", target));
+        }
+        Some(format!(
+            "access_log {} {}{};",
+            self.access_log
+                .as_deref()
+                .unwrap_or("/var/log/nginx/access.log"),
+            format.unwrap_or("combined"),
+            if self.is_conditional() {
+                " if=$rp_loggable"
+            } else {
+                ""
+            }
+        ))
+    }
+
+    /// Fails unless `format` is `combined` or one of `formats`.
+    pub fn check_format(&self, format: &str) -> Result<()> {
+        if format != "combined" && !self.formats.contains_key(format) {
+            return Err(Error::parse(format, "no such log format in log.formats"));
+        }
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<()> {
+        for (name, format) in &self.formats {
+            if name == "combined"
+                || name.is_empty()
+                || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+            {
+                return Err(Error::parse(
+                    name.as_str(),
+                    "a log format name is alphanumeric or _, other than combined",
+                ));
+            }
+            if let LogFormat::Variables(variables) = format {
+                for variable in variables {
+                    check_literal("log format variable", variable)?;
+                }
+            }
+        }
+        if let Some(ref format) = self.format {
+            self.check_format(format)?;
+        }
+        for target in self.access_log.iter().chain(&self.error_log) {
+            check_literal("log target", target)?;
+            if target.starts_with("syslog:") && !target.starts_with("syslog:server=") {
+                return Err(Error::parse(
+                    target.as_str(),
+                    "a syslog target is like syslog:server=10.0.0.1:514,tag=nginx",
+                ));
+            }
+        }
+        if let Some(status) = self.skip_status.iter().find(|status| {
+            status.len() != 3
+                || !status.starts_with(|c: char| ('1'..='5').contains(&c))
+                || !(status[1..].chars().all(|c| c.is_ascii_digit()) || &status[1..] == "xx")
+        }) {
+            return Err(Error::parse(
+                status.as_str(),
+                "skip_status is a status or a class such as 2xx",
+            ));
+        }
+        if let Some(seconds) = self.slower_than {
+            if !(seconds > 0.0 && seconds < 1e9) {
+                return Err(Error::parse(
+                    seconds.to_string(),
+                    "slower_than must be a positive number of seconds",
+                ));
+            }
+        }
+        if let Some(ref level) = self.error_log_level {
+            let levels = [
+                "debug", "info", "notice", "warn", "error", "crit", "alert", "emerg",
+            ];
+            if !levels.contains(&level.as_str()) {
+                return Err(Error::parse(
+                    level.as_str(),
+                    format!("expected one of {}", levels.join(", ")),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct RawWorkers {
+    /// A number, or `auto` for the CPUs available to the container
+    processes: Option<String>,
+    connections: Option<u32>,
+    rlimit_nofile: Option<u32>,
+    #[serde(default)]
+    multi_accept: bool,
+}
+
+impl RawWorkers {
+    fn resolve(self) -> Result<Workers> {
+        let processes = match self.processes.as_deref() {
+            None | Some("auto") => crate::container::cpu_count(),
+            Some(processes) => processes
+                .parse()
+                .map_err(|err| Error::parse(processes, err))?,
+        };
+        let connections = self.connections.unwrap_or(1024);
+        Ok(Workers {
+            processes,
+            connections,
+            // a proxied connection takes two descriptors
+            rlimit_nofile: self.rlimit_nofile.unwrap_or(connections * 2),
+            multi_accept: self.multi_accept,
+        })
+    }
+}
+
+/// `worker_processes` and the like of a full nginx.conf
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Workers {
+    pub processes: u32,
+    pub connections: u32,
+    pub rlimit_nofile: u32,
+    pub multi_accept: bool,
+}
+
+impl Default for Workers {
+    fn default() -> Workers {
+        Workers {
+            processes: 1,
+            connections: 1024,
+            rlimit_nofile: 2048,
+            multi_accept: false,
+        }
+    }
+}
+
+/// `hide_upstream_headers: true` hides the headers telling the backend implementation,
+/// and a list hides those headers instead.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum HideHeaders {
+    Preset(bool),
+    Names(Vec<String>),
+}
+
+impl HideHeaders {
+    pub fn resolve(&self) -> Vec<String> {
+        match self {
+            HideHeaders::Preset(true) => crate::IMPLEMENTATION_HEADERS
+                .iter()
+                .map(|name| name.to_string())
+                .collect(),
+            HideHeaders::Preset(false) => Vec::new(),
+            HideHeaders::Names(names) => names.clone(),
+        }
+    }
+}
+
+/// `default_headers: false` drops the default `proxy_set_header`s, and a table overrides
+/// some of them by name. An empty value removes the header.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum DefaultHeaders {
+    Switch(bool),
+    Overrides(HashMap<String, String>),
+}
+
+impl DefaultHeaders {
+    pub fn resolve(&self) -> Vec<(String, String)> {
+        let defaults = crate::DEFAULT_PROXY_HEADERS
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()));
+        match self {
+            DefaultHeaders::Switch(true) => defaults.collect(),
+            DefaultHeaders::Switch(false) => Vec::new(),
+            DefaultHeaders::Overrides(overrides) => {
+                // config keys are lowercased, so names are matched case-insensitively
+                let lookup = |name: &str| {
+                    overrides
+                        .iter()
+                        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+                        .map(|(_, value)| value.clone())
+                };
+                let mut headers: Vec<(String, String)> = defaults
+                    .map(|(name, value)| {
+                        let value = lookup(&name).unwrap_or(value);
+                        (name, value)
+                    })
+                    .collect();
+                let mut extra: Vec<(String, String)> = overrides
+                    .iter()
+                    .filter(|(key, _)| {
+                        !crate::DEFAULT_PROXY_HEADERS
+                            .iter()
+                            .any(|(name, _)| key.eq_ignore_ascii_case(name))
+                    })
+                    .map(|(key, value)| (key.clone(), value.clone()))
+                    .collect();
+                extra.sort();
+                headers.extend(extra);
+                headers.retain(|(_, value)| !value.is_empty());
+                headers
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AppConfig {
+    pub host: String,
+    pub port: u16,
+    pub domain: Option<String>,
+    /// `server_name` without `domain`, where `_` catches every host as `default_server`
+    #[serde(default = "default_domain")]
+    pub default_domain: String,
+    #[serde(default)]
+    pub reverse_proxy: Vec<ReverseProxyMapping>,
+    /// Server blocks of their own, after the one of `domain`
+    #[serde(default)]
+    pub subdomains: Vec<SubdomainMapping>,
+    #[serde(default)]
+    pub upstreams: Vec<Upstream>,
+    /// `map` blocks whose variables can be used in headers and URLs
+    #[serde(default)]
+    pub maps: Vec<Map>,
+    /// Locations serving files, in the server block of `domain`
+    #[serde(default)]
+    pub statics: Vec<StaticMapping>,
+    /// Locations answering by themselves, in the server block of `domain`
+    #[serde(default)]
+    pub responses: Vec<Response>,
+    /// A path answering 200 without logging in every server block, eg. `/healthz`
+    #[serde(default)]
+    pub healthz: Option<String>,
+    pub nginx_conf: PathBuf,
+    pub on_change: Option<String>,
+    /// Probed after a reload, which is rolled back unless it answers 2xx or 3xx
+    #[serde(default, with = "url_serde")]
+    pub reload_check: Option<Url>,
+    /// How many of the nginx confs written are kept for `rollback`, 0 for none
+    #[serde(default)]
+    pub history: usize,
+    /// Appended a JSON line per change of the nginx conf, `-` for stdout
+    pub audit_log: Option<PathBuf>,
+    /// POSTed what changed and how the reload went per change of the nginx conf
+    #[serde(default, with = "url_serde")]
+    pub notify_url: Option<Url>,
+    pub notify_format: NotifyFormat,
+    pub lock_timeout: u64,
+    pub conf_mode: Option<u32>,
+    pub conf_owner: Option<String>,
+    pub conf_group: Option<String>,
+    pub tls: Option<TlsConfig>,
+    /// nginx is OpenResty, so Lua can be used in mappings
+    #[serde(default)]
+    pub openresty: bool,
+    /// nginx is nginx plus, allowing `sticky cookie`
+    #[serde(default)]
+    pub nginx_plus: bool,
+    /// njs modules for `js_import`, eg. `main from njs/main.js`
+    #[serde(default)]
+    pub js_import: Vec<String>,
+    /// `proxy_set_header`s of the server, repeated in locations setting their own
+    pub default_headers: Vec<(String, String)>,
+    /// Pass trace context headers and make sure every request has an `X-Request-ID`
+    #[serde(default)]
+    pub tracing: bool,
+    /// Listeners expect the PROXY protocol of an L4 load balancer
+    #[serde(default)]
+    pub proxy_protocol: bool,
+    /// Addresses or CIDRs trusted to tell the client address, by PROXY protocol or else `X-Forwarded-For`
+    #[serde(default)]
+    pub real_ip_from: Vec<String>,
+    /// Socket options of `listen`
+    #[serde(default)]
+    pub listen: ListenOptions,
+    /// Write out a whole nginx.conf rather than a file included in its http block
+    #[serde(default)]
+    pub full_conf: bool,
+    /// Settings of worker processes, with `full_conf`
+    #[serde(default)]
+    pub workers: Workers,
+    /// Rewrite only the region between the markers of the nginx conf, appended if missing
+    #[serde(default)]
+    pub managed_region: bool,
+    /// Write out `<nginx_conf>.map.json` along with the nginx conf
+    #[serde(default)]
+    pub source_map: bool,
+    /// Create the directories of `nginx_conf` if they don't exist
+    #[serde(default)]
+    pub mkdirs: bool,
+    /// `proxy_hide_header`s of the server
+    #[serde(default)]
+    pub hide_upstream_headers: Vec<String>,
+    /// `server_tokens off` and, with OpenResty, no `Server` header at all
+    #[serde(default = "default_server_tokens")]
+    pub server_tokens: bool,
+    /// `keepalive_timeout` and the like for slow clients
+    #[serde(default)]
+    pub timeouts: ServerTimeouts,
+    /// `sendfile`, `tcp_nopush`, `tcp_nodelay` and `open_file_cache` for static-heavy servers
+    #[serde(default)]
+    pub performance: bool,
+    /// `charset` of responses, eg. `utf-8`, added to JSON too
+    #[serde(default)]
+    pub charset: Option<String>,
+    /// `default_type` of responses whose type nginx doesn't know
+    #[serde(default)]
+    pub default_type: Option<String>,
+    /// Paths which must not be served, eg. dotfiles under a static root
+    #[serde(default)]
+    pub deny: DenyPaths,
+    /// Where nginx logs to, eg. syslog
+    #[serde(default)]
+    pub log: LogConfig,
+    /// Config files loaded, in order
+    #[serde(skip)]
+    pub sources: Vec<PathBuf>,
+}
+
+fn default_domain() -> String {
+    "localhost".into()
+}
+
+fn default_server_tokens() -> bool {
+    true
+}
+
+impl Default for AppConfig {
+    fn default() -> AppConfig {
+        AppConfig {
+            host: "0.0.0.0".into(),
+            port: 10080,
+            domain: None,
+            default_domain: default_domain(),
+            reverse_proxy: Vec::new(),
+            subdomains: Vec::new(),
+            upstreams: Vec::new(),
+            maps: Vec::new(),
+            statics: Vec::new(),
+            responses: Vec::new(),
+            healthz: None,
+            nginx_conf: PathBuf::from("/etc/nginx/conf.d/default.conf"),
+            on_change: None,
+            reload_check: None,
+            history: 0,
+            audit_log: None,
+            notify_url: None,
+            notify_format: NotifyFormat::Json,
+            lock_timeout: 10,
+            conf_mode: None,
+            conf_owner: None,
+            conf_group: None,
+            tls: None,
+            openresty: false,
+            nginx_plus: false,
+            js_import: Vec::new(),
+            default_headers: DefaultHeaders::Switch(true).resolve(),
+            tracing: false,
+            proxy_protocol: false,
+            real_ip_from: Vec::new(),
+            listen: ListenOptions::default(),
+            full_conf: false,
+            workers: Workers::default(),
+            managed_region: false,
+            source_map: false,
+            mkdirs: false,
+            hide_upstream_headers: Vec::new(),
+            server_tokens: true,
+            timeouts: ServerTimeouts::default(),
+            performance: false,
+            charset: None,
+            default_type: None,
+            deny: DenyPaths::default(),
+            log: LogConfig::default(),
+            sources: Vec::new(),
+        }
+    }
+}
+
+impl AppConfig {
+    /// panic: error in config files or CLI arguments
+    pub fn from_args_and_config(args: Args) -> Result<AppConfig> {
+        let mut settings = config::Config::default();
+        let ConfigSources {
+            globals: mut config_files,
+            sites,
+        } = ConfigSources::from_config_dir(&args.config_dir)?;
+        config_files.extend(args.config_file.iter().cloned());
+        let globals = load_parallel(&config_files, |path| {
+            info!("load config file: {}", path.display());
+            GlobalFile::load(path)
+        })?;
+        // lists are replaced on merge, so the mappings come from the last file defining them
+        let mut reverse_proxy_source = None;
+        let mut subdomains_source = None;
+        for (path, global) in config_files.iter().zip(&globals) {
+            if global.defines_reverse_proxy {
+                reverse_proxy_source = Some(path.display().to_string());
+            }
+            if global.defines_subdomains {
+                subdomains_source = Some(path.display().to_string());
+            }
+        }
+        settings.merge(
+            globals
+                .into_iter()
+                .map(|global| global.file)
+                .collect::<Vec<_>>(),
+        )?;
+        if let Some(ref profile) = args.profile {
+            let overlay = settings
+                .get_table(&format!("profiles.{}", profile))
+                .map_err(|err| {
+                    Error::ConfigLoad(format!("Profile not found: {} ({})", profile, err))
+                })?;
+            info!("apply profile: {}", profile);
+            if overlay.contains_key("reverse_proxy") {
+                reverse_proxy_source = Some(format!("profiles.{}", profile));
+            }
+            if overlay.contains_key("subdomains") {
+                subdomains_source = Some(format!("profiles.{}", profile));
+            }
+            apply_overlay(&mut settings, "", overlay)?;
+        }
+        trace!("settings: {:#?}", settings);
+
+        let RawAppConfig {
+            host: rac_host,
+            port: rac_port,
+            domain: rac_domain,
+            default_domain: rac_default_domain,
+            reverse_proxy: rac_reverse_proxy,
+            subdomains: rac_subdomains,
+            default_backend: rac_default_backend,
+            upstreams: rac_upstreams,
+            maps: rac_maps,
+            statics: rac_statics,
+            responses: rac_responses,
+            healthz: rac_healthz,
+            nginx_conf: rac_nginx_conf,
+            on_change: rac_on_change,
+            reload_check: rac_reload_check,
+            history: rac_history,
+            audit_log: rac_audit_log,
+            notify_url: rac_notify_url,
+            notify_format: rac_notify_format,
+            conf_mode: rac_conf_mode,
+            conf_owner: rac_conf_owner,
+            conf_group: rac_conf_group,
+            rewrite_localhost: rac_rewrite_localhost,
+            nginx_container: rac_nginx_container,
+            openresty: rac_openresty,
+            nginx_plus: rac_nginx_plus,
+            js_import: rac_js_import,
+            tls: rac_tls,
+            default_headers: rac_default_headers,
+            tracing: rac_tracing,
+            proxy_protocol: rac_proxy_protocol,
+            real_ip_from: rac_real_ip_from,
+            listen: rac_listen,
+            full_conf: rac_full_conf,
+            workers: rac_workers,
+            managed_region: rac_managed_region,
+            source_map: rac_source_map,
+            mkdirs: rac_mkdirs,
+            hide_upstream_headers: rac_hide_upstream_headers,
+            server_tokens: rac_server_tokens,
+            timeouts: rac_timeouts,
+            performance: rac_performance,
+            charset: rac_charset,
+            default_type: rac_default_type,
+            deny: rac_deny,
+            log: rac_log,
+        } = {
+            let raw_app_config = settings.try_into()?;
+            debug!("raw_app_config: {:#?}", raw_app_config);
+            raw_app_config
+        };
+
+        let Args {
+            host: args_host,
+            port: args_port,
+            domain: args_domain,
+            default_domain: args_default_domain,
+            reverse_proxy: args_reverse_proxy,
+            stdin_mappings: _,
+            subdomain: args_subdomain,
+            default_backend: args_default_backend,
+            nginx_conf: args_nginx_conf,
+            config_dir: _,
+            config_file: _,
+            profile: _,
+            on_change: args_on_change,
+            reload_check: args_reload_check,
+            history: args_history,
+            audit_log: args_audit_log,
+            notify_url: args_notify_url,
+            notify_format: args_notify_format,
+            lock_timeout,
+            changed_exit_code: _,
+            strict: _,
+            conf_mode: args_conf_mode,
+            conf_owner: args_conf_owner,
+            conf_group: args_conf_group,
+            rewrite_localhost: args_rewrite_localhost,
+            nginx_container: args_nginx_container,
+            openresty: args_openresty,
+            full_conf: args_full_conf,
+            managed_region: args_managed_region,
+            source_map: args_source_map,
+            mkdirs: args_mkdirs,
+            emit_model: _,
+            deploy: _,
+            deploy_reload: _,
+            format: _,
+            configmap_name: _,
+            configmap_namespace: _,
+            log_format: _,
+            verbose: _,
+            command: _,
+        } = args;
+
+        let mut reverse_proxy: Vec<ReverseProxyMapping> = args_reverse_proxy
+            .into_iter()
+            .map(|rp| ReverseProxyMapping {
+                source: Some("command line".into()),
+                ..rp
+            })
+            .chain(rac_reverse_proxy.into_iter().map(|rp| ReverseProxyMapping {
+                source: reverse_proxy_source.clone(),
+                ..rp
+            }))
+            .collect();
+        for mappings in load_parallel(&sites, load_site)? {
+            reverse_proxy.extend(mappings);
+        }
+        config_files.extend(sites);
+        let mut reverse_proxy: Vec<ReverseProxyMapping> = reverse_proxy
+            .into_iter()
+            .flat_map(ReverseProxyMapping::expand_paths)
+            .collect();
+        if let Some(url) = args_default_backend.or(rac_default_backend) {
+            if reverse_proxy.iter().all(|rp| rp.path != "/")
+                && rac_statics.iter().all(|st| st.path != "/")
+            {
+                reverse_proxy.push(ReverseProxyMapping {
+                    source: Some("default_backend".into()),
+                    ..ReverseProxyMapping::new("/".into(), url)
+                });
+            }
+        }
+        let rewrite_localhost = args_rewrite_localhost || rac_rewrite_localhost.unwrap_or(false);
+        let mut networks = Vec::new();
+        let mut upstreams = rac_upstreams;
+        for rp in &mut reverse_proxy {
+            rp.path = normalize_path(&rp.path);
+            discover_upstream(rp, &mut upstreams)?;
+            normalize_scheme(rp);
+            check_localhost(rp, rewrite_localhost);
+            read_required_header(rp)?;
+            if let Some(network) = resolve_address(rp)? {
+                networks.push((rp.path.clone(), network));
+            }
+        }
+        let reverse_proxy = fold_routes(reverse_proxy)?;
+        let mut subdomains: Vec<SubdomainMapping> = args_subdomain
+            .into_iter()
+            .map(|sd| (sd, Some("command line".to_string())))
+            .chain(
+                rac_subdomains
+                    .into_iter()
+                    .map(|sd| (sd, subdomains_source.clone())),
+            )
+            .map(|(sd, source)| SubdomainMapping {
+                mapping: ReverseProxyMapping {
+                    path: if sd.mapping.path.is_empty() {
+                        "/".into()
+                    } else {
+                        sd.mapping.path
+                    },
+                    source,
+                    ..sd.mapping
+                },
+                ..sd
+            })
+            .collect();
+        for sd in &mut subdomains {
+            sd.mapping.path = normalize_path(&sd.mapping.path);
+            discover_upstream(&mut sd.mapping, &mut upstreams)?;
+            normalize_scheme(&mut sd.mapping);
+            check_localhost(&mut sd.mapping, rewrite_localhost);
+            read_required_header(&mut sd.mapping)?;
+            if let Some(network) = resolve_address(&mut sd.mapping)? {
+                networks.push((sd.domain.clone(), network));
+            }
+        }
+        if let Some(ref nginx_container) = args_nginx_container.or(rac_nginx_container) {
+            check_networks(nginx_container, &networks)?;
+        }
+        let tracing = rac_tracing.unwrap_or(false);
+        let mut default_headers = rac_default_headers
+            .unwrap_or(DefaultHeaders::Switch(true))
+            .resolve();
+        if tracing {
+            for (name, value) in crate::TRACING_HEADERS {
+                if !default_headers
+                    .iter()
+                    .any(|(own, _)| own.eq_ignore_ascii_case(name))
+                {
+                    default_headers.push((name.to_string(), value.to_string()));
+                }
+            }
+        }
+        let app_config = AppConfig {
+            host: args_host.or(rac_host).unwrap_or_else(|| "0.0.0.0".into()),
+            port: args_port.or(rac_port).unwrap_or(10080),
+            domain: args_domain.or(rac_domain),
+            default_domain: args_default_domain
+                .or(rac_default_domain)
+                .unwrap_or_else(default_domain),
+            reverse_proxy,
+            subdomains,
+            upstreams,
+            maps: rac_maps,
+            statics: rac_statics,
+            responses: rac_responses,
+            healthz: rac_healthz,
+            nginx_conf: args_nginx_conf
+                .or(rac_nginx_conf)
+                .unwrap_or_else(|| PathBuf::from("/etc/nginx/conf.d/default.conf")),
+            on_change: args_on_change.or(rac_on_change),
+            reload_check: args_reload_check.or(rac_reload_check),
+            history: args_history.or(rac_history).unwrap_or(0),
+            audit_log: args_audit_log.or(rac_audit_log),
+            notify_url: args_notify_url.or(rac_notify_url),
+            notify_format: args_notify_format
+                .or(rac_notify_format)
+                .unwrap_or(NotifyFormat::Json),
+            lock_timeout,
+            conf_mode: match args_conf_mode {
+                Some(mode) => Some(mode),
+                None => rac_conf_mode
+                    .as_ref()
+                    .map(|mode| parse_conf_mode(mode))
+                    .transpose()?,
+            },
+            conf_owner: args_conf_owner.or(rac_conf_owner),
+            conf_group: args_conf_group.or(rac_conf_group),
+            tls: rac_tls,
+            openresty: args_openresty || rac_openresty.unwrap_or(false),
+            nginx_plus: rac_nginx_plus.unwrap_or(false),
+            js_import: rac_js_import,
+            default_headers,
+            tracing,
+            proxy_protocol: rac_proxy_protocol.unwrap_or(false),
+            real_ip_from: rac_real_ip_from,
+            listen: rac_listen.unwrap_or_default(),
+            full_conf: args_full_conf || rac_full_conf.unwrap_or(false),
+            workers: rac_workers.unwrap_or_default().resolve()?,
+            managed_region: args_managed_region || rac_managed_region.unwrap_or(false),
+            source_map: args_source_map || rac_source_map.unwrap_or(false),
+            mkdirs: args_mkdirs || rac_mkdirs.unwrap_or(false),
+            hide_upstream_headers: rac_hide_upstream_headers
+                .map(|hide| hide.resolve())
+                .unwrap_or_default(),
+            server_tokens: rac_server_tokens.unwrap_or(true),
+            timeouts: rac_timeouts.unwrap_or_default(),
+            performance: rac_performance.unwrap_or(false),
+            charset: rac_charset,
+            default_type: rac_default_type,
+            deny: rac_deny.unwrap_or_default(),
+            log: rac_log.unwrap_or_default(),
+            sources: config_files,
+        };
+        app_config.validate()?;
+        Ok(app_config)
+    }
+
+    /// `domain`, or else `default_domain`.
+    pub fn server_name(&self) -> &str {
+        self.domain.as_deref().unwrap_or(&self.default_domain)
+    }
+
+    /// Whether there is a server block of `domain`, which there isn't with subdomains only.
+    pub fn renders_main_server(&self) -> bool {
+        !self.reverse_proxy.is_empty()
+            || !self.statics.is_empty()
+            || !self.responses.is_empty()
+            || self.subdomains.is_empty()
+    }
+
+    /// Every server block listens on the same address, where nginx would only warn
+    /// about a second server block of the same name and ignore it.
+    fn check_server_names(&self) -> Result<()> {
+        let main = if self.renders_main_server() {
+            Some((self.server_name(), "domain".to_owned()))
+        } else {
+            None
+        };
+        let mut seen: Vec<(String, String)> = Vec::new();
+        for (name, origin) in main.into_iter().chain(self.subdomains.iter().map(|sd| {
+            (
+                sd.domain.as_str(),
+                format!(
+                    "subdomain in {}",
+                    sd.mapping.source.as_deref().unwrap_or("a config")
+                ),
+            )
+        })) {
+            let name = name.to_ascii_lowercase();
+            if let Some((_, first)) = seen.iter().find(|(seen, _)| *seen == name) {
+                return Err(Error::Render(format!(
+                    "server_name {} on {}:{} is given by both {} and {}",
+                    name, self.host, self.port, first, origin
+                )));
+            }
+            seen.push((name, origin));
+        }
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if let Some(ref domain) = self.domain {
+            check_literal("domain", domain)?;
+        }
+        for sd in &self.subdomains {
+            check_literal("subdomain", &sd.domain)?;
+        }
+        self.check_server_names()?;
+        check_literal("host", &self.host)?;
+        check_literal("default_domain", &self.default_domain)?;
+        for address in &self.real_ip_from {
+            check_literal("real_ip_from", address)?;
+        }
+        if let Some(ref so_keepalive) = self.listen.so_keepalive {
+            check_literal("so_keepalive", so_keepalive)?;
+        }
+        for name in &self.hide_upstream_headers {
+            check_literal("header name", name)?;
+        }
+        for map in &self.maps {
+            check_literal("map source", &map.source)?;
+            check_literal("map variable", &map.variable)?;
+        }
+        for import in &self.js_import {
+            let words: Vec<&str> = import.split_whitespace().collect();
+            if !(words.len() == 1 || words.len() == 3 && words[1] == "from") {
+                return Err(Error::parse(
+                    import.as_str(),
+                    "js_import is like `main from njs/main.js` or `njs/main.js`",
+                ));
+            }
+            for word in words {
+                check_literal("js_import", word)?;
+            }
+        }
+        for (name, _) in &self.default_headers {
+            check_literal("header name", name)?;
+        }
+        for upstream in &self.upstreams {
+            check_literal("upstream name", &upstream.name)?;
+            if let Some(ref cookie) = upstream.sticky_cookie {
+                check_literal("sticky_cookie", cookie)?;
+            }
+            for server in &upstream.servers {
+                check_literal("upstream server", &server.address)?;
+            }
+        }
+        for path in crate::certificate_files(self) {
+            check_literal("certificate path", &path.to_string_lossy())?;
+        }
+        if let Some(hardening) = self.tls.as_ref().and_then(|tls| tls.hardening.as_ref()) {
+            for protocol in &hardening.protocols {
+                check_literal("tls.hardening.protocols", protocol)?;
+            }
+            check_literal("tls.hardening.ciphers", &hardening.ciphers)?;
+            check_literal("tls.hardening.session_cache", &hardening.session_cache)?;
+            check_literal("tls.hardening.session_timeout", &hardening.session_timeout)?;
+        }
+        if let Some(ref tls) = self.tls {
+            if tls.certificate.is_some() != tls.certificate_key.is_some() {
+                return Err(Error::Render(
+                    "tls.certificate and tls.certificate_key must be set together".into(),
+                ));
+            }
+            if tls.certificate_for(self.domain.as_deref()).is_none() {
+                return Err(Error::Render(format!(
+                        "no certificate for {}: set tls.certificate, tls.certificates or tls.cert_dir with a domain",
+                        self.domain.as_deref().unwrap_or("the server")
+                    )));
+            }
+        }
+        for map in &self.maps {
+            for variable in &[&map.source, &map.variable] {
+                if !variable.starts_with('$') || variable.len() < 2 {
+                    return Err(Error::parse(
+                        variable.as_str(),
+                        "a map needs $variables for source and variable",
+                    ));
+                }
+            }
+        }
+        for (i, upstream) in self.upstreams.iter().enumerate() {
+            if upstream.servers.is_empty() {
+                return Err(Error::Render(format!(
+                    "upstream {} has no servers",
+                    upstream.name
+                )));
+            }
+            if self.upstreams[..i].iter().any(|u| u.name == upstream.name) {
+                return Err(Error::Render(format!(
+                    "upstream {} is defined twice",
+                    upstream.name
+                )));
+            }
+            if upstream
+                .servers
+                .iter()
+                .any(|server| server.weight == Some(0))
+            {
+                return Err(Error::Render(format!(
+                    "weight must be 1 or more: upstream {}",
+                    upstream.name
+                )));
+            }
+            if upstream.sticky == Some(Sticky::Cookie)
+                && !self.nginx_plus
+                && upstream.sticky_cookie.is_none()
+            {
+                return Err(Error::Render(format!(
+                    "sticky: cookie needs nginx_plus or a sticky_cookie to hash: upstream {}",
+                    upstream.name
+                )));
+            }
+        }
+        if let Some(ref url) = self.reload_check {
+            if url.scheme() != "http" {
+                return Err(Error::parse(
+                    url.as_str(),
+                    "reload_check must be an http URL",
+                ));
+            }
+        }
+        if let Some(ref healthz) = self.healthz {
+            check_path(healthz)?;
+        }
+        for st in &self.statics {
+            check_path(&st.path)?;
+            check_literal("root", &st.root.to_string_lossy())?;
+            for prefix in &st.spa_exclude {
+                check_path(prefix)?;
+            }
+            if let Some(prefix) = st
+                .spa_exclude
+                .iter()
+                .find(|prefix| !prefix.starts_with(&st.path))
+            {
+                return Err(Error::parse(
+                    prefix.as_str(),
+                    format!("spa_exclude must be under {}", st.path),
+                ));
+            }
+            if self.reverse_proxy.iter().any(|rp| rp.path == st.path) {
+                return Err(Error::Render(format!(
+                    "{} is both a static and a reverse_proxy path",
+                    st.path
+                )));
+            }
+        }
+        for response in &self.responses {
+            check_path(&response.path)?;
+            for name in response.headers.keys() {
+                check_literal("header name", name)?;
+            }
+            if !(100..=599).contains(&response.status) {
+                return Err(Error::parse(
+                    response.status.to_string(),
+                    format!("invalid status of {}", response.path),
+                ));
+            }
+        }
+        self.log.validate()?;
+        for rp in self
+            .reverse_proxy
+            .iter()
+            .flat_map(|rp| rp.routes.iter().chain(Some(rp)))
+            .chain(self.subdomains.iter().map(|sd| &sd.mapping))
+        {
+            if let Some(ref format) = rp.log_format {
+                self.log.check_format(format)?;
+            }
+        }
+        if let Some(ref charset) = self.charset {
+            check_literal("charset", charset)?;
+        }
+        if let Some(ref default_type) = self.default_type {
+            check_literal("default_type", default_type)?;
+        }
+        for (name, value) in self.timeouts.directives() {
+            // nginx times, eg. `30s`, `1m30s`, or a bare number of seconds
+            if !value.starts_with(|c: char| c.is_ascii_digit())
+                || !value.chars().all(|c| c.is_ascii_alphanumeric())
+            {
+                return Err(Error::parse(value, format!("invalid time of {}", name)));
+            }
+        }
+        if self.workers.processes == 0 || self.workers.connections == 0 {
+            return Err(Error::Render(
+                "workers.processes and workers.connections must be 1 or more".into(),
+            ));
+        }
+        for sd in &self.subdomains {
+            if sd.mapping.match_header.is_some() || sd.mapping.match_cookie.is_some() {
+                return Err(Error::Render(format!(
+                    "match_header and match_cookie are only for reverse_proxy: {}",
+                    sd.domain
+                )));
+            }
+            if let Some(ref tls) = self.tls {
+                if tls.certificate_for(Some(&sd.domain)).is_none() {
+                    return Err(Error::Render(format!(
+                            "no certificate for {}: set tls.certificate, tls.certificates or tls.cert_dir",
+                            sd.domain
+                        )));
+                }
+            }
+        }
+        if let Some(ref tls) = self.tls {
+            // certbot may not have issued them yet, so nginx is left to fail
+            let mut missing = Vec::new();
+            let domains = std::iter::once(self.domain.as_deref())
+                .chain(self.subdomains.iter().map(|sd| Some(sd.domain.as_str())));
+            for pair in domains.filter_map(|domain| tls.certificate_for(domain)) {
+                for path in [pair.certificate, pair.certificate_key] {
+                    if !path.exists() && !missing.contains(&path) {
+                        diagnostics::warn(
+                            Category::Certificate,
+                            format!("no such file: {}", path.display()),
+                        );
+                        missing.push(path);
+                    }
+                }
+            }
+        }
+        for rp in self
+            .reverse_proxy
+            .iter()
+            .chain(self.subdomains.iter().map(|sd| &sd.mapping))
+        {
+            rp.validate()?;
+            if rp.require_client_certificate
+                && self
+                    .tls
+                    .as_ref()
+                    .and_then(|tls| tls.client_certificate.as_ref())
+                    .is_none()
+            {
+                return Err(Error::Render(format!(
+                    "require_client_certificate needs tls.client_certificate: {}",
+                    rp.path
+                )));
+            }
+            if (rp.access_by_lua.is_some() || rp.content_by_lua.is_some()) && !self.openresty {
+                return Err(Error::Render(format!(
+                    "Lua needs OpenResty, set openresty or --openresty: {}",
+                    rp.path
+                )));
+            }
+            if rp.content_by_lua.is_some() && rp.js_content.is_some() {
+                return Err(Error::Render(format!(
+                    "content_by_lua and js_content can't be used together: {}",
+                    rp.path
+                )));
+            }
+            for function in rp.js_content.iter().chain(rp.js_header_filter.iter()) {
+                let module = function.split('.').next().unwrap_or_default();
+                if !self
+                    .js_import
+                    .iter()
+                    .any(|import| js_module_name(import) == module)
+                {
+                    return Err(Error::parse(
+                        function.as_str(),
+                        format!("no js_import for the module of {}", rp.path),
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn config_dir_default_not_specified() {
+        use structopt::StructOpt;
+        let cli_args: &[&str] = &["test"];
+        let args = Args::from_iter_safe(cli_args.iter()).unwrap();
+        assert_eq!(
+            "/conf".to_string(),
+            format!("{}", args.config_dir.display())
+        );
+    }
+
+    #[test]
+    fn config_dir_default_omit_value() {
+        use structopt::StructOpt;
+        let cli_args: &[&str] = &["test", "--config-dir"];
+        let args = Args::from_iter_safe(cli_args.iter()).unwrap();
+        assert_eq!(
+            "/conf".to_string(),
+            format!("{}", args.config_dir.display())
+        );
+    }
+
+    #[test]
+    fn config_dir_custom_relative_path() {
+        use structopt::StructOpt;
+        let cli_args: &[&str] = &["test", "--config-dir", "./conf"];
+        let args = Args::from_iter_safe(cli_args.iter()).unwrap();
+        assert_eq!(
+            "./conf".to_string(),
+            format!("{}", args.config_dir.display())
+        );
+    }
+
+    #[test]
+    fn config_dir_custom_absolute_path() {
+        use structopt::StructOpt;
+        let cli_args: &[&str] = &["test", "--config-dir", "/path/to/conf"];
+        let args = Args::from_iter_safe(cli_args.iter()).unwrap();
+        assert_eq!(
+            "/path/to/conf".to_string(),
+            format!("{}", args.config_dir.display())
+        );
+    }
+
+    #[test]
+    fn config_dir_custom_relative_path_with_trailing_slash() {
+        use structopt::StructOpt;
+        let cli_args: &[&str] = &["test", "--config-dir", "./conf/"];
+        let args = Args::from_iter_safe(cli_args.iter()).unwrap();
+        assert_eq!(
+            "./conf".to_string(),
+            format!("{}", args.config_dir.display())
+        );
+    }
+
+    #[test]
+    fn config_dir_custom_absolute_path_with_trailing_slash() {
+        use structopt::StructOpt;
+        let cli_args: &[&str] = &["test", "--config-dir", "/path/to/conf/"];
+        let args = Args::from_iter_safe(cli_args.iter()).unwrap();
+        assert_eq!(
+            "/path/to/conf".to_string(),
+            format!("{}", args.config_dir.display())
+        );
+    }
+
+    #[test]
+    fn nginx_conf_path_default_args() {
+        use structopt::StructOpt;
+        let cli_args: &[&str] = &["test"];
+        let args = Args::from_iter_safe(cli_args.iter()).unwrap();
+        assert_eq!(None, args.nginx_conf);
+    }
+
+    #[test]
+    fn nginx_conf_path_custom_args() {
+        use structopt::StructOpt;
+        let cli_args: &[&str] = &["test", "--nginx-conf", "/etc/nginx/conf.d/custom.conf"];
+        let args = Args::from_iter_safe(cli_args.iter()).unwrap();
+        assert_eq!(
+            Some(PathBuf::from("/etc/nginx/conf.d/custom.conf")),
+            args.nginx_conf
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "--nginx-conf without value")]
+    fn nginx_conf_path_empty_args() {
+        use structopt::StructOpt;
+        let cli_args: &[&str] = &["test", "--nginx-conf"];
+        Args::from_iter_safe(cli_args.iter()).expect("--nginx-conf without value");
+    }
+
+    #[test]
+    fn nginx_conf_path_default_app_config() {
+        use structopt::StructOpt;
+        let cli_args: &[&str] = &["test"];
+        let args = Args::from_iter_safe(cli_args.iter()).unwrap();
+        let app_config = AppConfig::from_args_and_config(args).unwrap();
+        assert_eq!(
+            PathBuf::from("/etc/nginx/conf.d/default.conf"),
+            app_config.nginx_conf
+        );
+    }
+
+    #[test]
+    fn nginx_conf_path_custom_app_config() {
+        use structopt::StructOpt;
+        let cli_args: &[&str] = &["test", "--config-dir", "./tests/conf_nginx_dir"];
+        let args = Args::from_iter_safe(cli_args.iter()).unwrap();
+        let app_config = AppConfig::from_args_and_config(args).unwrap();
+        assert_eq!(
+            PathBuf::from("./tmp/nginx_default.conf"),
+            app_config.nginx_conf
+        );
+    }
+
+    #[test]
+    fn conf_mode_octal() {
+        assert_eq!(0o640, parse_conf_mode("0640").unwrap());
+        assert_eq!(0o600, parse_conf_mode("600").unwrap());
+        assert!(parse_conf_mode("0999").is_err());
+        assert!(parse_conf_mode("17777").is_err());
+    }
+
+    #[test]
+    fn dev_cert_command() {
+        use structopt::StructOpt;
+        let cli_args: &[&str] = &["test", "-p", "8443", "dev-cert", "app.localhost"];
+        let args = Args::from_iter_safe(cli_args.iter()).unwrap();
+        assert_eq!(Some(8443), args.port);
+        match args.command {
+            Some(Command::DevCert {
+                domain,
+                cert_dir,
+                force,
+            }) => {
+                assert_eq!("app.localhost", domain);
+                assert_eq!(PathBuf::from("/certs"), cert_dir);
+                assert!(!force);
+            }
+            command => panic!("unexpected command: {:?}", command),
+        }
+    }
+
+    #[test]
+    fn normalize_and_validate_paths() {
+        assert_eq!("/api/v1/", normalize_path("//api///v1//"));
+        let mut rp = ReverseProxyMapping::parse("/a b:http://app:3000").unwrap();
+        rp.source = Some("conf/sites/app.yaml".into());
+        match rp.validate() {
+            Err(Error::Parse { input, reason }) => {
+                assert_eq!("/a b", input);
+                assert!(reason.contains("conf/sites/app.yaml"));
+            }
+            result => panic!("unexpected result: {:?}", result),
+        }
+        for path in &["api", "/{api}"] {
+            rp.path = path.to_string();
+            assert!(rp.validate().is_err());
+        }
+    }
+
+    #[test]
+    fn reject_directive_injection() {
+        let rp = ReverseProxyMapping::parse("/a:http://app:3000/;return 200").unwrap();
+        assert!(rp.validate().is_err());
+        let rp = ReverseProxyMapping::parse("/a;:http://app:3000/").unwrap();
+        assert!(rp.validate().is_err());
+
+        let mut app_config = AppConfig {
+            domain: Some("example.com; include /etc/passwd".into()),
+            ..Default::default()
+        };
+        assert!(matches!(app_config.validate(), Err(Error::Parse { .. })));
+        app_config.domain = Some("*.example.com".into());
+        app_config.validate().unwrap();
+    }
+
+    #[test]
+    fn conflicting_server_names() {
+        let subdomain = |domain: &str| SubdomainMapping {
+            domain: domain.into(),
+            mapping: ReverseProxyMapping::parse("/:http://app:3000").unwrap(),
+        };
+        let mut app_config = AppConfig {
+            domain: Some("example.com".into()),
+            subdomains: vec![subdomain("api.example.com"), subdomain("API.example.com")],
+            ..Default::default()
+        };
+        assert!(matches!(app_config.validate(), Err(Error::Render(_))));
+        app_config.subdomains = vec![subdomain("api.example.com"), subdomain("example.com")];
+        app_config.validate().unwrap();
+        app_config.reverse_proxy = vec![ReverseProxyMapping::parse("/:http://web:3000").unwrap()];
+        assert!(matches!(app_config.validate(), Err(Error::Render(_))));
+    }
+
+    #[test]
+    fn load_parallel_in_order() {
+        let paths: Vec<PathBuf> = (0..20).map(|i| PathBuf::from(i.to_string())).collect();
+        let loaded = load_parallel(&paths, |path| Ok(path.display().to_string())).unwrap();
+        assert_eq!(
+            paths
+                .iter()
+                .map(|path| path.display().to_string())
+                .collect::<Vec<_>>(),
+            loaded
+        );
+        let failed = load_parallel(&paths, |path| match path.to_str() {
+            Some("3") | Some("15") => Err(Error::ConfigLoad(path.display().to_string())),
+            _ => Ok(()),
+        });
+        assert!(matches!(failed, Err(Error::ConfigLoad(ref path)) if path == "3"));
+    }
+
+    #[test]
+    fn upstream_schemes() {
+        let reason = |s: &str| match ReverseProxyMapping::parse(s).unwrap().validate() {
+            Err(Error::Parse { reason, .. }) => reason,
+            result => panic!("unexpected result: {:?}", result),
+        };
+        assert!(reason("/->api:3000").contains("eg. http://api:3000"));
+        assert!(reason("/:tcp://db:5432").contains("stream module"));
+        assert!(reason("/:ftp://files").contains("unsupported scheme ftp"));
+
+        let mut rp = ReverseProxyMapping::parse("/ws:wss://app:3000/ws").unwrap();
+        rp.validate().unwrap();
+        normalize_scheme(&mut rp);
+        assert_eq!("https://app:3000/ws", rp.url.as_str());
+        assert!(rp.websocket);
+    }
+
+    #[test]
+    fn srv_url_name() {
+        let rp = ReverseProxyMapping::parse("/api:srv://_http._tcp.api.service.consul/v1").unwrap();
+        assert_eq!(Some("_http._tcp.api.service.consul"), rp.url.host_str());
+        rp.validate().unwrap();
+
+        let mut upstreams = Vec::new();
+        let mut rp = ReverseProxyMapping::parse("/api:http://api:3000").unwrap();
+        discover_upstream(&mut rp, &mut upstreams).unwrap();
+        assert_eq!("http://api:3000/", rp.url.as_str());
+        let mut rp = ReverseProxyMapping::parse("/api:srv:///v1").unwrap();
+        assert!(discover_upstream(&mut rp, &mut upstreams).is_err());
+        assert!(upstreams.is_empty());
+    }
+
+    #[test]
+    fn env_url_upstream() {
+        env::set_var("RP_TEST_BACKENDS", "http://app-1:3000, http://app-2:3000,");
+        let mut upstreams = Vec::new();
+        let mut rp = ReverseProxyMapping::parse("/:env://RP_TEST_BACKENDS/app").unwrap();
+        discover_upstream(&mut rp, &mut upstreams).unwrap();
+        assert_eq!("http://env_rp_test_backends/app", rp.url.as_str());
+        assert_eq!(
+            vec!["app-1:3000", "app-2:3000"],
+            upstreams[0]
+                .servers
+                .iter()
+                .map(|server| server.address.as_str())
+                .collect::<Vec<_>>()
+        );
+
+        env::set_var("RP_TEST_BACKENDS", "http://app-1:3000,https://app-2");
+        assert!(env_upstream("RP_TEST_BACKENDS").is_err());
+        env::remove_var("RP_TEST_BACKENDS");
+        assert!(env_upstream("RP_TEST_BACKENDS").is_err());
+    }
+
+    #[test]
+    fn fold_route_mappings() {
+        let parse = |s| ReverseProxyMapping::parse(s).unwrap();
+        let folded = fold_routes(vec![
+            parse("/api:http://api-acme:3000/v1?match_header=X-Tenant:acme"),
+            parse("/api:http://api:3000/v1"),
+            parse("/api:http://api-v2:3000/v1?match_cookie=version=v2"),
+            parse("/:http://web:3000"),
+        ])
+        .unwrap();
+        assert_eq!(2, folded.len());
+        assert_eq!(
+            vec!["http://api-acme:3000/v1", "http://api-v2:3000/v1"],
+            folded[0]
+                .routes
+                .iter()
+                .map(|route| route.url.as_str())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            Some(("$http_x_tenant".to_owned(), "acme".to_owned())),
+            folded[0].routes[0].route_match()
+        );
+        assert_eq!(
+            Some(("$cookie_version".to_owned(), "v2".to_owned())),
+            folded[0].routes[1].route_match()
+        );
+        folded[0].validate().unwrap();
+
+        assert!(fold_routes(vec![parse("/api:http://api:3000?match_cookie=v=2")]).is_err());
+        assert!(fold_routes(vec![
+            parse("/api:http://api:3000/v1"),
+            parse("/api:http://api-v2:3000/v2?match_cookie=v=2"),
+        ])
+        .is_err());
+        assert!(parse("/api:http://api:3000?match_header=X-Tenant")
+            .validate()
+            .is_err());
+    }
+
+    #[test]
+    fn stdin_mappings() {
+        use structopt::StructOpt;
+        let stdin = "# from docker ps\n/a:http://a:3000\n\n/b:http://b:3000?websocket\n";
+        let cli_args: &[&str] = &["test", "-r", "/:http://web:3000", "-r", "-"];
+        let mut args = Args::from_iter_safe(cli_args.iter()).unwrap();
+        args.read_stdin_mappings(stdin.as_bytes()).unwrap();
+        let paths: Vec<_> = args
+            .reverse_proxy
+            .iter()
+            .map(|rp| rp.path.as_str())
+            .collect();
+        assert_eq!(vec!["/", "/a", "/b"], paths);
+
+        let cli_args: &[&str] = &["test", "-r", "/:http://web:3000"];
+        let mut args = Args::from_iter_safe(cli_args.iter()).unwrap();
+        args.read_stdin_mappings(stdin.as_bytes()).unwrap();
+        assert_eq!(1, args.reverse_proxy.len());
+    }
+
+    #[test]
+    fn tls_hardening_switch_app_config() {
+        use structopt::StructOpt;
+        let cli_args: &[&str] = &["test", "--config-dir", "./tests/conf_tls_dir"];
+        let args = Args::from_iter_safe(cli_args.iter()).unwrap();
+        let app_config = AppConfig::from_args_and_config(args).unwrap();
+        assert_eq!(
+            Some(TlsHardening::default()),
+            app_config.tls.unwrap().hardening
+        );
+    }
+
+    #[test]
+    fn cert_status_command_after_reverse_proxy() {
+        use structopt::StructOpt;
+        let cli_args: &[&str] = &["test", "-r", "/a:http://a/", "cert", "status"];
+        let args = Args::from_iter_safe(cli_args.iter()).unwrap();
+        assert_eq!(1, args.reverse_proxy.len());
+        match args.command {
+            Some(Command::Cert {
+                command: CertCommand::Status { warn_days },
+            }) => assert_eq!(30, warn_days),
+            command => panic!("unexpected command: {:?}", command),
+        }
+    }
+
+    #[test]
+    fn certificate_for_domain() {
+        let tls = TlsConfig {
+            cert_dir: Some(PathBuf::from("/certs")),
+            certificates: vec![DomainCertificate {
+                domain: "api.example.com".into(),
+                certificate: PathBuf::from("/etc/ssl/api.crt"),
+                certificate_key: PathBuf::from("/etc/ssl/api.key"),
+            }],
+            ..Default::default()
+        };
+        assert_eq!(
+            Some(PathBuf::from("/etc/ssl/api.crt")),
+            tls.certificate_for(Some("api.example.com"))
+                .map(|pair| pair.certificate)
+        );
+        assert_eq!(
+            Some(CertificatePair {
+                certificate: PathBuf::from("/certs/example.com/fullchain.pem"),
+                certificate_key: PathBuf::from("/certs/example.com/privkey.pem"),
+            }),
+            tls.certificate_for(Some("example.com"))
+        );
+        assert_eq!(None, tls.certificate_for(None));
+        assert_eq!(
+            vec![
+                PathBuf::from("/certs/example.com/fullchain.pem"),
+                PathBuf::from("/etc/ssl/api.crt")
+            ],
+            tls.certificate_paths(Some("example.com"))
+        );
+    }
+
+    #[test]
+    fn reverse_proxy_mapping_ipv6() {
+        let rp = ReverseProxyMapping::parse("/api:http://[::1]:3000").unwrap();
+        assert_eq!("/api", rp.path);
+        assert_eq!("http://[::1]:3000/", rp.url.as_str());
+
+        let rp = ReverseProxyMapping::parse("/api->http://[fe80::1]:3000/api").unwrap();
+        assert_eq!("/api", rp.path);
+        assert_eq!("http://[fe80::1]:3000/api", rp.url.as_str());
+    }
+
+    #[test]
+    fn reverse_proxy_mapping_separators() {
+        let rp = ReverseProxyMapping::parse("/a:b:https://app:3000/c").unwrap();
+        assert_eq!("/a:b", rp.path);
+        assert_eq!("https://app:3000/c", rp.url.as_str());
+
+        let rp = ReverseProxyMapping::parse("/a:b -> http://app:3000/").unwrap();
+        assert_eq!("/a:b", rp.path);
+        assert_eq!("http://app:3000/", rp.url.as_str());
+
+        assert!(matches!(
+            ReverseProxyMapping::parse("/api"),
+            Err(Error::Parse { .. })
+        ));
+        assert!(ReverseProxyMapping::parse("/api:localhost:3000").is_err());
+        assert!(ReverseProxyMapping::parse("/api->not a url").is_err());
+    }
+
+    #[test]
+    fn reverse_proxy_mapping_options() {
+        let rp = ReverseProxyMapping::parse(
+            "/ws:http://app:3000?websocket&strip_prefix&timeout=300&proxy_ssl_verify=off",
+        )
+        .unwrap();
+        assert_eq!("/ws", rp.path);
+        assert_eq!("http://app:3000/", rp.url.as_str());
+        assert!(rp.websocket);
+        assert!(rp.strip_prefix);
+        assert!(!rp.sse);
+        assert_eq!(Some(300), rp.timeout);
+        assert_eq!(Some(false), rp.proxy_ssl_verify);
+
+        assert!(ReverseProxyMapping::parse("/ws:http://app:3000?no_such_option").is_err());
+        assert!(ReverseProxyMapping::parse("/ws:http://app:3000?timeout=soon").is_err());
+        assert!(ReverseProxyMapping::parse("/ws:http://app:3000?websocket=maybe").is_err());
+    }
+
+    #[test]
+    fn config_file_overrides_config_dir() {
+        use structopt::StructOpt;
+        let cli_args: &[&str] = &[
+            "test",
+            "--config-dir",
+            "./tests/conf_nginx_dir",
+            "--config-file",
+            "./tests/conf_file/override.yaml",
+        ];
+        let args = Args::from_iter_safe(cli_args.iter()).unwrap();
+        let app_config = AppConfig::from_args_and_config(args).unwrap();
+        assert_eq!(PathBuf::from("./tmp/override.conf"), app_config.nginx_conf);
+        assert_eq!(8080, app_config.port);
+    }
+
+    #[test]
+    fn config_dir_recursive() {
+        let sources =
+            ConfigSources::from_config_dir(Path::new("./tests/conf_recursive_dir")).unwrap();
+        assert_eq!(
+            ConfigSources {
+                globals: vec![
+                    PathBuf::from("tests/conf_recursive_dir/conf.yaml"),
+                    PathBuf::from("tests/conf_recursive_dir/globals/listen.toml"),
+                ],
+                sites: vec![
+                    PathBuf::from("tests/conf_recursive_dir/sites/api.yaml"),
+                    PathBuf::from("tests/conf_recursive_dir/sites/web/web.toml"),
+                ],
+            },
+            sources
+        );
+
+        use structopt::StructOpt;
+        let cli_args: &[&str] = &["test", "--config-dir", "./tests/conf_recursive_dir"];
+        let args = Args::from_iter_safe(cli_args.iter()).unwrap();
+        let app_config = AppConfig::from_args_and_config(args).unwrap();
+        assert_eq!(8080, app_config.port);
+        assert_eq!(
+            vec![
+                "tests/conf_recursive_dir/conf.yaml",
+                "tests/conf_recursive_dir/sites/api.yaml",
+                "tests/conf_recursive_dir/sites/web/web.toml",
+            ],
+            app_config
+                .reverse_proxy
+                .iter()
+                .map(|rp| rp.source.as_deref().unwrap())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec!["/", "/api", "/web"],
+            app_config
+                .reverse_proxy
+                .iter()
+                .map(|rp| rp.path.as_str())
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn profile_overrides_base_config() {
+        use structopt::StructOpt;
+        let cli_args: &[&str] = &[
+            "test",
+            "--config-dir",
+            "./tests/conf_profile_dir",
+            "--profile",
+            "prod",
+        ];
+        let args = Args::from_iter_safe(cli_args.iter()).unwrap();
+        let app_config = AppConfig::from_args_and_config(args).unwrap();
+        assert_eq!(443, app_config.port);
+        assert_eq!(Some("example.com".to_string()), app_config.domain);
+        let tls = app_config.tls.unwrap();
+        assert_eq!(Some(PathBuf::from("/certs/prod.crt")), tls.certificate);
+        assert_eq!(Some(PathBuf::from("/certs/dev.key")), tls.certificate_key);
+        assert_eq!(
+            vec!["http://app-prod:3000/"],
+            app_config
+                .reverse_proxy
+                .iter()
+                .map(|rp| rp.url.as_str())
+                .collect::<Vec<_>>()
+        );
+
+        let cli_args: &[&str] = &["test", "--config-dir", "./tests/conf_profile_dir"];
+        let args = Args::from_iter_safe(cli_args.iter()).unwrap();
+        let app_config = AppConfig::from_args_and_config(args).unwrap();
+        assert_eq!(10443, app_config.port);
+    }
+
+    #[test]
+    fn profile_not_found() {
+        use structopt::StructOpt;
+        let cli_args: &[&str] = &[
+            "test",
+            "--config-dir",
+            "./tests/conf_profile_dir",
+            "--profile",
+            "staging",
+        ];
+        let args = Args::from_iter_safe(cli_args.iter()).unwrap();
+        assert!(AppConfig::from_args_and_config(args).is_err());
+    }
+
+    #[test]
+    fn interpolate_env_values() {
+        let lookup = |name: &str| {
+            Ok(match name {
+                "API_HOST" => Some("api".to_string()),
+                "EMPTY" => Some(String::new()),
+                _ => None,
+            })
+        };
+        assert_eq!(
+            "url: http://api:3000/",
+            interpolate_env("url: http://${API_HOST}:${PORT:-3000}/", lookup).unwrap()
+        );
+        assert_eq!(
+            "a: fallback, b: ",
+            interpolate_env("a: ${EMPTY:-fallback}, b: ${EMPTY-fallback}", lookup).unwrap()
+        );
+        assert_eq!(
+            "value: $host ${API_HOST}",
+            interpolate_env("value: $host $${API_HOST}", lookup).unwrap()
+        );
+        assert_eq!(
+            "key: ${remote_addr}${http_user_agent}",
+            interpolate_env("key: ${remote_addr}${http_user_agent}", lookup).unwrap()
+        );
+        assert!(interpolate_env("url: ${API_HOST", lookup).is_err());
+    }
+
+    #[test]
+    fn secret_file_fallback() {
+        let secret =
+            std::env::temp_dir().join(format!("rp-secret-file-test-{}", std::process::id()));
+        fs::write(&secret, "s3cret\n").unwrap();
+        env::set_var("RP_TEST_SECRET_FILE", &secret);
+        assert_eq!(
+            Some("s3cret".to_string()),
+            env_or_secret_file("RP_TEST_SECRET").unwrap()
+        );
+        env::set_var("RP_TEST_SECRET", "from-env");
+        assert_eq!(
+            Some("from-env".to_string()),
+            env_or_secret_file("RP_TEST_SECRET").unwrap()
+        );
+        assert_eq!(None, env_or_secret_file("RP_TEST_NO_SUCH_SECRET").unwrap());
+        env::set_var("RP_TEST_MISSING_SECRET_FILE", "/no/such/secret");
+        assert!(env_or_secret_file("RP_TEST_MISSING_SECRET").is_err());
+    }
+
+    #[test]
+    fn required_header_secret_file() {
+        let secret =
+            std::env::temp_dir().join(format!("rp-require-header-test-{}", std::process::id()));
+        fs::write(&secret, "t0ken\r\n").unwrap();
+        let mut rp = ReverseProxyMapping::parse(&format!(
+            "/hook:http://hook:3000?require_header=X-Token&require_header_file={}",
+            secret.display()
+        ))
+        .unwrap();
+        rp.validate().unwrap();
+        read_required_header(&mut rp).unwrap();
+        assert_eq!(
+            Some("t0ken"),
+            rp.require_header.as_ref().unwrap().value.as_deref()
+        );
+        assert!(!serde_json::to_string(&rp).unwrap().contains("t0ken"));
+
+        let rp =
+            ReverseProxyMapping::parse("/hook:http://hook:3000?require_header=X-Token").unwrap();
+        assert!(rp.validate().is_err());
+    }
+
+    #[test]
+    fn config_file_missing() {
+        use structopt::StructOpt;
+        let cli_args: &[&str] = &["test", "--config-file", "./tests/no_such_file.yaml"];
+        let args = Args::from_iter_safe(cli_args.iter()).unwrap();
+        assert!(matches!(
+            AppConfig::from_args_and_config(args),
+            Err(Error::Io { .. })
+        ));
+    }
+
+    #[test]
+    fn default_backend_last_unless_root_mapped() {
+        use structopt::StructOpt;
+        let cli_args: &[&str] = &[
+            "test",
+            "--default-backend",
+            "http://web:3000/",
+            "-r",
+            "/api:http://api:3000/api",
+        ];
+        let args = Args::from_iter_safe(cli_args.iter()).unwrap();
+        let app_config = AppConfig::from_args_and_config(args).unwrap();
+        let last = app_config.reverse_proxy.last().unwrap();
+        assert_eq!("/", last.path);
+        assert_eq!("http://web:3000/", last.url.as_str());
+
+        let cli_args: &[&str] = &[
+            "test",
+            "--default-backend",
+            "http://web:3000/",
+            "-r",
+            "/:http://app:3000/",
+        ];
+        let args = Args::from_iter_safe(cli_args.iter()).unwrap();
+        let app_config = AppConfig::from_args_and_config(args).unwrap();
+        assert_eq!(1, app_config.reverse_proxy.len());
+        assert_eq!("http://app:3000/", app_config.reverse_proxy[0].url.as_str());
+    }
+
+    #[test]
+    fn paths_aliases() {
+        let rp =
+            ReverseProxyMapping::parse("/api:http://app:3000/?paths=/v1,/v2&websocket").unwrap();
+        let expanded = rp.expand_paths();
+        assert_eq!(
+            vec!["/api", "/v1", "/v2"],
+            expanded
+                .iter()
+                .map(|rp| rp.path.as_str())
+                .collect::<Vec<_>>()
+        );
+        assert!(expanded
+            .iter()
+            .all(|rp| rp.websocket && rp.paths.is_empty()));
+
+        use structopt::StructOpt;
+        let cli_args: &[&str] = &["test", "--config-dir", "./tests/conf_paths_dir"];
+        let args = Args::from_iter_safe(cli_args.iter()).unwrap();
+        let app_config = AppConfig::from_args_and_config(args).unwrap();
+        assert_eq!(
+            vec!["/v1", "/v2"],
+            app_config
+                .reverse_proxy
+                .iter()
+                .map(|rp| rp.path.as_str())
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn rewrite_localhost_upstreams() {
+        let mut rp = ReverseProxyMapping::parse("/api:http://127.0.0.1:3000/api").unwrap();
+        check_localhost(&mut rp, false);
+        assert_eq!("http://127.0.0.1:3000/api", rp.url.as_str());
+        check_localhost(&mut rp, true);
+        assert_eq!("http://host.docker.internal:3000/api", rp.url.as_str());
+
+        for url in &["http://localhost:3000/", "http://[::1]:3000/"] {
+            let mut rp = ReverseProxyMapping::new("/".into(), Url::parse(url).unwrap());
+            check_localhost(&mut rp, true);
+            assert_eq!(Some("host.docker.internal"), rp.url.host_str());
+        }
+
+        let mut rp = ReverseProxyMapping::parse("/api:http://app:3000/api").unwrap();
+        check_localhost(&mut rp, true);
+        assert_eq!(Some("app"), rp.url.host_str());
+    }
+
+    #[test]
+    fn changed_exit_code_args() {
+        use structopt::StructOpt;
+        let cli_args: &[&str] = &["test", "--changed-exit-code", "100"];
+        let args = Args::from_iter_safe(cli_args.iter()).unwrap();
+        assert_eq!(Some(100), args.changed_exit_code);
+    }
+
+    #[test]
+    fn on_change_args() {
+        use structopt::StructOpt;
+        let cli_args: &[&str] = &["test", "--on-change", "nginx -s reload"];
+        let args = Args::from_iter_safe(cli_args.iter()).unwrap();
+        assert_eq!(Some("nginx -s reload".to_string()), args.on_change);
+    }
+}
@@ -0,0 +1,158 @@
+use crate::error::{Error, Result};
+use std::collections::BTreeMap;
+use std::fs;
+use std::net::IpAddr;
+use std::process::Command;
+use std::thread;
+
+/// The networks of `container` by name, with its address on each by `docker inspect`.
+pub fn docker_networks(container: &str) -> Result<BTreeMap<String, String>> {
+    let output = Command::new("docker")
+        .args([
+            "inspect",
+            "--format",
+            "{{json .NetworkSettings.Networks}}",
+            "--",
+        ])
+        .arg(container)
+        .output()
+        .map_err(|err| Error::Docker(format!("docker inspect {}: {}", container, err)))?;
+    if !output.status.success() {
+        return Err(Error::Docker(format!(
+            "docker inspect {}: {}",
+            container,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    parse_networks(&String::from_utf8_lossy(&output.stdout), container)
+}
+
+/// `{"<network>": {"IPAddress": "..."}, ...}` of `docker inspect`.
+fn parse_networks(networks: &str, container: &str) -> Result<BTreeMap<String, String>> {
+    let networks: BTreeMap<String, serde_json::Value> = serde_json::from_str(networks)
+        .map_err(|err| Error::Docker(format!("unexpected networks of {}: {}", container, err)))?;
+    Ok(networks
+        .into_iter()
+        .map(|(name, network)| {
+            let address = network["IPAddress"].as_str().unwrap_or_default().to_owned();
+            (name, address)
+        })
+        .collect())
+}
+
+/// The address of `container` on `network`, or else on the first of its networks by name
+/// where it has one. Returns the network too.
+pub fn pick_address(
+    networks: &BTreeMap<String, String>,
+    container: &str,
+    network: Option<&str>,
+) -> Result<(String, IpAddr)> {
+    let (name, address) = match network {
+        Some(network) => networks
+            .get_key_value(network)
+            .filter(|(_, address)| !address.is_empty())
+            .ok_or_else(|| {
+                Error::Docker(format!(
+                    "{} has no address on {}, but on: {}",
+                    container,
+                    network,
+                    networks.keys().cloned().collect::<Vec<_>>().join(", ")
+                ))
+            })?,
+        None => networks
+            .iter()
+            .find(|(_, address)| !address.is_empty())
+            .ok_or_else(|| Error::Docker(format!("{} has no address on any network", container)))?,
+    };
+    let address = address
+        .parse()
+        .map_err(|err| Error::Docker(format!("address of {}: {}", container, err)))?;
+    Ok((name.clone(), address))
+}
+
+/// CPUs available to this process: the cgroup CPU quota if there is one, which nginx's
+/// `worker_processes auto` ignores, or else the CPUs it may run on.
+pub fn cpu_count() -> u32 {
+    let available = thread::available_parallelism()
+        .map(|n| n.get() as u32)
+        .unwrap_or(1);
+    match cgroup_quota() {
+        Some(quota) => quota.clamp(1, available),
+        None => available,
+    }
+}
+
+fn cgroup_quota() -> Option<u32> {
+    // cgroup v2, then v1
+    let (quota, period) = match fs::read_to_string("/sys/fs/cgroup/cpu.max") {
+        Ok(max) => parse_cpu_max(&max)?,
+        Err(_) => (
+            fs::read_to_string("/sys/fs/cgroup/cpu/cpu.cfs_quota_us")
+                .ok()?
+                .trim()
+                .parse()
+                .ok()?,
+            fs::read_to_string("/sys/fs/cgroup/cpu/cpu.cfs_period_us")
+                .ok()?
+                .trim()
+                .parse()
+                .ok()?,
+        ),
+    };
+    quota_to_cpus(quota, period)
+}
+
+/// `<quota> <period>` of cpu.max, where the quota may be `max`.
+fn parse_cpu_max(max: &str) -> Option<(i64, i64)> {
+    let mut fields = max.split_whitespace();
+    let quota = match fields.next()? {
+        "max" => -1,
+        quota => quota.parse().ok()?,
+    };
+    Some((quota, fields.next()?.parse().ok()?))
+}
+
+/// Rounded up, none for no quota (-1).
+fn quota_to_cpus(quota: i64, period: i64) -> Option<u32> {
+    if quota <= 0 || period <= 0 {
+        return None;
+    }
+    Some(((quota + period - 1) / period) as u32)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn pick_address_of_networks() {
+        let networks = parse_networks(
+                r#"{"back":{"IPAddress":"172.19.0.2"},"front":{"IPAddress":"172.18.0.3"},"host":{"IPAddress":""}}"#,
+                "api",
+            )
+            .unwrap();
+        assert_eq!(
+            ("back".to_owned(), "172.19.0.2".parse::<IpAddr>().unwrap()),
+            pick_address(&networks, "api", None).unwrap()
+        );
+        assert_eq!(
+            ("front".to_owned(), "172.18.0.3".parse::<IpAddr>().unwrap()),
+            pick_address(&networks, "api", Some("front")).unwrap()
+        );
+        assert!(matches!(
+            pick_address(&networks, "api", Some("host")),
+            Err(Error::Docker(_))
+        ));
+        let none = parse_networks(r#"{"none":{"IPAddress":""}}"#, "api").unwrap();
+        assert!(pick_address(&none, "api", None).is_err());
+    }
+
+    #[test]
+    fn cpu_max_quota() {
+        assert_eq!(Some((150000, 100000)), parse_cpu_max("150000 100000\n"));
+        assert_eq!(Some(2), quota_to_cpus(150000, 100000));
+        assert_eq!(Some((-1, 100000)), parse_cpu_max("max 100000\n"));
+        assert_eq!(None, quota_to_cpus(-1, 100000));
+        assert!(cpu_count() >= 1);
+    }
+}
@@ -0,0 +1,110 @@
+use crate::error::{Error, Result};
+use std::io;
+use std::path::Path;
+use std::process::Command;
+use std::str::FromStr;
+
+/// `ssh://[user@]host[:port]:/path` or `ssh://[user@]host[:port]/path`
+#[derive(Debug, Clone, PartialEq)]
+pub struct SshTarget {
+    /// `[user@]host`
+    pub destination: String,
+    pub port: Option<u16>,
+    pub path: String,
+}
+
+impl FromStr for SshTarget {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<SshTarget> {
+        let rest = s
+            .strip_prefix("ssh://")
+            .ok_or_else(|| Error::parse(s, "expected ssh://[user@]host[:port]:/path"))?;
+        let slash = rest
+            .find('/')
+            .ok_or_else(|| Error::parse(s, "missing the remote path"))?;
+        let (authority, path) = rest.split_at(slash);
+        let authority = authority.trim_end_matches(':');
+        let (destination, port) = match authority.rfind(':') {
+            Some(i) => {
+                let port = &authority[i + 1..];
+                (
+                    &authority[..i],
+                    Some(port.parse().map_err(|err| Error::parse(port, err))?),
+                )
+            }
+            None => (authority, None),
+        };
+        if destination.is_empty() || destination.ends_with('@') {
+            return Err(Error::parse(s, "missing the host"));
+        }
+        Ok(SshTarget {
+            destination: destination.to_owned(),
+            port,
+            path: path.to_owned(),
+        })
+    }
+}
+
+fn run(command: &mut Command, context: String) -> Result<()> {
+    info!("run: {}", context);
+    let status = command
+        .status()
+        .map_err(|err| Error::io(context.as_str(), err))?;
+    if !status.success() {
+        return Err(Error::io(
+            context,
+            io::Error::other(format!("failed ({})", status)),
+        ));
+    }
+    Ok(())
+}
+
+/// Copies `local` to the target with scp, then runs `reload` there with ssh.
+pub fn deploy(target: &SshTarget, local: &Path, reload: &str) -> Result<()> {
+    let mut scp = Command::new("scp");
+    let mut ssh = Command::new("ssh");
+    if let Some(port) = target.port {
+        scp.arg("-P").arg(port.to_string());
+        ssh.arg("-p").arg(port.to_string());
+    }
+    let remote = format!("{}:{}", target.destination, target.path);
+    scp.arg("--").arg(local).arg(&remote);
+    run(&mut scp, format!("scp {} {}", local.display(), remote))?;
+    ssh.arg("--").arg(&target.destination).arg(reload);
+    run(&mut ssh, format!("ssh {} {}", target.destination, reload))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_ssh_target() {
+        assert_eq!(
+            SshTarget {
+                destination: "deploy@edge-1".into(),
+                port: None,
+                path: "/etc/nginx/conf.d/default.conf".into(),
+            },
+            "ssh://deploy@edge-1:/etc/nginx/conf.d/default.conf"
+                .parse()
+                .unwrap()
+        );
+        assert_eq!(
+            SshTarget {
+                destination: "edge-1".into(),
+                port: Some(2222),
+                path: "/etc/nginx/nginx.conf".into(),
+            },
+            "ssh://edge-1:2222/etc/nginx/nginx.conf".parse().unwrap()
+        );
+        assert!("scp://edge-1:/etc/nginx/nginx.conf"
+            .parse::<SshTarget>()
+            .is_err());
+        assert!("ssh://edge-1".parse::<SshTarget>().is_err());
+        assert!("ssh://deploy@:/etc/nginx/nginx.conf"
+            .parse::<SshTarget>()
+            .is_err());
+    }
+}
@@ -0,0 +1,87 @@
+use std::fmt;
+use std::sync::Mutex;
+
+/// What a warning is about, the group it is printed under.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Category {
+    Upstream,
+    Mapping,
+    Certificate,
+}
+
+impl fmt::Display for Category {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Category::Upstream => "upstreams",
+            Category::Mapping => "mappings",
+            Category::Certificate => "certificates",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Warning {
+    pub category: Category,
+    pub message: String,
+}
+
+// process-wide, since configs are loaded on other threads too
+static WARNINGS: Mutex<Vec<Warning>> = Mutex::new(Vec::new());
+
+fn warnings() -> std::sync::MutexGuard<'static, Vec<Warning>> {
+    WARNINGS.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+/// Records a problem which doesn't stop the nginx conf from being written,
+/// unless `--strict` is given.
+pub fn warn<S: Into<String>>(category: Category, message: S) {
+    let message = message.into();
+    debug!("warning ({}): {}", category, message);
+    warnings().push(Warning { category, message });
+}
+
+/// The warnings recorded so far, leaving none.
+pub fn take() -> Vec<Warning> {
+    warnings().split_off(0)
+}
+
+/// `warnings` grouped by category, one line each.
+pub fn format(warnings: &[Warning]) -> String {
+    let mut sorted: Vec<&Warning> = warnings.iter().collect();
+    sorted.sort_by_key(|warning| warning.category);
+    let mut lines = String::new();
+    let mut category = None;
+    for warning in sorted {
+        if category != Some(warning.category) {
+            category = Some(warning.category);
+            lines.push_str(&format!("warnings on {}:\n", warning.category));
+        }
+        lines.push_str(&format!("  {}\n", warning.message));
+    }
+    lines
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn take_and_format_grouped() {
+        // other tests load configs at the same time, warning as well
+        let messages = ["no file: a.pem", "localhost: /a", "no file: b.pem"];
+        let ours = |warning: &Warning| messages.contains(&warning.message.as_str());
+        warn(Category::Certificate, messages[0]);
+        // from another thread, as configs are loaded in parallel
+        std::thread::spawn(move || warn(Category::Upstream, messages[1]))
+            .join()
+            .unwrap();
+        warn(Category::Certificate, messages[2]);
+        let warnings: Vec<Warning> = take().into_iter().filter(ours).collect();
+        assert_eq!(3, warnings.len());
+        assert!(!take().iter().any(ours));
+        assert_eq!(
+                "warnings on upstreams:\n  localhost: /a\nwarnings on certificates:\n  no file: a.pem\n  no file: b.pem\n",
+                format(&warnings)
+            );
+    }
+}
@@ -0,0 +1,127 @@
+use crate::conf::ReverseProxyMapping;
+use crate::error::{Error, Result};
+use serde_yaml::{Mapping, Value};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+fn read(file: &Path) -> Result<Mapping> {
+    let content = match fs::read_to_string(file) {
+        Ok(content) => content,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(Mapping::new()),
+        Err(err) => return Err(Error::io(file.display(), err)),
+    };
+    match serde_yaml::from_str(&content) {
+        Ok(Value::Null) => Ok(Mapping::new()),
+        Ok(Value::Mapping(mapping)) => Ok(mapping),
+        Ok(_) => Err(Error::ConfigLoad(format!(
+            "not a mapping at the top: {}",
+            file.display()
+        ))),
+        Err(err) => Err(Error::ConfigLoad(format!("{}: {}", file.display(), err))),
+    }
+}
+
+fn write(file: &Path, config: &Mapping) -> Result<()> {
+    let content = serde_yaml::to_string(config)
+        .map_err(|err| Error::ConfigLoad(format!("{}: {}", file.display(), err)))?;
+    if let Some(dir) = file.parent() {
+        fs::create_dir_all(dir).map_err(|err| Error::io(dir.display(), err))?;
+    }
+    crate::output::replace(file, content)
+}
+
+fn entries(config: &mut Mapping) -> Result<&mut Vec<Value>> {
+    match config
+        .entry("reverse_proxy".into())
+        .or_insert_with(|| Value::Sequence(Vec::new()))
+    {
+        Value::Sequence(entries) => Ok(entries),
+        _ => Err(Error::ConfigLoad("reverse_proxy is not a list".into())),
+    }
+}
+
+fn is_path(entry: &Value, path: &str) -> bool {
+    entry.get("path").and_then(Value::as_str) == Some(path)
+}
+
+/// `mapping` as a config entry, with only the options differing from the defaults.
+fn to_entry(mapping: &ReverseProxyMapping) -> Result<Value> {
+    let mut entry = Mapping::new();
+    entry.insert("path".into(), mapping.path.clone().into());
+    entry.insert("url".into(), mapping.url.as_str().into());
+    for (key, value) in mapping.changed_options() {
+        let value =
+            serde_yaml::to_value(value).map_err(|err| Error::ConfigLoad(err.to_string()))?;
+        entry.insert(key.into(), value);
+    }
+    Ok(Value::Mapping(entry))
+}
+
+/// Adds `mapping` to the `reverse_proxy` of `file`, replacing the one of the same path.
+pub fn add_mapping(file: &Path, mapping: &ReverseProxyMapping) -> Result<()> {
+    mapping.validate()?;
+    let mut config = read(file)?;
+    let entry = to_entry(mapping)?;
+    let entries = entries(&mut config)?;
+    match entries
+        .iter_mut()
+        .find(|entry| is_path(entry, &mapping.path))
+    {
+        Some(existing) => *existing = entry,
+        None => entries.push(entry),
+    }
+    write(file, &config)
+}
+
+/// Removes the mapping of `path` from the `reverse_proxy` of `file`.
+pub fn remove_mapping(file: &Path, path: &str) -> Result<()> {
+    let mut config = read(file)?;
+    let entries = entries(&mut config)?;
+    let len = entries.len();
+    entries.retain(|entry| !is_path(entry, path));
+    if entries.len() == len {
+        return Err(Error::ConfigLoad(format!(
+            "no mapping of {} in {}",
+            path,
+            file.display()
+        )));
+    }
+    write(file, &config)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn add_replace_remove_mappings() {
+        let file = std::env::temp_dir()
+            .join(format!("rp-edit-test-{}", std::process::id()))
+            .join("sites/managed.yaml");
+        let _ = fs::remove_file(&file);
+        let api = ReverseProxyMapping::parse("/api:http://api:3000?websocket").unwrap();
+        add_mapping(&file, &api).unwrap();
+        add_mapping(
+            &file,
+            &ReverseProxyMapping::parse("/:http://web:3000").unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+                "reverse_proxy:\n- path: /api\n  url: http://api:3000/\n  websocket: true\n- path: /\n  url: http://web:3000/\n",
+                fs::read_to_string(&file).unwrap()
+            );
+        let api = ReverseProxyMapping::parse("/api:http://api:3001?timeout=300").unwrap();
+        add_mapping(&file, &api).unwrap();
+        let entries: Vec<ReverseProxyMapping> =
+            serde_yaml::from_value(read(&file).unwrap()["reverse_proxy"].clone()).unwrap();
+        assert_eq!(vec![api], entries[..1].to_vec());
+
+        remove_mapping(&file, "/api").unwrap();
+        assert!(remove_mapping(&file, "/api").is_err());
+        assert_eq!(
+            "reverse_proxy:\n- path: /\n  url: http://web:3000/\n",
+            fs::read_to_string(&file).unwrap()
+        );
+    }
+}
@@ -0,0 +1,80 @@
+use std::fmt;
+use std::io;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Errors grouped by category, so that callers can tell a bad config from a failed write.
+#[derive(Debug)]
+pub enum Error {
+    /// A config directory or file could not be found, read or merged.
+    ConfigLoad(String),
+    /// A value is malformed: a mapping, an option, a mode, an env reference, a certificate...
+    Parse { input: String, reason: String },
+    /// The settings are well-formed but cannot be rendered together.
+    Render(String),
+    /// Reading or writing a file, taking the lock or running a command failed.
+    Io { context: String, source: io::Error },
+    /// Talking to the docker engine failed.
+    Docker(String),
+    /// A certificate is missing or expiring, or could not be generated.
+    Certificate(String),
+    /// Managing htpasswd users failed.
+    Auth(String),
+}
+
+impl Error {
+    pub fn parse<I: Into<String>, R: ToString>(input: I, reason: R) -> Error {
+        Error::Parse {
+            input: input.into(),
+            reason: reason.to_string(),
+        }
+    }
+
+    pub fn io<C: ToString>(context: C, source: io::Error) -> Error {
+        Error::Io {
+            context: context.to_string(),
+            source,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::ConfigLoad(message) => write!(f, "Failed to load config: {}", message),
+            Error::Parse { input, reason } => write!(f, "{}: {}", reason, input),
+            Error::Render(message) => write!(f, "{}", message),
+            Error::Io { context, source } => write!(f, "{}: {}", source, context),
+            Error::Docker(message) => write!(f, "docker: {}", message),
+            Error::Certificate(message) => write!(f, "{}", message),
+            Error::Auth(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<config::ConfigError> for Error {
+    fn from(err: config::ConfigError) -> Error {
+        Error::ConfigLoad(err.to_string())
+    }
+}
+
+impl From<glob::PatternError> for Error {
+    fn from(err: glob::PatternError) -> Error {
+        Error::ConfigLoad(err.to_string())
+    }
+}
+
+impl From<glob::GlobError> for Error {
+    fn from(err: glob::GlobError) -> Error {
+        Error::ConfigLoad(err.to_string())
+    }
+}
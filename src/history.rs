@@ -0,0 +1,118 @@
+use crate::error::{Error, Result};
+use serde_derive::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// A nginx conf kept as `<id>.conf` with this as `<id>.json`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    pub id: u64,
+    /// RFC 3339
+    pub time: String,
+    pub sha256: String,
+    /// The config files it was generated from, empty for the command line only
+    pub sources: Vec<String>,
+}
+
+/// `<nginx_conf>.history`
+pub fn dir(nginx_conf: &Path) -> PathBuf {
+    let mut path = nginx_conf.as_os_str().to_owned();
+    path.push(".history");
+    path.into()
+}
+
+/// Oldest first. An entry which fails to parse is warned about and left out.
+pub fn entries(nginx_conf: &Path) -> Result<Vec<Entry>> {
+    let dir = dir(nginx_conf);
+    let read_dir = match fs::read_dir(&dir) {
+        Ok(read_dir) => read_dir,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(Error::io(dir.display(), err)),
+    };
+    let mut entries: Vec<Entry> = Vec::new();
+    for dir_entry in read_dir {
+        let path = dir_entry
+            .map_err(|err| Error::io(dir.display(), err))?
+            .path();
+        if path.extension().is_some_and(|ext| ext == "json") {
+            let json = fs::read_to_string(&path).map_err(|err| Error::io(path.display(), err))?;
+            match serde_json::from_str(&json) {
+                Ok(entry) => entries.push(entry),
+                Err(err) => warn!("skip history entry {}: {}", path.display(), err),
+            }
+        }
+    }
+    entries.sort_by_key(|entry| entry.id);
+    Ok(entries)
+}
+
+pub fn content(nginx_conf: &Path, id: u64) -> Result<String> {
+    let path = dir(nginx_conf).join(format!("{}.conf", id));
+    fs::read_to_string(&path).map_err(|err| Error::io(path.display(), err))
+}
+
+/// Keeps `content` as the next entry, removing the oldest ones beyond `keep`, at least 1.
+pub fn record(
+    nginx_conf: &Path,
+    content: &str,
+    time: String,
+    sha256: String,
+    sources: Vec<String>,
+    keep: usize,
+) -> Result<Entry> {
+    let dir = dir(nginx_conf);
+    fs::create_dir_all(&dir).map_err(|err| Error::io(dir.display(), err))?;
+    let mut entries = entries(nginx_conf)?;
+    let entry = Entry {
+        id: entries.last().map(|last| last.id + 1).unwrap_or(1),
+        time,
+        sha256,
+        sources,
+    };
+    let conf_path = dir.join(format!("{}.conf", entry.id));
+    fs::write(&conf_path, content).map_err(|err| Error::io(conf_path.display(), err))?;
+    let json_path = dir.join(format!("{}.json", entry.id));
+    let json = serde_json::to_string_pretty(&entry).expect("serializable");
+    fs::write(&json_path, json).map_err(|err| Error::io(json_path.display(), err))?;
+    entries.push(entry.clone());
+    let excess = entries.len().saturating_sub(keep.max(1));
+    for old in &entries[..excess] {
+        for ext in &["conf", "json"] {
+            let path = dir.join(format!("{}.{}", old.id, ext));
+            fs::remove_file(&path).map_err(|err| Error::io(path.display(), err))?;
+        }
+    }
+    Ok(entry)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn record_keeps_the_last() {
+        let dir = std::env::temp_dir().join(format!("rp-history-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let nginx_conf = dir.join("default.conf");
+        assert!(entries(&nginx_conf).unwrap().is_empty());
+        for i in 1..=3 {
+            let content = format!("# {}\n", i);
+            let entry =
+                record(&nginx_conf, &content, "now".into(), "sha".into(), vec![], 2).unwrap();
+            assert_eq!(i, entry.id);
+        }
+        let ids: Vec<u64> = entries(&nginx_conf).unwrap().iter().map(|e| e.id).collect();
+        assert_eq!(vec![2, 3], ids);
+        assert_eq!("# 2\n", content(&nginx_conf, 2).unwrap());
+        assert!(content(&nginx_conf, 1).is_err());
+
+        fs::write(super::dir(&nginx_conf).join("9.json"), "{").unwrap();
+        let entry = record(&nginx_conf, "# 4\n", "now".into(), "sha".into(), vec![], 0).unwrap();
+        assert_eq!(4, entry.id);
+        let ids: Vec<u64> = entries(&nginx_conf).unwrap().iter().map(|e| e.id).collect();
+        assert_eq!(vec![4], ids);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,96 @@
+use crate::error::{Error, Result};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// The `user:hash` lines of an htpasswd file, none if it doesn't exist.
+pub fn read(path: &Path) -> Result<Vec<(String, String)>> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(Error::io(path.display(), err)),
+    };
+    content
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| match line.find(':') {
+            Some(i) => Ok((line[..i].to_owned(), line[i + 1..].to_owned())),
+            None => Err(Error::parse(
+                line,
+                format!("expected user:hash in {}", path.display()),
+            )),
+        })
+        .collect()
+}
+
+fn write(path: &Path, users: &[(String, String)]) -> Result<()> {
+    let content = users
+        .iter()
+        .map(|(user, hash)| format!("{}:{}\n", user, hash))
+        .collect::<String>();
+    fs::write(path, content).map_err(|err| Error::io(path.display(), err))
+}
+
+/// Adds `user` with a bcrypt hash of `password`, replacing their hash if they exist.
+pub fn add_user(path: &Path, user: &str, password: &str, cost: u32) -> Result<()> {
+    if user.is_empty() || user.contains(':') {
+        return Err(Error::parse(
+            user,
+            "a user name must be non-empty without ':'",
+        ));
+    }
+    if password.is_empty() {
+        return Err(Error::Auth(format!("empty password for {}", user)));
+    }
+    // $2y$ is the prefix Apache's htpasswd writes and every crypt() knows
+    let hash = bcrypt::hash_with_result(password, cost)
+        .map_err(|err| Error::Auth(format!("Failed to hash the password: {}", err)))?
+        .format_for_version(bcrypt::Version::TwoY);
+    let mut users = read(path)?;
+    match users.iter_mut().find(|(name, _)| name == user) {
+        Some(entry) => entry.1 = hash,
+        None => users.push((user.to_owned(), hash)),
+    }
+    write(path, &users)
+}
+
+pub fn remove_user(path: &Path, user: &str) -> Result<()> {
+    let mut users = read(path)?;
+    let len = users.len();
+    users.retain(|(name, _)| name != user);
+    if users.len() == len {
+        return Err(Error::Auth(format!(
+            "no such user in {}: {}",
+            path.display(),
+            user
+        )));
+    }
+    write(path, &users)
+}
+
+pub fn list_users(path: &Path) -> Result<Vec<String>> {
+    Ok(read(path)?.into_iter().map(|(user, _)| user).collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn add_list_remove_users() {
+        let path = std::env::temp_dir().join(format!("rp-htpasswd-test-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        add_user(&path, "alice", "secret", 4).unwrap();
+        add_user(&path, "bob", "hunter2", 4).unwrap();
+        add_user(&path, "alice", "changed", 4).unwrap();
+        assert_eq!(vec!["alice", "bob"], list_users(&path).unwrap());
+        let users = read(&path).unwrap();
+        assert!(users[0].1.starts_with("$2y$04$"));
+        assert!(bcrypt::verify("changed", &users[0].1).unwrap());
+
+        remove_user(&path, "alice").unwrap();
+        assert_eq!(vec!["bob"], list_users(&path).unwrap());
+        assert!(matches!(remove_user(&path, "alice"), Err(Error::Auth(_))));
+        assert!(add_user(&path, "a:b", "secret", 4).is_err());
+    }
+}
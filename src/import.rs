@@ -0,0 +1,364 @@
+use crate::error::{Error, Result};
+
+/// A directive of an nginx conf, with the directives of its block if it has one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Directive {
+    pub name: String,
+    pub args: Vec<String>,
+    pub block: Option<Vec<Directive>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Semicolon,
+    Open,
+    Close,
+}
+
+fn tokenize(conf: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = conf.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '#' => {
+                while chars.peek().is_some_and(|&c| c != '\n') {
+                    chars.next();
+                }
+            }
+            ';' => tokens.push(Token::Semicolon),
+            '{' => tokens.push(Token::Open),
+            '}' => tokens.push(Token::Close),
+            '"' | '\'' => {
+                let mut word = String::new();
+                loop {
+                    match chars.next() {
+                        Some('\\') => word.extend(chars.next()),
+                        Some(end) if end == c => break,
+                        Some(c) => word.push(c),
+                        None => return Err(Error::parse(word, "unterminated quote")),
+                    }
+                }
+                tokens.push(Token::Word(word));
+            }
+            c if c.is_whitespace() => {}
+            c => {
+                let mut word = c.to_string();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || ";{}".contains(c) {
+                        break;
+                    }
+                    word.push(c);
+                    chars.next();
+                }
+                tokens.push(Token::Word(word));
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+fn parse_block<I: Iterator<Item = Token>>(tokens: &mut I, nested: bool) -> Result<Vec<Directive>> {
+    let mut directives = Vec::new();
+    let mut words: Vec<String> = Vec::new();
+    while let Some(token) = tokens.next() {
+        match token {
+            Token::Word(word) => words.push(word),
+            Token::Semicolon | Token::Open if words.is_empty() => {
+                return Err(Error::parse(
+                    format!("{:?}", token),
+                    "a directive without a name",
+                ))
+            }
+            Token::Semicolon => {
+                let name = words.remove(0);
+                directives.push(Directive {
+                    name,
+                    args: words.split_off(0),
+                    block: None,
+                });
+            }
+            Token::Open => {
+                let name = words.remove(0);
+                directives.push(Directive {
+                    name,
+                    args: words.split_off(0),
+                    block: Some(parse_block(tokens, true)?),
+                });
+            }
+            Token::Close if nested && words.is_empty() => return Ok(directives),
+            Token::Close => return Err(Error::parse("}", "unexpected")),
+        }
+    }
+    if nested || !words.is_empty() {
+        return Err(Error::parse(words.join(" "), "unexpected end of the conf"));
+    }
+    Ok(directives)
+}
+
+pub fn parse(conf: &str) -> Result<Vec<Directive>> {
+    parse_block(&mut tokenize(conf)?.into_iter(), false)
+}
+
+/// The `server` blocks and `upstream` blocks, whether the conf is an nginx.conf or a conf.d file.
+fn find<'a>(directives: &'a [Directive], name: &str, found: &mut Vec<&'a Directive>) {
+    for directive in directives {
+        if directive.name == name {
+            found.push(directive);
+        } else if let Some(ref block) = directive.block {
+            if directive.name == "http" {
+                find(block, name, found);
+            }
+        }
+    }
+}
+
+fn arg<'a>(block: &'a [Directive], name: &str) -> Option<&'a [String]> {
+    block
+        .iter()
+        .find(|directive| directive.name == name)
+        .map(|directive| directive.args.as_slice())
+}
+
+/// A config file in YAML with the upstreams, the first server as `domain` and its
+/// proxying locations as `reverse_proxy`, and the other servers proxying `/` as `subdomains`.
+/// What has no counterpart is logged and left out.
+pub fn to_config(conf: &str) -> Result<String> {
+    let directives = parse(conf)?;
+    let mut yaml = String::new();
+
+    let mut upstreams = Vec::new();
+    find(&directives, "upstream", &mut upstreams);
+    if !upstreams.is_empty() {
+        yaml.push_str("upstreams:\n");
+    }
+    for upstream in upstreams {
+        yaml.push_str(&format!(
+            "  - name: {}\n    servers:\n",
+            quote(&upstream.args.join(" "))
+        ));
+        for server in upstream
+            .block
+            .iter()
+            .flatten()
+            .filter(|d| d.name == "server")
+        {
+            let (address, params) = match server.args.split_first() {
+                Some(args) => args,
+                None => {
+                    warn!(
+                        "skip server without an address: {}",
+                        upstream.args.join(" ")
+                    );
+                    continue;
+                }
+            };
+            yaml.push_str(&format!("      - address: {}\n", quote(address)));
+            for param in params {
+                match param.strip_prefix("weight=") {
+                    Some(weight) => yaml.push_str(&format!("        weight: {}\n", weight)),
+                    None => warn!("skip server parameter: {} {}", address, param),
+                }
+            }
+        }
+    }
+
+    let mut servers = Vec::new();
+    find(&directives, "server", &mut servers);
+    let mut subdomains = String::new();
+    for (i, server) in servers.iter().enumerate() {
+        let block = server.block.as_deref().unwrap_or(&[]);
+        let server_name = arg(block, "server_name")
+            .and_then(|names| names.first())
+            .filter(|name| name.as_str() != "_" && name.as_str() != "localhost");
+        let locations: Vec<(String, String)> = block
+            .iter()
+            .filter(|directive| directive.name == "location")
+            .filter_map(|location| {
+                let path = match location.args.as_slice() {
+                    [path] => path,
+                    [modifier, path] if modifier == "^~" => path,
+                    args => {
+                        warn!("skip location: {}", args.join(" "));
+                        return None;
+                    }
+                };
+                let location_block = location.block.as_deref().unwrap_or(&[]);
+                match arg(location_block, "proxy_pass").and_then(|args| args.first()) {
+                    Some(url) if !url.contains('$') => {
+                        let mut mapping =
+                            format!("  - path: {}\n    url: {}\n", quote(path), quote(url));
+                        mapping.push_str(&options(location_block));
+                        Some((path.clone(), mapping))
+                    }
+                    _ => {
+                        warn!("skip location without a plain proxy_pass: {}", path);
+                        None
+                    }
+                }
+            })
+            .collect();
+        if i == 0 {
+            if let Some(name) = server_name {
+                yaml.push_str(&format!("domain: {}\n", quote(name)));
+            }
+            if !locations.is_empty() {
+                yaml.push_str("reverse_proxy:\n");
+            }
+            for (_, mapping) in locations {
+                yaml.push_str(&mapping);
+            }
+            continue;
+        }
+        match (server_name, locations.as_slice()) {
+            (Some(name), [(path, mapping)]) if path == "/" => {
+                subdomains.push_str(&format!("  - domain: {}\n", quote(name)));
+                // `- path: /` is the default of subdomains
+                subdomains.push_str(
+                    mapping
+                        .split_once('\n')
+                        .map(|(_, rest)| rest)
+                        .unwrap_or_default(),
+                );
+            }
+            _ => warn!(
+                "skip server {}: only a server proxying / can be a subdomain",
+                server_name
+                    .map(String::as_str)
+                    .unwrap_or("without server_name")
+            ),
+        }
+    }
+    if !subdomains.is_empty() {
+        yaml.push_str("subdomains:\n");
+        yaml.push_str(&subdomains);
+    }
+    Ok(yaml)
+}
+
+/// Mapping options for the directives of a location known to have one.
+fn options(block: &[Directive]) -> String {
+    let mut options = String::new();
+    for directive in block {
+        match (directive.name.as_str(), directive.args.as_slice()) {
+            ("proxy_pass", _) => {}
+            ("proxy_set_header", [name, value])
+                if name.eq_ignore_ascii_case("Upgrade") && value == "$http_upgrade" =>
+            {
+                options.push_str("    websocket: true\n")
+            }
+            ("proxy_set_header", [name, _]) if name.eq_ignore_ascii_case("Connection") => {}
+            ("proxy_set_header", [name, value])
+                if name.eq_ignore_ascii_case("Host") && !value.starts_with('$') =>
+            {
+                options.push_str(&format!("    upstream_host: {}\n", quote(value)))
+            }
+            ("proxy_set_header", _)
+                if crate::DEFAULT_PROXY_HEADERS.iter().any(|(name, value)| {
+                    directive.args == [name.to_string(), value.to_string()]
+                }) => {}
+            ("proxy_http_version", _) => {}
+            ("proxy_read_timeout", [timeout]) => {
+                match timeout.trim_end_matches('s').parse::<u64>() {
+                    Ok(timeout) => options.push_str(&format!("    timeout: {}\n", timeout)),
+                    Err(_) => warn!("skip proxy_read_timeout: {}", timeout),
+                }
+            }
+            ("proxy_send_timeout", _) => {}
+            ("proxy_redirect", [from, to]) => options.push_str(&format!(
+                "    redirect_rewrites:\n      - from: {}\n        to: {}\n",
+                quote(from),
+                quote(to)
+            )),
+            ("access_log", [off]) if off == "off" => options.push_str("    access_log: false\n"),
+            (name, args) => warn!("skip directive: {} {}", name, args.join(" ")),
+        }
+    }
+    options
+}
+
+/// A YAML scalar; JSON strings are YAML too.
+fn quote(s: &str) -> String {
+    if !s.is_empty()
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || "/._-:$".contains(c))
+        && !s.starts_with('$')
+    {
+        return s.to_owned();
+    }
+    serde_json::to_string(s).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_nested_blocks() {
+        let directives =
+            parse("http {\n  # comment\n  server { listen 80; server_name \"a b\"; }\n}\n")
+                .unwrap();
+        assert_eq!("http", directives[0].name);
+        let server = &directives[0].block.as_ref().unwrap()[0];
+        assert_eq!(vec!["a b"], server.block.as_ref().unwrap()[1].args);
+        assert!(parse("server { listen 80;").is_err());
+        assert!(parse("listen 80; }").is_err());
+    }
+
+    #[test]
+    fn to_config_servers_and_upstreams() {
+        let conf = r#"
+upstream app {
+    server app-1:3000 weight=3;
+    server app-2:3000;
+    server;
+}
+server {
+    listen 80;
+    server_name example.com;
+    location / {
+        proxy_pass http://app/;
+        proxy_set_header Host $host;
+    }
+    location /ws {
+        proxy_pass http://chat:3000/socket;
+        proxy_http_version 1.1;
+        proxy_set_header Upgrade $http_upgrade;
+        proxy_set_header Connection "upgrade";
+        proxy_read_timeout 300s;
+    }
+    location ~ \.php$ {
+        fastcgi_pass php:9000;
+    }
+}
+server {
+    listen 80;
+    server_name api.example.com;
+    location / {
+        proxy_pass http://api:3000;
+    }
+}
+"#;
+        assert_eq!(
+            r#"upstreams:
+  - name: app
+    servers:
+      - address: app-1:3000
+        weight: 3
+      - address: app-2:3000
+domain: example.com
+reverse_proxy:
+  - path: /
+    url: http://app/
+  - path: /ws
+    url: http://chat:3000/socket
+    websocket: true
+    timeout: 300
+subdomains:
+  - domain: api.example.com
+    url: http://api:3000
+"#,
+            to_config(conf).unwrap()
+        );
+    }
+}
@@ -0,0 +1,132 @@
+use crate::error::{Error, Result};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+const CONF_YAML: &str = r#"# Global settings, overridden by command line arguments.
+# Every file in this directory except sites/ is merged in path order.
+
+# the virtual host
+domain: localhost
+# listen address and port
+# host: 0.0.0.0
+# port: 10080
+
+# requests matching no mapping
+# default_backend: http://web:3000
+
+# a server block of its own for another virtual host
+# subdomains:
+#   - domain: api.example.com
+#     url: http://api:3000/
+"#;
+
+const SITE_YAML: &str = r#"# Each file under sites/ adds its reverse_proxy mappings.
+reverse_proxy:
+  - path: /
+    url: http://app:3000/
+    # websocket: true
+    # timeout: 300
+  # - path: /api
+  #   url: http://api:3000/api
+  #   strip_prefix: true
+"#;
+
+const CONF_TOML: &str = r#"# Global settings, overridden by command line arguments.
+# Every file in this directory except sites/ is merged in path order.
+
+# the virtual host
+domain = "localhost"
+# listen address and port
+# host = "0.0.0.0"
+# port = 10080
+
+# requests matching no mapping
+# default_backend = "http://web:3000"
+
+# a server block of its own for another virtual host
+# [[subdomains]]
+# domain = "api.example.com"
+# url = "http://api:3000/"
+"#;
+
+const SITE_TOML: &str = r#"# Each file under sites/ adds its reverse_proxy mappings.
+[[reverse_proxy]]
+path = "/"
+url = "http://app:3000/"
+# websocket = true
+# timeout = 300
+
+# [[reverse_proxy]]
+# path = "/api"
+# url = "http://api:3000/api"
+# strip_prefix = true
+"#;
+
+/// A docker-compose service running the image with `config_dir` mounted.
+pub fn compose_snippet(config_dir: &Path) -> String {
+    format!(
+            "services:\n  rp:\n    image: sifyfy/rp\n    ports:\n      - \"80:10080\"\n    volumes:\n      - {}:/conf:ro\n",
+            config_dir.display()
+        )
+}
+
+/// Writes commented example files into `config_dir`, `toml` or else YAML.
+/// Existing files are kept unless `force`. Returns the files written.
+pub fn scaffold(config_dir: &Path, toml: bool, force: bool) -> Result<Vec<PathBuf>> {
+    let (ext, conf, site) = if toml {
+        ("toml", CONF_TOML, SITE_TOML)
+    } else {
+        ("yaml", CONF_YAML, SITE_YAML)
+    };
+    let files = vec![
+        (config_dir.join(format!("conf.{}", ext)), conf),
+        (config_dir.join("sites").join(format!("app.{}", ext)), site),
+    ];
+    if !force {
+        if let Some((path, _)) = files.iter().find(|(path, _)| path.exists()) {
+            return Err(Error::io(
+                path.display(),
+                io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    "exists, use --force to overwrite",
+                ),
+            ));
+        }
+    }
+    let mut written = Vec::new();
+    for (path, content) in files {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(|err| Error::io(dir.display(), err))?;
+        }
+        fs::write(&path, content).map_err(|err| Error::io(path.display(), err))?;
+        written.push(path);
+    }
+    Ok(written)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::conf::{AppConfig, Args};
+
+    #[test]
+    fn scaffold_loads() {
+        use structopt::StructOpt;
+        for &toml in &[false, true] {
+            let config_dir =
+                std::env::temp_dir().join(format!("rp-init-test-{}-{}", std::process::id(), toml));
+            let _ = fs::remove_dir_all(&config_dir);
+            assert_eq!(2, scaffold(&config_dir, toml, false).unwrap().len());
+            assert!(scaffold(&config_dir, toml, false).is_err());
+            scaffold(&config_dir, toml, true).unwrap();
+
+            let config_dir = config_dir.display().to_string();
+            let cli_args: &[&str] = &["test", "--config-dir", &config_dir];
+            let args = Args::from_iter_safe(cli_args.iter()).unwrap();
+            let app_config = AppConfig::from_args_and_config(args).unwrap();
+            assert_eq!(Some("localhost"), app_config.domain.as_deref());
+            assert_eq!(1, app_config.reverse_proxy.len());
+        }
+    }
+}
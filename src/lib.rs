@@ -0,0 +1,27 @@
+#[macro_use]
+extern crate log;
+
+pub mod audit;
+pub mod bake;
+pub mod cert;
+pub mod conf;
+pub mod container;
+pub mod deploy;
+pub mod diagnostics;
+pub mod edit;
+pub mod error;
+pub mod history;
+pub mod htpasswd;
+pub mod import;
+pub mod init;
+pub mod lock;
+pub mod logging;
+pub mod notify;
+pub mod output;
+pub mod reload;
+pub mod render;
+pub mod srv;
+pub mod supervise;
+
+pub use reload::{check_reload, run_on_change};
+pub use render::*;
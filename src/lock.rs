@@ -0,0 +1,89 @@
+use crate::error::{Error, Result};
+use std::fs;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// An advisory lock (`flock`) on `<nginx_conf>.lock`, released on drop.
+#[derive(Debug)]
+pub struct ConfLock {
+    file: fs::File,
+    path: PathBuf,
+}
+
+impl ConfLock {
+    pub fn lock_path(nginx_conf: &Path) -> PathBuf {
+        let mut path = nginx_conf.as_os_str().to_owned();
+        path.push(".lock");
+        PathBuf::from(path)
+    }
+
+    /// Waits up to `timeout` for other runs writing the same nginx conf.
+    pub fn acquire(nginx_conf: &Path, timeout: Duration) -> Result<ConfLock> {
+        let path = Self::lock_path(nginx_conf);
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .map_err(|err| Error::io(path.display(), err))?;
+        let started = Instant::now();
+        loop {
+            let ret = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+            if ret == 0 {
+                debug!("acquired lock: {}", path.display());
+                return Ok(ConfLock { file, path });
+            }
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::WouldBlock {
+                return Err(Error::io(path.display(), err));
+            }
+            if started.elapsed() >= timeout {
+                return Err(Error::io(
+                    path.display(),
+                    io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "Timed out waiting for another run to release the lock",
+                    ),
+                ));
+            }
+            info!("waiting for lock: {}", path.display());
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+}
+
+impl Drop for ConfLock {
+    fn drop(&mut self) {
+        unsafe { libc::flock(self.file.as_raw_fd(), libc::LOCK_UN) };
+        debug!("released lock: {}", self.path.display());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn lock_path_next_to_nginx_conf() {
+        assert_eq!(
+            PathBuf::from("/etc/nginx/conf.d/default.conf.lock"),
+            ConfLock::lock_path(Path::new("/etc/nginx/conf.d/default.conf"))
+        );
+    }
+
+    #[test]
+    fn acquire_fails_while_locked() {
+        let nginx_conf =
+            std::env::temp_dir().join(format!("rp-lock-test-{}.conf", std::process::id()));
+        let lock = ConfLock::acquire(&nginx_conf, Duration::from_secs(0)).unwrap();
+        match ConfLock::acquire(&nginx_conf, Duration::from_millis(200)) {
+            Err(Error::Io { source, .. }) => assert_eq!(io::ErrorKind::TimedOut, source.kind()),
+            other => panic!("expected a timeout: {:?}", other),
+        }
+        drop(lock);
+        ConfLock::acquire(&nginx_conf, Duration::from_secs(0)).unwrap();
+    }
+}
@@ -0,0 +1,74 @@
+use std::io::Write;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<LogFormat, String> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format: {}", s)),
+        }
+    }
+}
+
+/// Initializes the global logger writing to stderr.
+pub fn init(level: log::LevelFilter, format: LogFormat) {
+    let mut builder = env_logger::builder();
+    builder.filter_level(level);
+    if format == LogFormat::Json {
+        builder.format(|buf, record| {
+            let line = json_line(
+                &buf.timestamp().to_string(),
+                record.level(),
+                record.target(),
+                &record.args().to_string(),
+            );
+            writeln!(buf, "{}", line)
+        });
+    }
+    builder.init();
+}
+
+/// One log record as a JSON object, eg. `{"timestamp":"...","level":"INFO","target":"...","message":"..."}`.
+pub fn json_line(timestamp: &str, level: log::Level, target: &str, message: &str) -> String {
+    serde_json::json!({
+        "timestamp": timestamp,
+        "level": level.to_string(),
+        "target": target,
+        "message": message,
+    })
+    .to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn json_line_escapes_message() {
+        let line = json_line(
+            "2019-04-01T00:00:00Z",
+            log::Level::Info,
+            "rp",
+            "load config file: \"a.yaml\"",
+        );
+        let value: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!("INFO", value["level"]);
+        assert_eq!("load config file: \"a.yaml\"", value["message"]);
+        assert!(!line.contains('\n'));
+    }
+
+    #[test]
+    fn parse_log_format() {
+        assert_eq!(Ok(LogFormat::Json), "json".parse());
+        assert!("xml".parse::<LogFormat>().is_err());
+    }
+}
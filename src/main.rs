@@ -1,356 +1,786 @@
 #[macro_use]
 extern crate log;
 
-pub mod conf {
-    use failure::{format_err, ResultExt};
-    use glob::glob;
-    use serde_derive::{Deserialize, Serialize};
-    use std::path::PathBuf;
-    use structopt::StructOpt;
-    use url::Url;
-
-    #[derive(Debug, StructOpt)]
-    #[structopt(rename_all = "kebab-case")]
-    pub struct Args {
-        #[structopt(
-            short,
-            long,
-            help = "listen address, such as 0.0.0.0, 127.0.0.1, 192.168.1.2"
-        )]
-        pub host: Option<String>,
-        #[structopt(short, long, help = "listen port")]
-        pub port: Option<u16>,
-        #[structopt(short, long, help = "virtual host. eg. localhost, example.com")]
-        pub domain: Option<String>,
-        #[structopt(
-            short = "r",
-            long,
-            parse(try_from_str = "parse_reverse_proxy_mapping"),
-            help = "eg. /path/to:http://localhost:3000/path/to"
-        )]
-        pub reverse_proxy: Vec<ReverseProxyMapping>,
-        #[structopt(
-            long,
-            parse(from_os_str),
-            help = "a nginx conf file path to which this will write out"
-        )]
-        pub nginx_conf: Option<PathBuf>,
-        #[structopt(
-            long,
-            default_value = "/conf",
-            parse(from_str = "parse_path_without_trailing_slash")
-        )]
-        pub config_dir: PathBuf,
-        #[structopt(flatten)]
-        pub verbose: clap_verbosity_flag::Verbosity,
-    }
-
-    impl Args {
-        pub fn from_args() -> Args {
-            <Args as StructOpt>::from_args()
+use generate_simple_reverse_proxy_conf_to_nginx::error::{Error, Result};
+use generate_simple_reverse_proxy_conf_to_nginx::{
+    audit, bake, cert, certificate_files, check_reload, conf, conf_sha256, deploy, diagnostics,
+    edit, first_difference, format_model, format_routing_table, history, htpasswd, import, init,
+    lock, logging, managed_region, notify, output, render_banner, render_compose, render_configmap,
+    render_model, render_nginx_conf, render_sha256, render_source_map, render_tf_external,
+    render_to, replace_managed_region, routing_table, run_on_change, source_map_path, strip_banner,
+    supervise::{self, Metrics},
+};
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+fn main() {
+    match run() {
+        Ok(0) => {}
+        Ok(code) => process::exit(code),
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            process::exit(exit_code(&err));
         }
     }
+}
 
-    pub fn parse_reverse_proxy_mapping(s: &str) -> Result<ReverseProxyMapping, failure::Error> {
-        ReverseProxyMapping::parse(s)
+/// Exit codes by error category. Scripts depend on them, so don't renumber.
+/// 1 is left to invalid arguments, which are reported by clap.
+fn exit_code(err: &Error) -> i32 {
+    match err {
+        Error::ConfigLoad(_) => 2,
+        Error::Parse { .. } => 3,
+        Error::Render(_) => 4,
+        Error::Io { .. } => 5,
+        Error::Docker(_) => 6,
+        Error::Certificate(_) => 7,
+        Error::Auth(_) => 8,
     }
+}
 
-    pub fn parse_path_without_trailing_slash(s: &str) -> PathBuf {
-        PathBuf::from(s.trim_end_matches("/"))
+fn run() -> Result<i32> {
+    let args = conf::Args::from_args();
+    logging::init(args.verbose.log_level().to_level_filter(), args.log_format);
+    debug!("args: {:#?}", args);
+    if let Some(conf::Command::Run {
+        ref nginx,
+        ref admin,
+        interval,
+        refresh,
+        debounce,
+    }) = args.command
+    {
+        return supervise_nginx(
+            nginx,
+            admin.as_deref(),
+            Duration::from_secs(interval),
+            refresh.map(Duration::from_secs),
+            debounce.unwrap_or_default(),
+        );
     }
+    generate(args, None, &mut Watched::default())
+}
 
-    #[derive(Debug, Clone, Ord, PartialOrd, Eq, PartialEq, Serialize, Deserialize)]
-    pub struct ReverseProxyMapping {
-        pub path: String,
-        #[serde(with = "url_serde")]
-        pub url: Url,
-    }
+/// What `run` keeps from one generation to the next.
+#[derive(Debug, Default)]
+struct Watched {
+    /// The certificate files of the loaded config; rotating them leaves the nginx conf as it is.
+    certificates: Vec<PathBuf>,
+    /// `tls.cert_dir`, which certbot or cert-manager may renew behind symlinks.
+    cert_dir: Option<PathBuf>,
+    /// The sha256 of the loaded config, which is the same when an irrelevant file was touched.
+    config_sha256: Option<String>,
+}
 
-    impl ReverseProxyMapping {
-        pub fn parse(s: &str) -> Result<ReverseProxyMapping, failure::Error> {
-            let i = s
-                .find(":")
-                .ok_or_else(|| format_err!("missing separator ':' in {}", s))?;
-            let (path, url) = s.split_at(i);
-            let url = url.trim_start_matches(":");
-            Ok(ReverseProxyMapping {
-                path: path.into(),
-                url: Url::parse(url)
-                    .with_context(|_| format!("Failed to parse as URL: {}", url.to_owned()))?,
-            })
+impl Watched {
+    /// Modification times of the certificate files and of everything under `cert_dir`.
+    fn certificate_stamp(&self) -> Vec<(PathBuf, Option<SystemTime>)> {
+        match self.cert_dir {
+            Some(ref cert_dir) => supervise::input_stamp(cert_dir, &self.certificates),
+            None => supervise::files_stamp(&self.certificates),
         }
     }
+}
 
-    #[derive(Debug, Clone, Serialize, Deserialize)]
-    struct RawAppConfig {
-        host: Option<String>,
-        port: Option<u16>,
-        domain: Option<String>,
-        #[serde(default)]
-        reverse_proxy: Vec<ReverseProxyMapping>,
-        nginx_conf: Option<PathBuf>,
+/// Writes the nginx conf and runs nginx as a child until it exits or a signal stops both.
+/// The config is loaded again every `interval` while the config files changed, or on SIGHUP,
+/// and nginx reloaded when the nginx conf changed. A broken edit leaves both as they were.
+/// `admin` is an address to serve metrics on. Every `refresh` the config is loaded again
+/// regardless of changes, for upstreams looked up while loading. Changed config files are
+/// loaded once they have stayed the same for `debounce`.
+fn supervise_nginx(
+    nginx: &str,
+    admin: Option<&str>,
+    interval: Duration,
+    refresh: Option<Duration>,
+    debounce: Duration,
+) -> Result<i32> {
+    // parsed again for each generation since loading consumes them
+    let args = || {
+        let mut args = conf::Args::from_args();
+        args.command = None;
+        // a non-zero code tells that the nginx conf changed
+        args.changed_exit_code = args.changed_exit_code.filter(|&code| code != 0).or(Some(1));
+        args
+    };
+    let first = args();
+    if first.reads_stdin() {
+        return Err(Error::parse(
+            "-",
+            "run loads the config again on changes, so mappings can't come from stdin",
+        ));
     }
-
-    #[derive(Debug, Clone, Serialize, Deserialize)]
-    pub struct AppConfig {
-        pub host: String,
-        pub port: u16,
-        pub domain: Option<String>,
-        #[serde(default)]
-        pub reverse_proxy: Vec<ReverseProxyMapping>,
-        pub nginx_conf: PathBuf,
+    let mut stamp = supervise::input_stamp(&first.config_dir, &first.config_file);
+    let metrics = Arc::new(supervise::Metrics::default());
+    Metrics::count(&metrics.renders);
+    let mut watched = Watched::default();
+    generate(first, None, &mut watched)?;
+    // rotated certificates leave the nginx conf as it is, so they are watched themselves
+    let mut certificate_stamp = watched.certificate_stamp();
+    Metrics::stamp(&metrics.last_success);
+    if let Some(admin) = admin {
+        let addr = supervise::serve_admin(admin, metrics.clone())?;
+        info!("serve /metrics and /healthz on {}", addr);
     }
 
-    impl AppConfig {
-        /// panic: error in config files or CLI arguments
-        pub fn from_args_and_config(args: Args) -> Result<AppConfig, failure::Error> {
-            let mut settings = config::Config::default();
-            let config_dir = format!("{}/*", args.config_dir.display());
-            debug!("config_dir: {}", config_dir);
-            settings.merge(
-                glob(&config_dir)?
-                    .map(|path| {
-                        path.map(|path| {
-                            info!("load config file: {}", path.display());
-                            config::File::from(path)
-                        })
-                    })
-                    .collect::<Result<Vec<_>, _>>()?,
-            )?;
-            trace!("settings: {:#?}", settings);
-
-            let RawAppConfig {
-                host: rac_host,
-                port: rac_port,
-                domain: rac_domain,
-                reverse_proxy: rac_reverse_proxy,
-                nginx_conf: rac_nginx_conf,
-            } = {
-                let raw_app_config = settings.try_into()?;
-                debug!("raw_app_config: {:#?}", raw_app_config);
-                raw_app_config
-            };
-
-            let Args {
-                host: args_host,
-                port: args_port,
-                domain: args_domain,
-                reverse_proxy: args_reverse_proxy,
-                nginx_conf: args_nginx_conf,
-                config_dir: _,
-                verbose: _,
-            } = args;
-
-            Ok(AppConfig {
-                host: args_host.or(rac_host).unwrap_or_else(|| "0.0.0.0".into()),
-                port: args_port.or(rac_port).unwrap_or(10080),
-                domain: args_domain.or(rac_domain),
-                reverse_proxy: args_reverse_proxy
-                    .into_iter()
-                    .chain(rac_reverse_proxy.into_iter())
-                    .collect(),
-                nginx_conf: args_nginx_conf
-                    .or(rac_nginx_conf)
-                    .unwrap_or_else(|| PathBuf::from("/etc/nginx/conf.d/default.conf")),
-            })
+    supervise::install_signal_handlers();
+    let mut child = supervise::spawn_nginx(nginx)?;
+    info!("run {} (pid {})", nginx, child.id());
+    let wait = |child: &mut process::Child| {
+        child
+            .wait()
+            .map_err(|err| Error::io(nginx, err))
+            .map(supervise::exit_code)
+    };
+    let mut next_check = Instant::now() + interval;
+    let mut last_load = Instant::now();
+    // the changed files last seen, and when they are loaded unless they change again
+    let mut settling = None;
+    loop {
+        thread::sleep(Duration::from_millis(100));
+        if let Some(status) = child.try_wait().map_err(|err| Error::io(nginx, err))? {
+            warn!("{} exited: {}", nginx, status);
+            return Ok(supervise::exit_code(status));
+        }
+        let hangup = match supervise::take_signal() {
+            Some(libc::SIGHUP) => true,
+            Some(signal) => {
+                info!("forward signal {} to {}", signal, nginx);
+                supervise::send(&child, signal)?;
+                return wait(&mut child);
+            }
+            None => false,
+        };
+        if !hangup && settling.is_none() && Instant::now() < next_check {
+            continue;
+        }
+        next_check = Instant::now() + interval;
+        let args = args();
+        let current = supervise::input_stamp(&args.config_dir, &args.config_file);
+        let refreshing = refresh.is_some_and(|refresh| last_load.elapsed() >= refresh);
+        let renewed_stamp = watched.certificate_stamp();
+        let renewed = renewed_stamp != certificate_stamp;
+        if !hangup && !refreshing && !renewed && current == stamp {
+            settling = None;
+            continue;
+        }
+        if !hangup && !debounce.is_zero() && current != stamp {
+            match settling {
+                Some((ref seen, until)) if *seen == current && Instant::now() >= until => {}
+                Some((ref seen, _)) if *seen == current => continue,
+                _ => {
+                    settling = Some((current, Instant::now() + debounce));
+                    continue;
+                }
+            }
+        }
+        settling = None;
+        for (path, _) in renewed_stamp
+            .iter()
+            .filter(|f| !certificate_stamp.contains(f))
+        {
+            if !path.is_dir() {
+                info!("certificate renewed: {}", path.display());
+            }
+        }
+        if hangup || refreshing {
+            // rendered again even if the config is the same
+            watched.config_sha256 = None;
+        }
+        stamp = current;
+        last_load = Instant::now();
+        let reloading = Cell::new(false);
+        let reload_nginx = || {
+            info!("reload {}", nginx);
+            reloading.set(true);
+            Metrics::count(&metrics.reloads);
+            Metrics::stamp(&metrics.last_reload);
+            supervise::send(&child, libc::SIGHUP)
+        };
+        Metrics::count(&metrics.renders);
+        let generated = match generate(args, Some(&reload_nginx), &mut watched) {
+            Ok(0) if hangup || renewed => reload_nginx(),
+            Ok(_) => Ok(()),
+            Err(err) => Err(err),
+        };
+        certificate_stamp = watched.certificate_stamp();
+        if generated.is_err() {
+            // tried again on the next change, even one that leaves the config the same
+            watched.config_sha256 = None;
+        }
+        metrics.set_last_error(generated.as_ref().err().map(|err| err.to_string()));
+        match generated {
+            Ok(()) => Metrics::stamp(&metrics.last_success),
+            Err(err) => {
+                Metrics::count(if reloading.get() {
+                    &metrics.reload_failures
+                } else {
+                    &metrics.render_errors
+                });
+                error!("{}, keeping the nginx conf as it was", err);
+            }
         }
     }
+}
 
-    #[cfg(test)]
-    mod test {
-        use super::*;
-
-        #[test]
-        fn config_dir_default_not_specified() {
-            use structopt::StructOpt;
-            let cli_args: &[&str] = &["test"];
-            let args = Args::from_iter_safe(cli_args.iter()).unwrap();
-            assert_eq!(
-                "/conf".to_string(),
-                format!("{}", args.config_dir.display())
-            );
+/// Returns the exit code on success: 0, or `--changed-exit-code` if the nginx conf changed.
+/// `reload_nginx` is called before `--on-change` when the nginx conf changed.
+/// `watched` is updated with the loaded config; when its sha256 is the same as before,
+/// nothing is rendered again.
+fn generate(
+    mut args: conf::Args,
+    reload_nginx: Option<&dyn Fn() -> Result<()>>,
+    watched: &mut Watched,
+) -> Result<i32> {
+    let command = args.command.take();
+    let changed_exit_code = args.changed_exit_code;
+    let strict = args.strict;
+    let emit_model = args.emit_model.take();
+    let deploy = args.deploy.take();
+    let deploy_reload = args.deploy_reload.clone();
+    let format = args.format;
+    let configmap_name = args.configmap_name.clone();
+    let configmap_namespace = args.configmap_namespace.take();
+    // users are managed without loading the config
+    if let Some(conf::Command::Auth { command }) = command {
+        return manage_users(command).map(|_| 0);
+    }
+    if let Some(conf::Command::Init {
+        toml,
+        compose,
+        force,
+    }) = command
+    {
+        for path in init::scaffold(&args.config_dir, toml, force)? {
+            info!("write {}", path.display());
         }
-
-        #[test]
-        fn config_dir_default_omit_value() {
-            use structopt::StructOpt;
-            let cli_args: &[&str] = &["test", "--config-dir"];
-            let args = Args::from_iter_safe(cli_args.iter()).unwrap();
-            assert_eq!(
-                "/conf".to_string(),
-                format!("{}", args.config_dir.display())
-            );
+        if compose {
+            print!("{}", init::compose_snippet(&args.config_dir));
         }
-
-        #[test]
-        fn config_dir_custom_relative_path() {
-            use structopt::StructOpt;
-            let cli_args: &[&str] = &["test", "--config-dir", "./conf"];
-            let args = Args::from_iter_safe(cli_args.iter()).unwrap();
-            assert_eq!(
-                "./conf".to_string(),
-                format!("{}", args.config_dir.display())
-            );
+        return Ok(0);
+    }
+    if let Some(conf::Command::Import {
+        nginx_conf,
+        name,
+        force,
+    }) = command
+    {
+        return import_conf(&nginx_conf, &args.config_dir.join(name), force).map(|_| 0);
+    }
+    args.read_stdin_mappings(io::stdin().lock())?;
+    if let Some(conf::Command::TfExternal) = command {
+        return tf_external(args).map(|_| 0);
+    }
+    let config_dir = args.config_dir.clone();
+    let backup = edit_mappings(command.as_ref(), &args.config_dir)?;
+    let loaded = conf::AppConfig::from_args_and_config(args)
+        .and_then(|app_config| report_warnings(strict).map(|_| app_config));
+    let mut app_config = match loaded {
+        Ok(app_config) => app_config,
+        Err(err) => {
+            // leave the config file as it was rather than broken
+            if let Some((file, content)) = backup {
+                match content {
+                    Some(content) => fs::write(&file, content),
+                    None => fs::remove_file(&file),
+                }
+                .map_err(|err| Error::io(file.display(), err))?;
+            }
+            return Err(err);
         }
-
-        #[test]
-        fn config_dir_custom_absolute_path() {
-            use structopt::StructOpt;
-            let cli_args: &[&str] = &["test", "--config-dir", "/path/to/conf"];
-            let args = Args::from_iter_safe(cli_args.iter()).unwrap();
-            assert_eq!(
-                "/path/to/conf".to_string(),
-                format!("{}", args.config_dir.display())
-            );
+    };
+    debug!("app_config: {:#?}", app_config);
+    watched.certificates = certificate_files(&app_config);
+    watched.cert_dir = app_config.tls.as_ref().and_then(|tls| tls.cert_dir.clone());
+    if command.is_none() {
+        // Debug rather than the model, which leaves out the routes, the sources and secrets
+        let config_sha256 = conf_sha256(&format!("{:?}", app_config));
+        if watched.config_sha256.as_deref() == Some(config_sha256.as_str()) {
+            info!("config unchanged: {}", config_dir.display());
+            return Ok(0);
         }
+        watched.config_sha256 = Some(config_sha256);
+    }
 
-        #[test]
-        fn config_dir_custom_relative_path_with_trailing_slash() {
-            use structopt::StructOpt;
-            let cli_args: &[&str] = &["test", "--config-dir", "./conf/"];
-            let args = Args::from_iter_safe(cli_args.iter()).unwrap();
-            assert_eq!(
-                "./conf".to_string(),
-                format!("{}", args.config_dir.display())
-            );
+    match command {
+        Some(conf::Command::DevCert {
+            domain,
+            cert_dir,
+            force,
+        }) => {
+            let pair = cert::generate_dev_cert(&domain, &cert_dir, force)?;
+            let tls = app_config.tls.get_or_insert_with(Default::default);
+            tls.certificate = Some(pair.certificate);
+            tls.certificate_key = Some(pair.certificate_key);
+            if app_config.domain.is_none() {
+                app_config.domain = Some(domain);
+            }
         }
-
-        #[test]
-        fn config_dir_custom_absolute_path_with_trailing_slash() {
-            use structopt::StructOpt;
-            let cli_args: &[&str] = &["test", "--config-dir", "/path/to/conf/"];
-            let args = Args::from_iter_safe(cli_args.iter()).unwrap();
-            assert_eq!(
-                "/path/to/conf".to_string(),
-                format!("{}", args.config_dir.display())
-            );
+        Some(conf::Command::Cert {
+            command: conf::CertCommand::Status { warn_days },
+        }) => return print_cert_status(&app_config, warn_days).map(|_| 0),
+        Some(conf::Command::Verify { against }) => {
+            let golden =
+                fs::read_to_string(&against).map_err(|err| Error::io(against.display(), err))?;
+            let conf = render_nginx_conf(&app_config);
+            if let Some(difference) = first_difference(strip_banner(&golden), &conf) {
+                return Err(Error::Render(format!(
+                    "differs from {}, {}",
+                    against.display(),
+                    difference
+                )));
+            }
+            info!("same as {}", against.display());
+            return Ok(0);
+        }
+        Some(conf::Command::List { output }) => {
+            let routes = routing_table(&app_config);
+            match output.as_str() {
+                "json" => println!(
+                    "{:#}",
+                    serde_json::to_value(&routes).map_err(|err| Error::Render(err.to_string()))?
+                ),
+                _ => print!("{}", format_routing_table(&routes)),
+            }
+            return Ok(0);
+        }
+        Some(conf::Command::Rollback { to, list }) => {
+            return rollback(&app_config, to, list).map(|_| 0)
+        }
+        Some(conf::Command::Bake { out, force }) => {
+            let conf = render_nginx_conf(&app_config);
+            let banner = render_banner(&app_config, &conf_sha256(&conf), SystemTime::now());
+            let files = certificate_files(&app_config);
+            for file in bake::bake(&app_config, &(banner + &conf), &files, &out, force)? {
+                info!("wrote {}", file.display());
+            }
+            return Ok(0);
         }
+        Some(conf::Command::Compose) => {
+            print!("{}", render_compose(&app_config, &config_dir)?);
+            return Ok(0);
+        }
+        Some(conf::Command::Auth { .. })
+        | Some(conf::Command::Import { .. })
+        | Some(conf::Command::Init { .. })
+        | Some(conf::Command::TfExternal)
+        | Some(conf::Command::Add { .. })
+        | Some(conf::Command::Remove { .. })
+        | Some(conf::Command::Run { .. })
+        | None => {}
+    }
 
-        #[test]
-        fn nginx_conf_path_default_args() {
-            use structopt::StructOpt;
-            let cli_args: &[&str] = &["test"];
-            let args = Args::from_iter_safe(cli_args.iter()).unwrap();
-            assert_eq!(None, args.nginx_conf);
+    if let Some(dir) = app_config
+        .nginx_conf
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty() && !dir.exists())
+    {
+        if !app_config.mkdirs {
+            return Err(Error::io(
+                dir.display(),
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "no directory for the nginx conf, use --mkdirs to create it",
+                ),
+            ));
         }
+        fs::create_dir_all(dir).map_err(|err| Error::io(dir.display(), err))?;
+        info!("create directory: {}", dir.display());
+    }
+    let _lock = lock::ConfLock::acquire(
+        &app_config.nginx_conf,
+        Duration::from_secs(app_config.lock_timeout),
+    )?;
+    // a ConfigMap and a managed region are built in memory, while a plain conf is streamed
+    let text = match format {
+        conf::OutputFormat::K8sConfigMap => Some(render_configmap(
+            &render_nginx_conf(&app_config),
+            &configmap_name,
+            configmap_namespace.as_deref(),
+            &app_config
+                .nginx_conf
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_else(|| "default.conf".into()),
+        )?),
+        conf::OutputFormat::Nginx if app_config.managed_region => {
+            Some(render_nginx_conf(&app_config))
+        }
+        conf::OutputFormat::Nginx => None,
+    };
+    let sha256 = match text {
+        Some(ref text) => conf_sha256(text),
+        None => render_sha256(&app_config),
+    };
+    let existing = fs::read_to_string(&app_config.nginx_conf).ok();
+    let current = match existing {
+        Some(ref existing) if app_config.managed_region => managed_region(existing),
+        Some(ref existing) => Some(existing.as_str()),
+        None => None,
+    };
+    // the banner has the time of writing, so only the rest is compared
+    let changed = current
+        .map(|current| conf_sha256(strip_banner(current)) != sha256)
+        .unwrap_or(true);
+
+    let set_mode_and_owner = |file: &fs::File| -> Result<()> {
+        if let Some(mode) = app_config.conf_mode {
+            output::set_mode(file, mode)?;
+        }
+        output::set_owner(
+            file,
+            app_config.conf_owner.as_deref(),
+            app_config.conf_group.as_deref(),
+        )
+    };
+    if changed {
+        // written aside and renamed over, so that nginx never reads a half-written conf
+        let banner = render_banner(&app_config, &sha256, SystemTime::now());
+        output::replace_with(
+            &app_config.nginx_conf,
+            set_mode_and_owner,
+            |mut writer| match text {
+                Some(text) => {
+                    let mut content = format!("{}{}", banner, text);
+                    if app_config.managed_region {
+                        content =
+                            replace_managed_region(existing.as_deref().unwrap_or(""), &content);
+                    }
+                    writer.write_all(content.as_bytes())
+                }
+                None => writer
+                    .write_all(banner.as_bytes())
+                    .and_then(|_| render_to(&app_config, &mut writer)),
+            },
+        )?;
+        info!("write nginx conf: {}", app_config.nginx_conf.display());
+    } else {
+        // Leave an identical file alone so that its mtime doesn't trigger reloads.
+        let file = fs::File::open(app_config.nginx_conf.as_path())
+            .map_err(|err| Error::io(app_config.nginx_conf.display(), err))?;
+        set_mode_and_owner(&file)?;
+        info!("nginx conf unchanged: {}", app_config.nginx_conf.display());
+    }
 
-        #[test]
-        fn nginx_conf_path_custom_args() {
-            use structopt::StructOpt;
-            let cli_args: &[&str] = &["test", "--nginx-conf", "/etc/nginx/conf.d/custom.conf"];
-            let args = Args::from_iter_safe(cli_args.iter()).unwrap();
-            assert_eq!(
-                Some(PathBuf::from("/etc/nginx/conf.d/custom.conf")),
-                args.nginx_conf
-            );
+    if let Some(ref target) = deploy {
+        if changed {
+            deploy::deploy(target, &app_config.nginx_conf, &deploy_reload)?;
         }
+    }
+
+    if let Some(ref path) = emit_model {
+        let model = format_model(&render_model(&app_config), path)?;
+        fs::write(path, model).map_err(|err| Error::io(path.display(), err))?;
+        info!("write model: {}", path.display());
+    }
 
-        #[test]
-        #[should_panic(expected = "--nginx-conf without value")]
-        fn nginx_conf_path_empty_args() {
-            use structopt::StructOpt;
-            let cli_args: &[&str] = &["test", "--nginx-conf"];
-            Args::from_iter_safe(cli_args.iter()).expect("--nginx-conf without value");
+    if app_config.source_map {
+        let path = source_map_path(&app_config.nginx_conf);
+        let map = format!("{:#}\n", render_source_map(&app_config));
+        if fs::read_to_string(&path).ok().as_deref() != Some(map.as_str()) {
+            fs::write(&path, map).map_err(|err| Error::io(path.display(), err))?;
+            info!("write source map: {}", path.display());
         }
+    }
 
-        #[test]
-        fn nginx_conf_path_default_app_config() {
-            use structopt::StructOpt;
-            let cli_args: &[&str] = &["test"];
-            let args = Args::from_iter_safe(cli_args.iter()).unwrap();
-            let app_config = AppConfig::from_args_and_config(args).unwrap();
-            assert_eq!(
-                PathBuf::from("/etc/nginx/conf.d/default.conf"),
-                app_config.nginx_conf
-            );
+    let reloads = reload_nginx.is_some() || app_config.on_change.is_some();
+    let reload = |changed: bool, check: bool| -> Result<()> {
+        if let (true, Some(reload_nginx)) = (changed, reload_nginx) {
+            reload_nginx()?;
+        }
+        if let Some(ref on_change) = app_config.on_change {
+            run_on_change(on_change, &app_config.nginx_conf, changed)?;
+        }
+        match app_config.reload_check {
+            Some(ref url) if check && changed && reloads => {
+                check_reload(url, 5, Duration::from_millis(500))
+            }
+            _ => Ok(()),
         }
+    };
+    // what was written, for the history, the audit log and the webhook
+    let path = &app_config.nginx_conf;
+    let keeps_written =
+        app_config.history > 0 || app_config.audit_log.is_some() || app_config.notify_url.is_some();
+    let written = match changed && keeps_written {
+        true => Some(fs::read_to_string(path).map_err(|err| Error::io(path.display(), err))?),
+        false => None,
+    };
+
+    let reloaded = reload(changed, true);
+    if reloaded.is_err() {
+        // put back the conf nginx was running with
+        if let (true, true, Some(previous)) = (changed, reloads, existing.as_deref()) {
+            output::replace(path, previous)?;
+            warn!("restore the previous nginx conf: {}", path.display());
+            if let Err(err) = reload(true, false) {
+                error!("reload with the previous nginx conf: {}", err);
+            }
+        }
+    }
 
-        #[test]
-        fn nginx_conf_path_custom_app_config() {
-            use structopt::StructOpt;
-            let cli_args: &[&str] = &["test", "--config-dir", "./tests/conf_nginx_dir"];
-            let args = Args::from_iter_safe(cli_args.iter()).unwrap();
-            let app_config = AppConfig::from_args_and_config(args).unwrap();
-            assert_eq!(
-                PathBuf::from("./tmp/nginx_default.conf"),
-                app_config.nginx_conf
-            );
+    let now = humantime::format_rfc3339_seconds(SystemTime::now()).to_string();
+    let sources: Vec<String> = app_config
+        .sources
+        .iter()
+        .map(|source| source.display().to_string())
+        .collect();
+    let trigger = if reload_nginx.is_some() { "run" } else { "cli" };
+    let record = written
+        .as_deref()
+        .map(|written| audit::record(&now, path, trigger, &sources, existing.as_deref(), written));
+    if let (Some(record), Some(url)) = (&record, &app_config.notify_url) {
+        let result = match reloaded {
+            Ok(()) if reloads => "ok".to_owned(),
+            Ok(()) => "none".to_owned(),
+            Err(ref err) if existing.is_some() => {
+                format!("failed, the previous nginx conf restored: {}", err)
+            }
+            Err(ref err) => format!("failed: {}", err),
+        };
+        let payload = notify::payload(record, &result, app_config.notify_format);
+        // the nginx conf is in place either way
+        if let Err(err) = notify::post(url, &payload) {
+            warn!("{}", err);
         }
     }
-}
+    reloaded?;
 
-use failure::ResultExt;
-use std::fs;
-use std::io::{self, Write};
+    if let (Some(record), Some(audit_log)) = (&record, &app_config.audit_log) {
+        audit::append(audit_log, record)?;
+    }
+    if let (Some(written), true) = (written, app_config.history > 0) {
+        let entry = history::record(path, &written, now, sha256, sources, app_config.history)?;
+        info!(
+            "keep nginx conf as {}: {}",
+            entry.id,
+            history::dir(path).display()
+        );
+    }
 
-fn main() -> Result<(), exitfailure::ExitFailure> {
-    let args = conf::Args::from_args();
-    env_logger::builder()
-        .filter_level(args.verbose.log_level().to_level_filter())
-        .init();
-    debug!("args: {:#?}", args);
-    let app_config = conf::AppConfig::from_args_and_config(args).context("Load config")?;
-    debug!("app_config: {:#?}", app_config);
+    Ok(match changed_exit_code {
+        Some(code) if changed => code,
+        _ => 0,
+    })
+}
 
-    let mut writer = io::BufWriter::new(
-        fs::File::create(app_config.nginx_conf.as_path())
-            .with_context(|err| format!("{}: {}", err, app_config.nginx_conf.display()))?,
-    );
-    write!(
-        writer,
-        "{}",
-        render_nginx_conf(
-            &app_config.host,
-            app_config.port,
-            app_config.domain.as_ref().map(|s| s.as_str()),
-            &app_config.reverse_proxy
+/// Writes back a kept nginx conf, `to` or the one before the last, and runs `--on-change`.
+fn rollback(app_config: &conf::AppConfig, to: Option<u64>, list: bool) -> Result<()> {
+    let path = &app_config.nginx_conf;
+    let entries = history::entries(path)?;
+    if list {
+        for entry in entries.iter().rev() {
+            let sources = if entry.sources.is_empty() {
+                "command line".to_owned()
+            } else {
+                entry.sources.join(", ")
+            };
+            println!(
+                "{}\t{}\t{}\t{}",
+                entry.id,
+                entry.time,
+                &entry.sha256[..entry.sha256.len().min(12)],
+                sources
+            );
+        }
+        return Ok(());
+    }
+    let entry = match to {
+        Some(id) => entries.iter().find(|entry| entry.id == id),
+        None => entries.iter().rev().nth(1),
+    }
+    .ok_or_else(|| {
+        let what = match to {
+            Some(id) => format!("no nginx conf {} kept", id),
+            None => "no previous nginx conf kept, see --history".to_owned(),
+        };
+        Error::io(
+            history::dir(path).display(),
+            io::Error::new(io::ErrorKind::NotFound, what),
         )
-    )?;
+    })?;
+    let content = history::content(path, entry.id)?;
+    let _lock = lock::ConfLock::acquire(path, Duration::from_secs(app_config.lock_timeout))?;
+    let previous = fs::read_to_string(path).ok();
+    fs::write(path, &content).map_err(|err| Error::io(path.display(), err))?;
+    if let Some(ref audit_log) = app_config.audit_log {
+        let now = humantime::format_rfc3339_seconds(SystemTime::now()).to_string();
+        let record = audit::record(
+            &now,
+            path,
+            "rollback",
+            &entry.sources,
+            previous.as_deref(),
+            &content,
+        );
+        audit::append(audit_log, &record)?;
+    }
+    info!(
+        "roll back to {} of {}: {}",
+        entry.id,
+        entry.time,
+        path.display()
+    );
+    if let Some(ref on_change) = app_config.on_change {
+        run_on_change(on_change, path, true)?;
+    }
+    Ok(())
+}
 
+/// Terraform's external data source protocol: a JSON object in stdin, another in stdout.
+fn tf_external(mut args: conf::Args) -> Result<()> {
+    let query: BTreeMap<String, String> = serde_json::from_reader(io::stdin())
+        .map_err(|err| Error::parse("stdin", format!("Failed to parse the query ({})", err)))?;
+    args.apply_query(query)?;
+    let app_config = conf::AppConfig::from_args_and_config(args)?;
+    println!("{}", render_tf_external(&render_nginx_conf(&app_config)));
     Ok(())
 }
 
-pub fn render_nginx_conf(
-    host: &str,
-    port: u16,
-    domain: Option<&str>,
-    reverse_proxy_mappings: &[conf::ReverseProxyMapping],
-) -> String {
-    let reverse_proxy_locations =
-        reverse_proxy_mappings
-            .iter()
-            .fold(String::new(), |mut buf, rp| {
-                buf.push_str(&format!(
-                    r#"
-    location {} {{
-        proxy_pass {};
-    }}
-"#,
-                    rp.path, rp.url
-                ));
-                buf
-            });
+/// Logs the warnings of loading the config grouped, failing on any with `--strict`.
+fn report_warnings(strict: bool) -> Result<()> {
+    let warnings = diagnostics::take();
+    let report = diagnostics::format(&warnings);
+    if strict && !warnings.is_empty() {
+        return Err(Error::Render(format!(
+            "{} warnings with --strict\n{}",
+            warnings.len(),
+            report.trim_end()
+        )));
+    }
+    for line in report.lines() {
+        warn!("{}", line);
+    }
+    Ok(())
+}
 
-    let conf = format!(
-        r#"
-server {{
-    listen {}:{};
-    server_name {};
+/// Applies `add` or `remove`, returning the edited file and its previous content.
+fn edit_mappings(
+    command: Option<&conf::Command>,
+    config_dir: &Path,
+) -> Result<Option<(PathBuf, Option<String>)>> {
+    let managed = |file: &Option<PathBuf>| {
+        file.clone()
+            .unwrap_or_else(|| config_dir.join("sites").join("managed.yaml"))
+    };
+    match command {
+        Some(conf::Command::Add {
+            path,
+            url,
+            websocket,
+            strip_prefix,
+            options,
+            file,
+        }) => {
+            let mut mapping = conf::ReverseProxyMapping::parse(&format!("{}->{}", path, url))?;
+            for option in options {
+                let (key, value) = match option.split_once('=') {
+                    Some((key, value)) => (key, Some(value)),
+                    None => (option.as_str(), None),
+                };
+                mapping.set_option(key, value)?;
+            }
+            mapping.websocket |= websocket;
+            mapping.strip_prefix |= strip_prefix;
+            let file = managed(file);
+            let backup = fs::read_to_string(&file).ok();
+            edit::add_mapping(&file, &mapping)?;
+            info!("add {} to {}", path, file.display());
+            Ok(Some((file, backup)))
+        }
+        Some(conf::Command::Remove { path, file }) => {
+            let file = managed(file);
+            let backup = fs::read_to_string(&file).ok();
+            edit::remove_mapping(&file, path)?;
+            info!("remove {} from {}", path, file.display());
+            Ok(Some((file, backup)))
+        }
+        _ => Ok(None),
+    }
+}
 
-    proxy_set_header Host $host;
-    proxy_set_header X-Real-IP $remote_addr;
-    proxy_set_header X-Forwarded-Host $http_host;
-    proxy_set_header X-Forwarded-Server $host;
-    proxy_set_header X-Forwarded-For $proxy_add_x_forwarded_for;
+fn import_conf(nginx_conf: &Path, output: &Path, force: bool) -> Result<()> {
+    let conf =
+        fs::read_to_string(nginx_conf).map_err(|err| Error::io(nginx_conf.display(), err))?;
+    let config = import::to_config(&conf)?;
+    if output.exists() && !force {
+        return Err(Error::io(
+            output.display(),
+            io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "exists, use --force to overwrite",
+            ),
+        ));
+    }
+    fs::write(output, config).map_err(|err| Error::io(output.display(), err))?;
+    info!("import {} into {}", nginx_conf.display(), output.display());
+    Ok(())
+}
 
-    {}
-}}
-"#,
-        host,
-        port,
-        domain.unwrap_or("localhost"),
-        reverse_proxy_locations,
-    );
+fn manage_users(command: conf::AuthCommand) -> Result<()> {
+    match command {
+        conf::AuthCommand::AddUser { user, file } => {
+            let mut password = String::new();
+            io::stdin()
+                .read_line(&mut password)
+                .map_err(|err| Error::io("stdin", err))?;
+            let password = password.trim_end_matches(&['\r', '\n'][..]);
+            htpasswd::add_user(&file, &user, password, bcrypt::DEFAULT_COST)?;
+            info!("add user {} to {}", user, file.display());
+        }
+        conf::AuthCommand::RemoveUser { user, file } => {
+            htpasswd::remove_user(&file, &user)?;
+            info!("remove user {} from {}", user, file.display());
+        }
+        conf::AuthCommand::ListUsers { file } => {
+            for user in htpasswd::list_users(&file)? {
+                println!("{}", user);
+            }
+        }
+    }
+    Ok(())
+}
 
-    conf
+fn print_cert_status(app_config: &conf::AppConfig, warn_days: i64) -> Result<()> {
+    let tls = app_config
+        .tls
+        .as_ref()
+        .ok_or_else(|| Error::Certificate("no certificate is configured".into()))?;
+    let mut paths = tls.certificate_paths(app_config.domain.as_deref());
+    for sd in &app_config.subdomains {
+        for path in tls.certificate_paths(Some(&sd.domain)) {
+            if !paths.contains(&path) {
+                paths.push(path);
+            }
+        }
+    }
+    let mut expiring = Vec::new();
+    for path in paths {
+        let status = cert::status(&path)?;
+        println!(
+            "{}\t{}\t{}\t{} days left",
+            status.path.display(),
+            status.subject,
+            status.not_after,
+            status.days_left
+        );
+        if status.days_left < warn_days {
+            expiring.push(status.path.display().to_string());
+        }
+    }
+    if !expiring.is_empty() {
+        return Err(Error::Certificate(format!(
+            "certificate expires within {} days: {}",
+            warn_days,
+            expiring.join(", ")
+        )));
+    }
+    Ok(())
 }
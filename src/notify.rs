@@ -0,0 +1,91 @@
+use crate::conf::NotifyFormat;
+use crate::error::{Error, Result};
+use std::io::{self, Write};
+use std::process::{Command, Stdio};
+use url::Url;
+
+/// An `audit::record` with `reload`: `ok`, `none` without a reload, or why it failed.
+pub fn payload(
+    record: &serde_json::Value,
+    reload: &str,
+    format: NotifyFormat,
+) -> serde_json::Value {
+    match format {
+        NotifyFormat::Json => {
+            let mut payload = record.clone();
+            payload["reload"] = reload.into();
+            payload
+        }
+        NotifyFormat::Slack => {
+            let mut text = format!(
+                "nginx conf changed ({}): {}\n+{} -{} lines",
+                record["trigger"].as_str().unwrap_or(""),
+                record["nginx_conf"].as_str().unwrap_or(""),
+                record["lines_added"],
+                record["lines_removed"]
+            );
+            for (key, sign) in &[("added", "+"), ("removed", "-")] {
+                for line in record[*key].as_array().into_iter().flatten() {
+                    text.push_str(&format!("\n{} `{}`", sign, line.as_str().unwrap_or("")));
+                }
+            }
+            text.push_str(&format!("\nreload: {}", reload));
+            serde_json::json!({ "text": text })
+        }
+    }
+}
+
+/// POSTs `payload` with `curl`, which also takes https.
+pub fn post(url: &Url, payload: &serde_json::Value) -> Result<()> {
+    let context = format!("notify {}", url);
+    let mut child = Command::new("curl")
+        .args(["-fsS", "-m", "10", "-X", "POST"])
+        .args([
+            "-H",
+            "Content-Type: application/json",
+            "--data-binary",
+            "@-",
+        ])
+        .arg(url.as_str())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()
+        .map_err(|err| Error::io(&context, err))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin
+            .write_all(payload.to_string().as_bytes())
+            .map_err(|err| Error::io(&context, err))?;
+    }
+    let status = child.wait().map_err(|err| Error::io(&context, err))?;
+    if !status.success() {
+        return Err(Error::io(
+            &context,
+            io::Error::other(format!("curl failed ({})", status)),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn slack_payload() {
+        let record = serde_json::json!({
+            "trigger": "run",
+            "nginx_conf": "/etc/nginx/conf.d/default.conf",
+            "lines_added": 5,
+            "lines_removed": 0,
+            "added": ["location /api"],
+            "removed": [],
+        });
+        assert_eq!(
+            serde_json::json!({
+                "text": "nginx conf changed (run): /etc/nginx/conf.d/default.conf\n+5 -0 lines\n+ `location /api`\nreload: ok"
+            }),
+            payload(&record, "ok", NotifyFormat::Slack)
+        );
+        assert_eq!("ok", payload(&record, "ok", NotifyFormat::Json)["reload"]);
+    }
+}
@@ -0,0 +1,161 @@
+use crate::error::{Error, Result};
+use std::ffi::{CString, OsString};
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+/// Writes `path` through `<path>.tmp`, synced and renamed over it, so that nobody reads a
+/// half-written file. The new file keeps the mode and, where allowed, the owner of the one
+/// it replaces; `prepare` may change them before anything is written.
+pub fn replace_with(
+    path: &Path,
+    prepare: impl FnOnce(&fs::File) -> Result<()>,
+    write: impl FnOnce(&mut dyn Write) -> io::Result<()>,
+) -> Result<()> {
+    let tmp = tmp_path(path);
+    let result = write_tmp(path, &tmp, prepare, write)
+        .and_then(|_| fs::rename(&tmp, path).map_err(|err| Error::io(path.display(), err)));
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    result
+}
+
+/// [`replace_with`] for content at hand.
+pub fn replace(path: &Path, content: impl AsRef<[u8]>) -> Result<()> {
+    replace_with(
+        path,
+        |_| Ok(()),
+        |writer| writer.write_all(content.as_ref()),
+    )
+}
+
+fn tmp_path(path: &Path) -> PathBuf {
+    let mut tmp = OsString::from(path.as_os_str());
+    tmp.push(".tmp");
+    PathBuf::from(tmp)
+}
+
+fn write_tmp(
+    path: &Path,
+    tmp: &Path,
+    prepare: impl FnOnce(&fs::File) -> Result<()>,
+    write: impl FnOnce(&mut dyn Write) -> io::Result<()>,
+) -> Result<()> {
+    let file = fs::File::create(tmp).map_err(|err| Error::io(tmp.display(), err))?;
+    if let Ok(metadata) = fs::metadata(path) {
+        file.set_permissions(metadata.permissions())
+            .map_err(|err| Error::io(tmp.display(), err))?;
+        let created = file
+            .metadata()
+            .map_err(|err| Error::io(tmp.display(), err))?;
+        if (created.uid(), created.gid()) != (metadata.uid(), metadata.gid()) {
+            if let Err(err) = fchown(&file, metadata.uid(), metadata.gid()) {
+                debug!("keep the owner of {}: {}", path.display(), err);
+            }
+        }
+    }
+    prepare(&file)?;
+    let mut writer = io::BufWriter::new(&file);
+    write(&mut writer)
+        .and_then(|_| writer.flush())
+        .map_err(|err| Error::io(path.display(), err))?;
+    drop(writer);
+    file.sync_all().map_err(|err| Error::io(tmp.display(), err))
+}
+
+pub fn set_mode(file: &fs::File, mode: u32) -> Result<()> {
+    file.set_permissions(fs::Permissions::from_mode(mode))
+        .map_err(|err| Error::io("Failed to set the nginx conf mode", err))?;
+    Ok(())
+}
+
+/// `owner` and `group` are names or numeric ids; `None` leaves them unchanged.
+pub fn set_owner(file: &fs::File, owner: Option<&str>, group: Option<&str>) -> Result<()> {
+    let uid = owner.map(resolve_uid).transpose()?;
+    let gid = group.map(resolve_gid).transpose()?;
+    if uid.is_none() && gid.is_none() {
+        return Ok(());
+    }
+    fchown(
+        file,
+        uid.unwrap_or(!0 as libc::uid_t),
+        gid.unwrap_or(!0 as libc::gid_t),
+    )
+    .map_err(|err| Error::io("Failed to change the nginx conf owner", err))
+}
+
+fn fchown(file: &fs::File, uid: libc::uid_t, gid: libc::gid_t) -> io::Result<()> {
+    if unsafe { libc::fchown(file.as_raw_fd(), uid, gid) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+pub fn resolve_uid(owner: &str) -> Result<libc::uid_t> {
+    if let Ok(uid) = owner.parse() {
+        return Ok(uid);
+    }
+    let name = CString::new(owner).map_err(|err| Error::parse(owner, err))?;
+    let passwd = unsafe { libc::getpwnam(name.as_ptr()) };
+    if passwd.is_null() {
+        return Err(Error::parse(owner, "unknown user"));
+    }
+    Ok(unsafe { (*passwd).pw_uid })
+}
+
+pub fn resolve_gid(group: &str) -> Result<libc::gid_t> {
+    if let Ok(gid) = group.parse() {
+        return Ok(gid);
+    }
+    let name = CString::new(group).map_err(|err| Error::parse(group, err))?;
+    let grp = unsafe { libc::getgrnam(name.as_ptr()) };
+    if grp.is_null() {
+        return Err(Error::parse(group, "unknown group"));
+    }
+    Ok(unsafe { (*grp).gr_gid })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn resolve_numeric_and_named_ids() {
+        assert_eq!(101, resolve_uid("101").unwrap());
+        assert_eq!(0, resolve_uid("root").unwrap());
+        assert_eq!(0, resolve_gid("0").unwrap());
+        assert!(resolve_uid("no-such-user-for-rp").is_err());
+    }
+
+    #[test]
+    fn replace_keeps_the_mode() {
+        let dir = std::env::temp_dir().join(format!("rp-output-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("default.conf");
+        fs::write(&path, "old").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+
+        replace(&path, "new").unwrap();
+        assert_eq!("new", fs::read_to_string(&path).unwrap());
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(0o640, mode & 0o7777);
+        assert!(!tmp_path(&path).exists());
+
+        replace_with(
+            &path,
+            |file| set_mode(file, 0o600),
+            |writer| writer.write_all(b"newer"),
+        )
+        .unwrap();
+        assert_eq!("newer", fs::read_to_string(&path).unwrap());
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(0o600, mode & 0o7777);
+
+        assert!(replace(&dir.join("missing/default.conf"), "new").is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,131 @@
+use crate::error::{Error, Result};
+use std::io::{self, BufRead, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+
+/// Runs `cmd` with `sh -c`, passing the written conf path and whether its content changed.
+pub fn run_on_change(cmd: &str, nginx_conf: &Path, changed: bool) -> Result<()> {
+    info!("run on-change command: {}", cmd);
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(cmd)
+        .env("RP_CONF_PATH", nginx_conf)
+        .env(
+            "RP_CONF_STATUS",
+            if changed { "changed" } else { "unchanged" },
+        )
+        .status()
+        .map_err(|err| Error::io(format!("on-change command: {}", cmd), err))?;
+    info!("on-change command exited with {}: {}", status, cmd);
+    if !status.success() {
+        return Err(Error::io(
+            format!("on-change command: {}", cmd),
+            io::Error::other(format!("failed ({})", status)),
+        ));
+    }
+    Ok(())
+}
+
+/// GETs `url` until it answers 2xx or 3xx, `attempts` times `interval` apart,
+/// since nginx takes a moment to start workers with a new conf.
+pub fn check_reload(url: &url::Url, attempts: u32, interval: Duration) -> Result<()> {
+    let mut failure = String::from("not checked");
+    for _ in 0..attempts {
+        thread::sleep(interval);
+        match http_status(url) {
+            Ok(status) if (200..400).contains(&status) => {
+                info!("reload check {}: {}", url, status);
+                return Ok(());
+            }
+            Ok(status) => failure = format!("answered {}", status),
+            Err(err) => failure = err.to_string(),
+        }
+    }
+    Err(Error::io(
+        format!("reload check {}", url),
+        io::Error::other(failure),
+    ))
+}
+
+fn http_status(url: &url::Url) -> io::Result<u16> {
+    let host = url.host_str().unwrap_or("localhost");
+    let port = url.port_or_known_default().unwrap_or(80);
+    let mut stream = TcpStream::connect((host, port))?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut target = url.path().to_owned();
+    if let Some(query) = url.query() {
+        target.push('?');
+        target.push_str(query);
+    }
+    let authority = match url.port() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host.to_owned(),
+    };
+    let request = format!(
+        "GET {} HTTP/1.0\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        target, authority
+    );
+    stream.write_all(request.as_bytes())?;
+    let mut status_line = String::new();
+    io::BufReader::new(stream).read_line(&mut status_line)?;
+    status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("not an HTTP response: {:?}", status_line.trim()),
+            )
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn run_on_change_passes_status() {
+        let nginx_conf = Path::new("/tmp/nginx.conf");
+        run_on_change(
+            r#"test "$RP_CONF_PATH" = /tmp/nginx.conf && test "$RP_CONF_STATUS" = changed"#,
+            nginx_conf,
+            true,
+        )
+        .unwrap();
+        run_on_change(r#"test "$RP_CONF_STATUS" = unchanged"#, nginx_conf, false).unwrap();
+    }
+
+    fn serve_once(response: &'static str) -> url::Url {
+        use std::io::Read;
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/healthz", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut request = [0; 1024];
+                let _ = stream.read(&mut request);
+                let _ = stream.write_all(response.as_bytes());
+            }
+        });
+        url::Url::parse(&url).unwrap()
+    }
+
+    #[test]
+    fn check_reload_status() {
+        let interval = Duration::from_millis(1);
+        let ok = serve_once("HTTP/1.1 200 OK\r\n\r\n");
+        check_reload(&ok, 1, interval).unwrap();
+        let bad_gateway = serve_once("HTTP/1.1 502 Bad Gateway\r\n\r\n");
+        let err = check_reload(&bad_gateway, 2, interval).unwrap_err();
+        assert!(err.to_string().contains("answered 502"), "{}", err);
+    }
+
+    #[test]
+    fn run_on_change_fails_on_non_zero_exit() {
+        assert!(run_on_change("exit 3", Path::new("/tmp/nginx.conf"), true).is_err());
+    }
+}
@@ -0,0 +1,2990 @@
+use crate::conf;
+use crate::error::{Error, Result};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeSet;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+pub fn render_nginx_conf(app_config: &conf::AppConfig) -> String {
+    let mut conf = Vec::new();
+    render_to(app_config, &mut conf).expect("writing to a Vec doesn't fail");
+    String::from_utf8(conf).expect("rendered from strings")
+}
+
+/// Writes the nginx conf to `writer` a block at a time, never holding all of it,
+/// which matters with thousands of mappings.
+pub fn render_to<W: io::Write>(app_config: &conf::AppConfig, writer: &mut W) -> io::Result<()> {
+    if app_config.full_conf {
+        writer.write_all(render_full_conf_head(&app_config.workers).as_bytes())?;
+    }
+    for import in &app_config.js_import {
+        writeln!(writer, "js_import {};", import)?;
+    }
+    if app_config.tracing {
+        writer.write_all(
+            br#"
+map $http_x_request_id $rp_request_id {
+    "" $request_id;
+    default $http_x_request_id;
+}
+"#,
+        )?;
+    }
+    for (name, format) in &app_config.log.formats {
+        writeln!(
+            writer,
+            "log_format {} {};",
+            name,
+            quote(&format.format_string())
+        )?;
+    }
+    if app_config.log.is_conditional() {
+        writer.write_all(render_log_condition(&app_config.log).as_bytes())?;
+    }
+    for map in &app_config.maps {
+        writer.write_all(render_map(map).as_bytes())?;
+    }
+    for upstream in &app_config.upstreams {
+        writer.write_all(render_upstream(app_config, upstream).as_bytes())?;
+    }
+    for rp in app_config
+        .reverse_proxy
+        .iter()
+        .chain(app_config.subdomains.iter().map(|sd| &sd.mapping))
+    {
+        if let Some(ref canary) = rp.canary {
+            writer.write_all(render_canary(app_config, rp, canary).as_bytes())?;
+        }
+        if !rp.routes.is_empty() {
+            writer.write_all(render_routes(app_config, rp).as_bytes())?;
+        }
+        if let Some(ref require_header) = rp.require_header {
+            writer.write_all(render_require_header(rp, require_header).as_bytes())?;
+        }
+        if let Some(ref mirror_url) = rp.mirror_url {
+            let mut block = String::new();
+            upstream_for(
+                app_config,
+                mirror_url,
+                generated_name("mirror", rp),
+                &mut block,
+            );
+            writer.write_all(block.as_bytes())?;
+        }
+    }
+    // with subdomains only, there is no server block of `domain`
+    if app_config.renders_main_server() {
+        writer.write_all(
+            render_server(
+                app_config,
+                app_config.domain.as_deref(),
+                &app_config.reverse_proxy,
+                true,
+            )
+            .as_bytes(),
+        )?;
+    }
+    for sd in &app_config.subdomains {
+        writer.write_all(
+            render_server(
+                app_config,
+                Some(&sd.domain),
+                std::slice::from_ref(&sd.mapping),
+                false,
+            )
+            .as_bytes(),
+        )?;
+    }
+    if app_config.full_conf {
+        writer.write_all(b"}\n")?;
+    }
+    Ok(())
+}
+
+/// The sha256 of the nginx conf in hex, rendered without holding it.
+pub fn render_sha256(app_config: &conf::AppConfig) -> String {
+    let mut hasher = Sha256::new();
+    render_to(app_config, &mut hasher).expect("hashing doesn't fail");
+    format!("{:x}", hasher.finalize())
+}
+
+/// The sha256 of `conf` in hex, as `render_sha256` and the banner have it.
+pub fn conf_sha256(conf: &str) -> String {
+    format!("{:x}", Sha256::digest(conf.as_bytes()))
+}
+
+/// The beginning of a whole nginx.conf, up to the opening of the http block
+/// which the rendered conf goes in, closed by `}`.
+pub fn render_full_conf_head(workers: &conf::Workers) -> String {
+    format!(
+        r#"user nginx;
+worker_processes {};
+worker_rlimit_nofile {};
+error_log /var/log/nginx/error.log warn;
+pid /var/run/nginx.pid;
+
+events {{
+    worker_connections {};
+    multi_accept {};
+}}
+
+http {{
+    include /etc/nginx/mime.types;
+    default_type application/octet-stream;
+    access_log /var/log/nginx/access.log;
+    sendfile on;
+    keepalive_timeout 65;
+"#,
+        workers.processes,
+        workers.rlimit_nofile,
+        workers.connections,
+        if workers.multi_accept { "on" } else { "off" },
+    )
+}
+
+/// `$rp_loggable`, `1` for the requests to access log, by `skip_status` or `slower_than`.
+pub fn render_log_condition(log: &conf::LogConfig) -> String {
+    let mut conf = String::new();
+    let mut variables = Vec::new();
+    if !log.skip_status.is_empty() {
+        conf.push_str("\nmap $status $rp_log_status {\n");
+        for status in &log.skip_status {
+            match status.strip_suffix("xx") {
+                Some(class) => conf.push_str(&format!("    ~^{} 0;\n", class)),
+                None => conf.push_str(&format!("    {} 0;\n", status)),
+            }
+        }
+        conf.push_str("    default 1;\n}\n");
+        variables.push("$rp_log_status");
+    }
+    if let Some(seconds) = log.slower_than {
+        // $request_time is like 1.234, compared as text by a regex
+        let ms = (seconds * 1000.0).round() as u64;
+        let longer = digits_at_least(&(ms / 1000 + 1).to_string());
+        let fraction = digits_at_least(&format!("{:03}", ms % 1000));
+        let regex = format!(
+            "~^([1-9]\\d{{{},}}|{})\\.|^{}\\.({})$",
+            (ms / 1000 + 1).to_string().len(),
+            longer.join("|"),
+            ms / 1000,
+            fraction.join("|"),
+        );
+        conf.push_str(&format!(
+            "\nmap $request_time $rp_log_slow {{\n    {} 1;\n    default 0;\n}}\n",
+            quote(&regex)
+        ));
+        variables.push("$rp_log_slow");
+    }
+    let source = variables.concat();
+    conf.push_str(&format!(
+        "\nmap {} $rp_loggable {{\n    {} 0;\n    default 1;\n}}\n",
+        quote(&source),
+        quote(&"0".repeat(variables.len())),
+    ));
+    conf
+}
+
+/// Regex alternatives matching the decimal numbers of as many digits as `digits`, not less.
+fn digits_at_least(digits: &str) -> Vec<String> {
+    let mut alternatives = vec![digits.to_owned()];
+    for (i, digit) in digits.bytes().enumerate() {
+        if digit < b'9' {
+            let rest = digits.len() - i - 1;
+            alternatives.push(format!(
+                "{}[{}-9]{}",
+                &digits[..i],
+                (digit + 1) as char,
+                if rest > 0 {
+                    format!("\\d{{{}}}", rest)
+                } else {
+                    String::new()
+                }
+            ));
+        }
+    }
+    alternatives
+}
+
+pub fn render_map(map: &conf::Map) -> String {
+    let mut lines = String::new();
+    if let Some(ref default) = map.default {
+        lines.push_str(&format!("    default {};\n", quote(default)));
+    }
+    for value in &map.values {
+        lines.push_str(&format!(
+            "    {} {};\n",
+            quote(&value.pattern),
+            quote(&value.value)
+        ));
+    }
+    format!(
+        r#"
+map {} {} {{
+{}}}
+"#,
+        map.source, map.variable, lines
+    )
+}
+
+pub fn render_upstream(app_config: &conf::AppConfig, upstream: &conf::Upstream) -> String {
+    let balancing = match (upstream.sticky, &upstream.sticky_cookie) {
+        (Some(conf::Sticky::IpHash), _) => "    ip_hash;\n".to_owned(),
+        (Some(conf::Sticky::Cookie), _) if app_config.nginx_plus => format!(
+            "    sticky cookie {};\n",
+            upstream
+                .sticky_cookie
+                .clone()
+                .unwrap_or_else(|| format!("rp_{}", upstream.name))
+        ),
+        (Some(conf::Sticky::Cookie), Some(cookie)) => {
+            format!("    hash $cookie_{} consistent;\n", cookie)
+        }
+        _ => String::new(),
+    };
+    let servers = upstream
+        .servers
+        .iter()
+        .map(|server| match server.weight {
+            Some(weight) => format!("    server {} weight={};\n", server.address, weight),
+            None => format!("    server {};\n", server.address),
+        })
+        .collect::<String>();
+    format!(
+        r#"
+upstream {} {{
+{}{}}}
+"#,
+        upstream.name, balancing, servers
+    )
+}
+
+/// A name for things generated for `rp`, eg. `canary_api_1a2b3c4d` for `/api`.
+fn generated_name(prefix: &str, rp: &conf::ReverseProxyMapping) -> String {
+    let path: String = rp
+        .path
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    let hash = Sha256::digest(format!("{} {}", rp.path, rp.url).as_bytes());
+    format!(
+        "{}{}_{:02x}{:02x}{:02x}{:02x}",
+        prefix,
+        path.trim_end_matches('_'),
+        hash[0],
+        hash[1],
+        hash[2],
+        hash[3]
+    )
+}
+
+/// The host of `url` if it is the name of one of `upstreams`.
+fn defined_upstream<'a>(app_config: &conf::AppConfig, url: &'a url::Url) -> Option<&'a str> {
+    url.host_str()
+        .filter(|host| app_config.upstreams.iter().any(|u| u.name == *host))
+}
+
+/// The name of one of `upstreams` if `url` points to it, or else of a new `upstream` block
+/// pushed to `conf`, so that nginx needs no resolver for a variable in `proxy_pass`.
+fn upstream_for(
+    app_config: &conf::AppConfig,
+    url: &url::Url,
+    name: String,
+    conf: &mut String,
+) -> String {
+    if let Some(upstream) = defined_upstream(app_config, url) {
+        return upstream.to_owned();
+    }
+    let host = url.host_str().unwrap_or_default();
+    let upstream = conf::Upstream {
+        name,
+        servers: vec![conf::UpstreamServer {
+            address: format!("{}:{}", host, url.port_or_known_default().unwrap_or(80)),
+            weight: None,
+        }],
+        sticky: None,
+        sticky_cookie: None,
+    };
+    conf.push_str(&render_upstream(app_config, &upstream));
+    upstream.name
+}
+
+/// `split_clients` choosing between the upstreams of `rp` and its canary.
+pub fn render_canary(
+    app_config: &conf::AppConfig,
+    rp: &conf::ReverseProxyMapping,
+    canary: &conf::Canary,
+) -> String {
+    let name = generated_name("canary", rp);
+    let mut conf = String::new();
+    let stable = upstream_for(app_config, &rp.url, format!("{}_stable", name), &mut conf);
+    let canary_upstream = upstream_for(
+        app_config,
+        &canary.url,
+        format!("{}_canary", name),
+        &mut conf,
+    );
+    conf.push_str(&format!(
+        r#"
+split_clients "${{remote_addr}}${{http_user_agent}}" ${} {{
+    {}% {};
+    * {};
+}}
+"#,
+        name, canary.percentage, canary_upstream, stable
+    ));
+    conf
+}
+
+/// A `map` of the header to the status rejecting the request, or `0` with the token.
+pub fn render_require_header(
+    rp: &conf::ReverseProxyMapping,
+    require_header: &conf::RequireHeader,
+) -> String {
+    let token = require_header.value.as_deref().unwrap_or_default();
+    // map takes a leading `~` for a regex and some words as parameters unless escaped
+    let token = if token.starts_with('~')
+        || ["default", "hostnames", "include", "volatile"].contains(&token)
+    {
+        format!("\\{}", token)
+    } else {
+        token.to_owned()
+    };
+    format!(
+        r#"
+map $http_{} ${} {{
+    "" 401;
+    {} 0;
+    default 403;
+}}
+"#,
+        require_header.name.to_ascii_lowercase().replace('-', "_"),
+        generated_name("require_header", rp),
+        quote(&token)
+    )
+}
+
+/// The upstreams of `rp` and its `routes` and the `map`s choosing one of them by the header
+/// or cookie, checked in order.
+pub fn render_routes(app_config: &conf::AppConfig, rp: &conf::ReverseProxyMapping) -> String {
+    let name = generated_name("route", rp);
+    let mut conf = String::new();
+    let default = upstream_for(app_config, &rp.url, format!("{}_default", name), &mut conf);
+    let upstreams: Vec<String> = rp
+        .routes
+        .iter()
+        .enumerate()
+        .map(|(i, route)| {
+            upstream_for(app_config, &route.url, format!("{}_{}", name, i), &mut conf)
+        })
+        .collect();
+    for (i, (route, upstream)) in rp.routes.iter().zip(&upstreams).enumerate() {
+        let (variable, value) = route.route_match().unwrap_or_default();
+        let key = match value.strip_prefix('~') {
+            Some(regex) => quote(&format!("~{}", regex.trim())),
+            None => format!("\"{}\"", value),
+        };
+        let result = if i == 0 {
+            name.clone()
+        } else {
+            format!("{}_next{}", name, i)
+        };
+        let next = if i + 1 < upstreams.len() {
+            format!("${}_next{}", name, i + 1)
+        } else {
+            default.clone()
+        };
+        conf.push_str(&format!(
+            r#"
+map {} ${} {{
+    {} {};
+    default {};
+}}
+"#,
+            variable, result, key, upstream, next
+        ));
+    }
+    conf
+}
+
+pub fn render_server(
+    app_config: &conf::AppConfig,
+    server_name: Option<&str>,
+    mappings: &[conf::ReverseProxyMapping],
+    main: bool,
+) -> String {
+    let mut reverse_proxy_locations = mappings.iter().fold(String::new(), |mut buf, rp| {
+        buf.push_str(&render_location(app_config, rp));
+        buf
+    });
+    // statics and responses belong to the server block of `domain`
+    if main {
+        for st in &app_config.statics {
+            reverse_proxy_locations.push_str(&render_static_location(st));
+        }
+        for response in &app_config.responses {
+            reverse_proxy_locations.push_str(&render_response_location(response));
+        }
+    }
+    reverse_proxy_locations.push_str(&render_deny_locations(&app_config.deny));
+    if let Some(ref healthz) = app_config.healthz {
+        reverse_proxy_locations.push_str(&format!(
+            r#"
+    location = {} {{
+        access_log off;
+        default_type text/plain;
+        return 200 "ok\n";
+    }}
+"#,
+            healthz
+        ));
+    }
+
+    let tls = app_config
+        .tls
+        .as_ref()
+        .and_then(|tls| tls.certificate_for(server_name).map(|pair| (tls, pair)));
+    let (listen_ssl, ssl_directives) = match tls {
+        Some((tls, pair)) => {
+            let mut ssl_directives = format!(
+                r#"
+    ssl_certificate {};
+    ssl_certificate_key {};
+"#,
+                pair.certificate.display(),
+                pair.certificate_key.display()
+            );
+            if let Some(ref client_certificate) = tls.client_certificate {
+                ssl_directives.push_str(&format!(
+                    r#"    ssl_client_certificate {};
+    ssl_verify_client {};
+"#,
+                    client_certificate.display(),
+                    tls.verify_client.unwrap_or(conf::VerifyClient::On).as_str()
+                ));
+            }
+            if let Some(ref hardening) = tls.hardening {
+                ssl_directives.push_str(&render_tls_hardening(hardening));
+            }
+            (true, ssl_directives)
+        }
+        None => (false, String::new()),
+    };
+    let mut listen_params = String::new();
+    if server_name.unwrap_or(&app_config.default_domain) == "_" {
+        listen_params.push_str(" default_server");
+    }
+    if listen_ssl {
+        listen_params.push_str(" ssl");
+    }
+    if app_config.proxy_protocol {
+        listen_params.push_str(" proxy_protocol");
+    }
+    let first = if app_config.renders_main_server() {
+        main
+    } else {
+        app_config.subdomains.first().map(|sd| sd.domain.as_str()) == server_name
+    };
+    if first {
+        let listen = &app_config.listen;
+        if let Some(backlog) = listen.backlog {
+            listen_params.push_str(&format!(" backlog={}", backlog));
+        }
+        if listen.reuseport {
+            listen_params.push_str(" reuseport");
+        }
+        if let Some(ref so_keepalive) = listen.so_keepalive {
+            listen_params.push_str(&format!(" so_keepalive={}", so_keepalive));
+        }
+    }
+
+    let mut server_directives = String::new();
+    for address in &app_config.real_ip_from {
+        server_directives.push_str(&format!("    set_real_ip_from {};\n", address));
+    }
+    if !app_config.real_ip_from.is_empty() {
+        server_directives.push_str(&format!(
+            "    real_ip_header {};\n",
+            if app_config.proxy_protocol {
+                "proxy_protocol"
+            } else {
+                "X-Forwarded-For"
+            }
+        ));
+    }
+    if app_config.tracing {
+        // headers like X_Request_ID are dropped without this
+        server_directives.push_str("    underscores_in_headers on;\n");
+    }
+    if !app_config.server_tokens {
+        server_directives.push_str("    server_tokens off;\n");
+        if app_config.openresty {
+            server_directives.push_str("    more_clear_headers Server;\n");
+        }
+    }
+    for name in &app_config.hide_upstream_headers {
+        server_directives.push_str(&format!("    proxy_hide_header {};\n", name));
+    }
+    for (name, value) in app_config.timeouts.directives() {
+        server_directives.push_str(&format!("    {} {};\n", name, value));
+    }
+    if let Some(access_log) = app_config.log.access_log_directive(None) {
+        server_directives.push_str(&format!("    {}\n", access_log));
+    }
+    if let Some(ref error_log) = app_config.log.error_log {
+        server_directives.push_str(&format!(
+            "    error_log {}{};\n",
+            error_log,
+            app_config
+                .log
+                .error_log_level
+                .as_ref()
+                .map(|level| format!(" {}", level))
+                .unwrap_or_default()
+        ));
+    }
+    if let Some(ref charset) = app_config.charset {
+        server_directives.push_str(&format!("    charset {};\n", charset));
+        // the default charset_types and JSON
+        server_directives.push_str("    charset_types text/html text/xml text/plain text/vnd.wap.wml application/javascript application/rss+xml application/json;\n");
+    }
+    if let Some(ref default_type) = app_config.default_type {
+        server_directives.push_str(&format!("    default_type {};\n", default_type));
+    }
+    if app_config.performance {
+        server_directives.push_str(
+            r#"    sendfile on;
+    tcp_nopush on;
+    tcp_nodelay on;
+    open_file_cache max=10000 inactive=60s;
+    open_file_cache_valid 60s;
+    open_file_cache_min_uses 2;
+    open_file_cache_errors on;
+"#,
+        );
+    }
+
+    let conf = format!(
+        r#"
+server {{
+    listen {}:{}{};
+    server_name {};
+{}
+{}
+    {}
+}}
+"#,
+        app_config.host,
+        app_config.port,
+        listen_params,
+        server_name.unwrap_or(&app_config.default_domain),
+        ssl_directives,
+        app_config
+            .default_headers
+            .iter()
+            .fold(server_directives, |mut buf, (name, value)| {
+                buf.push_str(&format!(
+                    "    proxy_set_header {} {};\n",
+                    name,
+                    quote(value)
+                ));
+                buf
+            }),
+        reverse_proxy_locations,
+    );
+
+    conf
+}
+
+/// What the nginx conf is rendered from, for tools checking it without parsing nginx syntax.
+pub fn render_model(app_config: &conf::AppConfig) -> serde_json::Value {
+    let mut servers = Vec::new();
+    if app_config.renders_main_server() {
+        servers.push(serde_json::json!({
+            "server_name": app_config.server_name(),
+            "locations": app_config.reverse_proxy,
+            "statics": app_config.statics,
+            "responses": app_config.responses,
+        }));
+    }
+    for sd in &app_config.subdomains {
+        servers.push(serde_json::json!({
+            "server_name": sd.domain,
+            "locations": [sd.mapping],
+        }));
+    }
+    serde_json::json!({
+        "listen": { "host": app_config.host, "port": app_config.port, "tls": app_config.tls.is_some() },
+        "servers": servers,
+        "upstreams": app_config.upstreams,
+        "maps": app_config.maps,
+        "default_headers": app_config.default_headers,
+    })
+}
+
+/// A row of `list`, what a location of a server block proxies to.
+#[derive(Debug, Clone, PartialEq, serde_derive::Serialize)]
+pub struct Route {
+    pub domain: String,
+    pub path: String,
+    pub upstream: String,
+    pub options: serde_json::Map<String, serde_json::Value>,
+    pub source: Option<String>,
+}
+
+/// The locations proxied by the server blocks, in the order rendered.
+pub fn routing_table(app_config: &conf::AppConfig) -> Vec<Route> {
+    let route = |domain: &str, rp: &conf::ReverseProxyMapping| Route {
+        domain: domain.to_owned(),
+        path: rp.path.clone(),
+        upstream: rp.url.to_string(),
+        options: rp.changed_options(),
+        source: rp.source.clone(),
+    };
+    let mut routes = Vec::new();
+    if app_config.renders_main_server() {
+        let domain = app_config.server_name();
+        for rp in &app_config.reverse_proxy {
+            routes.extend(
+                rp.routes
+                    .iter()
+                    .map(|alternative| route(domain, alternative)),
+            );
+            routes.push(route(domain, rp));
+        }
+    }
+    for sd in &app_config.subdomains {
+        routes.push(route(&sd.domain, &sd.mapping));
+    }
+    routes
+}
+
+/// `routes` as columns aligned by spaces, with flags by their names and other options as `key=value`.
+pub fn format_routing_table(routes: &[Route]) -> String {
+    let rows: Vec<[String; 5]> = routes
+        .iter()
+        .map(|route| {
+            let options = route
+                .options
+                .iter()
+                .map(|(key, value)| match value {
+                    serde_json::Value::Bool(true) => key.clone(),
+                    serde_json::Value::String(value) => format!("{}={}", key, value),
+                    value => format!("{}={}", key, value),
+                })
+                .collect::<Vec<_>>()
+                .join(",");
+            [
+                route.domain.clone(),
+                route.path.clone(),
+                route.upstream.clone(),
+                if options.is_empty() {
+                    "-".into()
+                } else {
+                    options
+                },
+                route.source.clone().unwrap_or_else(|| "-".into()),
+            ]
+        })
+        .collect();
+    let header = ["DOMAIN", "PATH", "UPSTREAM", "OPTIONS", "SOURCE"].map(String::from);
+    let mut widths = [0; 5];
+    for row in std::iter::once(&header).chain(&rows) {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    let mut table = String::new();
+    for row in std::iter::once(&header).chain(&rows) {
+        let line = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, &width)| format!("{:width$}", cell, width = width))
+            .collect::<Vec<_>>()
+            .join("  ");
+        table.push_str(line.trim_end());
+        table.push('\n');
+    }
+    table
+}
+
+/// `model` as YAML if `path` ends with `.yaml` or `.yml`, or else as JSON.
+pub fn format_model(model: &serde_json::Value, path: &Path) -> Result<String> {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("yaml") | Some("yml") => {
+            serde_yaml::to_string(model).map_err(|err| Error::Render(err.to_string()))
+        }
+        _ => Ok(format!("{:#}\n", model)),
+    }
+}
+
+/// The result of `tf-external`, which Terraform requires to be an object of strings.
+pub fn render_tf_external(conf: &str) -> serde_json::Value {
+    serde_json::json!({
+        "conf": conf,
+        "sha256": conf_sha256(conf),
+    })
+}
+
+/// A ConfigMap manifest with `conf` as `key`, for `kubectl apply`.
+pub fn render_configmap(
+    conf: &str,
+    name: &str,
+    namespace: Option<&str>,
+    key: &str,
+) -> Result<String> {
+    let mut metadata = serde_json::json!({ "name": name });
+    if let Some(namespace) = namespace {
+        metadata["namespace"] = namespace.into();
+    }
+    let manifest = serde_json::json!({
+        "apiVersion": "v1",
+        "kind": "ConfigMap",
+        "metadata": metadata,
+        "data": { key: conf },
+    });
+    serde_yaml::to_string(&manifest).map_err(|err| Error::Render(err.to_string()))
+}
+
+/// Directories holding the certificates and CA bundle the conf refers to, which nginx has to
+/// see at the same paths.
+fn referenced_dirs(app_config: &conf::AppConfig) -> BTreeSet<PathBuf> {
+    let mut dirs = BTreeSet::new();
+    if let Some(tls) = &app_config.tls {
+        dirs.extend(tls.cert_dir.clone());
+        let files = tls
+            .certificate
+            .iter()
+            .chain(&tls.certificate_key)
+            .chain(&tls.client_certificate)
+            .chain(tls.certificates.iter().map(|dc| &dc.certificate))
+            .chain(tls.certificates.iter().map(|dc| &dc.certificate_key));
+        for file in files {
+            if let Some(parent) = file.parent() {
+                if !dirs.iter().any(|dir| parent.starts_with(dir)) {
+                    dirs.insert(parent.to_path_buf());
+                }
+            }
+        }
+    }
+    dirs
+}
+
+/// The certificate and key of each domain and the CA bundle the conf refers to.
+pub fn certificate_files(app_config: &conf::AppConfig) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    if let Some(tls) = &app_config.tls {
+        let domains = std::iter::once(app_config.domain.as_deref()).chain(
+            app_config
+                .subdomains
+                .iter()
+                .map(|sd| Some(sd.domain.as_str())),
+        );
+        let pairs = domains.filter_map(|domain| tls.certificate_for(domain));
+        let paths = pairs
+            .flat_map(|pair| vec![pair.certificate, pair.certificate_key])
+            .chain(tls.client_certificate.clone());
+        for path in paths {
+            if !files.contains(&path) {
+                files.push(path);
+            }
+        }
+    }
+    files
+}
+
+/// A docker-compose file running nginx with the conf written by this command in a sidecar
+/// reading `config_dir`, through a volume shared between the two.
+pub fn render_compose(app_config: &conf::AppConfig, config_dir: &Path) -> Result<String> {
+    let conf_name = app_config
+        .nginx_conf
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "default.conf".to_owned());
+    let mut nginx_volumes = vec![serde_json::json!("nginx-conf:/etc/nginx/conf.d:ro")];
+    nginx_volumes.extend(
+        referenced_dirs(app_config)
+            .iter()
+            .map(|dir| format!("{0}:{0}:ro", dir.display()).into()),
+    );
+    let compose = serde_json::json!({
+        "services": {
+            "nginx": {
+                "image": "nginx:alpine",
+                "ports": [format!("{0}:{0}", app_config.port)],
+                "volumes": nginx_volumes,
+                "labels": { "docker-rp.role": "nginx" },
+                "depends_on": {
+                    "rp": { "condition": "service_completed_successfully" },
+                },
+                "restart": "unless-stopped",
+            },
+            "rp": {
+                "image": "sifyfy/rp",
+                "entrypoint": ["generate-simple-reverse-proxy-conf-to-nginx"],
+                "command": [
+                    "--config-dir",
+                    "/conf",
+                    "--nginx-conf",
+                    format!("/etc/nginx/conf.d/{}", conf_name),
+                    "--mkdirs",
+                ],
+                "volumes": [
+                    format!("{}:/conf:ro", config_dir.display()),
+                    "nginx-conf:/etc/nginx/conf.d",
+                ],
+                "labels": { "docker-rp.role": "generator" },
+            },
+        },
+        "volumes": { "nginx-conf": {} },
+    });
+    serde_yaml::to_string(&compose).map_err(|err| Error::Render(err.to_string()))
+}
+
+/// `<nginx_conf>.map.json`
+pub fn source_map_path(nginx_conf: &Path) -> std::path::PathBuf {
+    let mut path = nginx_conf.as_os_str().to_owned();
+    path.push(".map.json");
+    path.into()
+}
+
+/// Where each server block and location of the nginx conf came from: a config file,
+/// `command line`, `default_backend` or `profiles.<name>`.
+pub fn render_source_map(app_config: &conf::AppConfig) -> serde_json::Value {
+    let location = |rp: &conf::ReverseProxyMapping| {
+        serde_json::json!({
+            "path": rp.path,
+            "url": rp.url.as_str(),
+            "source": rp.source,
+        })
+    };
+    let mut servers = Vec::new();
+    if app_config.renders_main_server() {
+        let mut locations: Vec<serde_json::Value> =
+            app_config.reverse_proxy.iter().map(location).collect();
+        locations.extend(app_config.statics.iter().map(
+            |st| serde_json::json!({ "path": st.path, "root": st.root, "source": "statics" }),
+        ));
+        locations.extend(app_config.responses.iter().map(|response| {
+            serde_json::json!({ "path": response.path, "status": response.status, "source": "responses" })
+        }));
+        servers.push(serde_json::json!({
+            "server_name": app_config.server_name(),
+            "locations": locations,
+        }));
+    }
+    for sd in &app_config.subdomains {
+        servers.push(serde_json::json!({
+            "server_name": sd.domain,
+            "source": sd.mapping.source,
+            "locations": [location(&sd.mapping)],
+        }));
+    }
+    serde_json::json!({
+        "sources": app_config.sources,
+        "servers": servers,
+    })
+}
+
+pub const REGION_BEGIN: &str = "# BEGIN docker-rp\n";
+pub const REGION_END: &str = "# END docker-rp\n";
+
+/// The content between the markers of the managed region of `conf`, if there is one.
+pub fn managed_region(conf: &str) -> Option<&str> {
+    let begin = find_line(conf, REGION_BEGIN)? + REGION_BEGIN.len();
+    let end = begin + find_line(&conf[begin..], REGION_END)?;
+    Some(&conf[begin..end])
+}
+
+/// `conf` with its managed region replaced by `region`, or with the region appended.
+pub fn replace_managed_region(conf: &str, region: &str) -> String {
+    match managed_region(conf) {
+        Some(current) => {
+            let begin = current.as_ptr() as usize - conf.as_ptr() as usize;
+            format!(
+                "{}{}{}",
+                &conf[..begin],
+                region,
+                &conf[begin + current.len()..]
+            )
+        }
+        None => {
+            let separator = if conf.is_empty() || conf.ends_with('\n') {
+                ""
+            } else {
+                "\n"
+            };
+            format!(
+                "{}{}{}{}{}",
+                conf, separator, REGION_BEGIN, region, REGION_END
+            )
+        }
+    }
+}
+
+/// The offset of `line`, a whole line ending with a newline, in `s`.
+fn find_line(s: &str, line: &str) -> Option<usize> {
+    s.match_indices(line)
+        .map(|(i, _)| i)
+        .find(|&i| i == 0 || s[..i].ends_with('\n'))
+}
+
+/// A comment block marking the file as generated, with `sha256` of the conf to tell edits.
+pub fn render_banner(app_config: &conf::AppConfig, sha256: &str, now: SystemTime) -> String {
+    let mut banner = format!(
+        "# Generated by {} {} at {}. Do not edit; it will be overwritten.\n",
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION"),
+        humantime::format_rfc3339_seconds(now)
+    );
+    if app_config.sources.is_empty() {
+        banner.push_str("# sources: command line\n");
+    } else {
+        banner.push_str("# sources:\n");
+        for source in &app_config.sources {
+            banner.push_str(&format!("#   {}\n", source.display()));
+        }
+    }
+    banner.push_str(&format!("# sha256: {}\n", sha256));
+    banner
+}
+
+/// The first line where `actual` differs from `expected`, eg. `line 3: -listen 80; +listen 8080;`.
+pub fn first_difference(expected: &str, actual: &str) -> Option<String> {
+    let mut expected_lines = expected.lines();
+    let mut actual_lines = actual.lines();
+    let mut number = 0;
+    loop {
+        number += 1;
+        match (expected_lines.next(), actual_lines.next()) {
+            (None, None) => {
+                return if expected == actual {
+                    None
+                } else {
+                    Some("the trailing newline differs".into())
+                };
+            }
+            (expected, actual) if expected == actual => {}
+            (expected, actual) => {
+                return Some(format!(
+                    "line {}: -{} +{}",
+                    number,
+                    expected.map(str::trim).unwrap_or("(end)"),
+                    actual.map(str::trim).unwrap_or("(end)")
+                ))
+            }
+        }
+    }
+}
+
+/// `conf` without the comment lines at the top written by `render_banner`.
+pub fn strip_banner(conf: &str) -> &str {
+    let mut rest = conf;
+    while rest.starts_with('#') {
+        rest = rest.find('\n').map(|i| &rest[i + 1..]).unwrap_or("");
+    }
+    rest
+}
+
+pub fn render_tls_hardening(hardening: &conf::TlsHardening) -> String {
+    let mut directives = format!(
+        r#"
+    ssl_protocols {};
+    ssl_ciphers {};
+    ssl_prefer_server_ciphers off;
+    ssl_session_cache {};
+    ssl_session_timeout {};
+    ssl_session_tickets off;
+"#,
+        hardening.protocols.join(" "),
+        hardening.ciphers,
+        hardening.session_cache,
+        hardening.session_timeout,
+    );
+    if hardening.ocsp_stapling {
+        directives.push_str("    ssl_stapling on;\n    ssl_stapling_verify on;\n");
+    }
+    if hardening.hsts_max_age > 0 {
+        directives.push_str(&format!(
+            "    add_header Strict-Transport-Security \"max-age={}{}\" always;\n",
+            hardening.hsts_max_age,
+            if hardening.hsts_include_subdomains {
+                "; includeSubDomains"
+            } else {
+                ""
+            }
+        ));
+    }
+    directives
+}
+
+pub const DEFAULT_PROXY_HEADERS: &[(&str, &str)] = &[
+    ("Host", "$host"),
+    ("X-Real-IP", "$remote_addr"),
+    ("X-Forwarded-Host", "$http_host"),
+    ("X-Forwarded-Server", "$host"),
+    ("X-Forwarded-For", "$proxy_add_x_forwarded_for"),
+];
+
+/// Added to the default headers by `tracing: true`, unless set there already.
+pub const TRACING_HEADERS: &[(&str, &str)] = &[
+    ("X-Request-ID", "$rp_request_id"),
+    ("traceparent", "$http_traceparent"),
+    ("tracestate", "$http_tracestate"),
+    ("b3", "$http_b3"),
+];
+
+/// Hidden by `hide_upstream_headers: true`. nginx hides `Server` of upstreams by itself.
+pub const IMPLEMENTATION_HEADERS: &[&str] = &[
+    "X-Powered-By",
+    "X-AspNet-Version",
+    "X-AspNetMvc-Version",
+    "X-Runtime",
+    "X-Generator",
+    "X-Drupal-Cache",
+    "X-Varnish",
+    "Via",
+];
+
+/// Quotes a directive argument unless it is a single plain word.
+fn quote(s: &str) -> String {
+    if !s.is_empty() && !s.contains(|c: char| c.is_whitespace() || "\"';{}\\".contains(c)) {
+        return s.to_owned();
+    }
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn regex_escape(s: &str) -> String {
+    s.chars().fold(String::new(), |mut buf, c| {
+        if "\\.+*?()|[]{}^$".contains(c) {
+            buf.push('\\');
+        }
+        buf.push(c);
+        buf
+    })
+}
+
+/// A directive with a block of `code`, indented to be a directive of a location.
+fn lua_block(directive: &str, code: &str) -> String {
+    let body = code
+        .trim_end()
+        .lines()
+        .map(|line| {
+            if line.is_empty() {
+                "\n".to_owned()
+            } else {
+                format!("            {}\n", line)
+            }
+        })
+        .collect::<String>();
+    format!("{} {{\n{}        }}", directive, body)
+}
+
+pub fn render_static_location(st: &conf::StaticMapping) -> String {
+    let mut directives = vec![format!("root {};", st.root.display())];
+    if st.gzip_static {
+        directives.push("gzip_static on;".into());
+    }
+    if st.brotli_static {
+        directives.push("brotli_static on;".into());
+    }
+    if st.spa {
+        directives.push(format!(
+            "try_files $uri $uri/ {}/index.html;",
+            st.path.trim_end_matches('/')
+        ));
+        for prefix in &st.spa_exclude {
+            directives.push(format!("location ^~ {} {{ try_files $uri =404; }}", prefix));
+        }
+    }
+    format!(
+        r#"
+    location {} {{
+{}
+    }}
+"#,
+        st.path,
+        directives
+            .iter()
+            .map(|directive| format!("        {}", directive))
+            .collect::<Vec<_>>()
+            .join("\n")
+    )
+}
+
+/// Regex locations, which nginx checks before the prefix ones of the mappings.
+pub fn render_deny_locations(deny: &conf::DenyPaths) -> String {
+    [
+        (deny.hidden, r"/\.(?!well-known)"),
+        (deny.git, r"/\.git(/|$)"),
+        (deny.env, r"/\.env"),
+        (deny.backups, r"(~|\.(bak|old|orig|save|swp))$"),
+    ]
+    .iter()
+    .filter(|(denied, _)| *denied)
+    .map(|(_, regex)| format!("\n    location ~ {} {{\n        deny all;\n    }}\n", regex))
+    .collect()
+}
+
+pub fn render_response_location(response: &conf::Response) -> String {
+    let mut directives = Vec::new();
+    if let Some(ref content_type) = response.content_type {
+        directives.push(format!("default_type {};", quote(content_type)));
+    }
+    for (name, value) in &response.headers {
+        directives.push(format!("add_header {} {} always;", name, quote(value)));
+    }
+    directives.push(match response.body {
+        Some(ref body) => format!("return {} {};", response.status, quote(body)),
+        None => format!("return {};", response.status),
+    });
+    format!(
+        r#"
+    location = {} {{
+{}
+    }}
+"#,
+        response.path,
+        directives
+            .iter()
+            .map(|directive| format!("        {}", directive))
+            .collect::<Vec<_>>()
+            .join("\n")
+    )
+}
+
+pub fn render_location(app_config: &conf::AppConfig, rp: &conf::ReverseProxyMapping) -> String {
+    let mut directives = Vec::new();
+    // proxy_set_header in a location drops the server level ones, so they are repeated
+    let mut headers: Vec<(&str, String)> = Vec::new();
+
+    if let Some(ref lua) = rp.content_by_lua {
+        directives.push(lua_block("content_by_lua_block", lua));
+    } else if let Some(ref function) = rp.js_content {
+        directives.push(format!("js_content {};", function));
+    } else if rp.canary.is_some() || !rp.routes.is_empty() {
+        // with a variable, proxy_pass sends the URI as it is, so the path is replaced by rewrite
+        if rp.strip_prefix {
+            directives.push(format!(
+                "rewrite ^{}/?(.*)$ {}/$1 break;",
+                regex_escape(rp.path.trim_end_matches('/')),
+                rp.url.path().trim_end_matches('/')
+            ));
+        } else if rp.path != rp.url.path() {
+            directives.push(format!(
+                "rewrite ^{}(.*)$ {}$1 break;",
+                regex_escape(&rp.path),
+                rp.url.path()
+            ));
+        }
+        let prefix = if rp.canary.is_some() {
+            "canary"
+        } else {
+            "route"
+        };
+        directives.push(format!(
+            "proxy_pass {}://${};",
+            rp.url.scheme(),
+            generated_name(prefix, rp)
+        ));
+    } else if rp.root.is_some() && !rp.strip_prefix {
+        // proxy_pass in a named location takes no URI, so the path is replaced by rewrite
+        let mut url = rp.url.clone();
+        url.set_path("");
+        if rp.pass_mode != Some(conf::PassMode::KeepPath) && rp.path != rp.url.path() {
+            directives.push(format!(
+                "rewrite ^{}(.*)$ {}$1 break;",
+                regex_escape(&rp.path),
+                rp.url.path()
+            ));
+        }
+        directives.push(format!(
+            "proxy_pass {};",
+            url.as_str().trim_end_matches('/')
+        ));
+    } else if rp.strip_prefix {
+        let mut url = rp.url.clone();
+        url.set_path("");
+        directives.push(format!(
+            "rewrite ^{}/?(.*)$ {}/$1 break;",
+            regex_escape(rp.path.trim_end_matches('/')),
+            rp.url.path().trim_end_matches('/')
+        ));
+        directives.push(format!(
+            "proxy_pass {};",
+            url.as_str().trim_end_matches('/')
+        ));
+    } else {
+        match rp.pass_mode {
+            Some(conf::PassMode::KeepPath) => {
+                let mut url = rp.url.clone();
+                url.set_path("");
+                directives.push(format!(
+                    "proxy_pass {};",
+                    url.as_str().trim_end_matches('/')
+                ));
+            }
+            Some(conf::PassMode::AppendSlash) if !rp.url.path().ends_with('/') => {
+                let mut url = rp.url.clone();
+                url.set_path(&format!("{}/", rp.url.path()));
+                directives.push(format!("proxy_pass {};", url));
+            }
+            _ => directives.push(format!("proxy_pass {};", rp.url)),
+        }
+    }
+    let path = match rp.pass_mode {
+        Some(conf::PassMode::AppendSlash) if !rp.strip_prefix && !rp.path.ends_with('/') => {
+            format!("{}/", rp.path)
+        }
+        _ => rp.path.clone(),
+    };
+    if rp.url.scheme() == "https" {
+        directives.push("proxy_ssl_server_name on;".into());
+    }
+    if let Some(ref certificate) = rp.proxy_ssl_certificate {
+        directives.push(format!("proxy_ssl_certificate {};", certificate.display()));
+    }
+    if let Some(ref certificate_key) = rp.proxy_ssl_certificate_key {
+        directives.push(format!(
+            "proxy_ssl_certificate_key {};",
+            certificate_key.display()
+        ));
+    }
+    if let Some(ref trusted_certificate) = rp.proxy_ssl_trusted_certificate {
+        directives.push(format!(
+            "proxy_ssl_trusted_certificate {};",
+            trusted_certificate.display()
+        ));
+    }
+    if let Some(verify) = rp.proxy_ssl_verify {
+        directives.push(format!(
+            "proxy_ssl_verify {};",
+            if verify { "on" } else { "off" }
+        ));
+    }
+    if !rp.proxy_ssl_protocols.is_empty() {
+        directives.push(format!(
+            "proxy_ssl_protocols {};",
+            rp.proxy_ssl_protocols.join(" ")
+        ));
+    }
+    if rp.sse || rp.websocket {
+        directives.push("proxy_http_version 1.1;".into());
+    }
+    if let Some(ref upstream_host) = rp.upstream_host {
+        headers.push(("Host", upstream_host.clone()));
+    }
+    for (name, value) in &rp.proxy_headers {
+        headers.push((name, quote(value)));
+    }
+    for (name, value) in &rp.add_headers {
+        directives.push(format!("add_header {} {} always;", name, quote(value)));
+    }
+    if rp.websocket {
+        headers.push(("Upgrade", "$http_upgrade".into()));
+        headers.push(("Connection", "\"upgrade\"".into()));
+    }
+    if rp.sse {
+        directives.push("proxy_buffering off;".into());
+        directives.push("proxy_cache off;".into());
+        directives.push("add_header X-Accel-Buffering no;".into());
+    }
+    match (rp.timeout, rp.sse) {
+        (Some(timeout), _) => {
+            directives.push(format!("proxy_read_timeout {}s;", timeout));
+            directives.push(format!("proxy_send_timeout {}s;", timeout));
+        }
+        (None, true) => directives.push("proxy_read_timeout 24h;".into()),
+        (None, false) => {}
+    }
+    if let Some(preset) = rp.preset {
+        for directive in preset.directives() {
+            let name = directive.split(' ').next().unwrap_or_default();
+            if !directives
+                .iter()
+                .any(|set| set.split(' ').next() == Some(name))
+            {
+                directives.push((*directive).to_owned());
+            }
+        }
+    }
+    if !rp.sub_filters.is_empty() {
+        // compressed responses can't be filtered
+        headers.push(("Accept-Encoding", "\"\"".into()));
+        for filter in &rp.sub_filters {
+            directives.push(format!(
+                "sub_filter {} {};",
+                quote(&filter.pattern),
+                quote(&filter.replacement)
+            ));
+        }
+        directives.push("sub_filter_once off;".into());
+        if !rp.sub_filter_types.is_empty() {
+            directives.push(format!(
+                "sub_filter_types {};",
+                rp.sub_filter_types.join(" ")
+            ));
+        }
+    }
+    if !rp.retry_on.is_empty() {
+        directives.push(format!("proxy_next_upstream {};", rp.retry_on.join(" ")));
+    }
+    if let Some(retries) = rp.retries {
+        directives.push(format!("proxy_next_upstream_tries {};", retries));
+    }
+    if let Some(retry_timeout) = rp.retry_timeout {
+        directives.push(format!("proxy_next_upstream_timeout {}s;", retry_timeout));
+    }
+    if !headers.is_empty() {
+        for (name, value) in &app_config.default_headers {
+            if !headers
+                .iter()
+                .any(|(own, _)| own.eq_ignore_ascii_case(name))
+            {
+                directives.push(format!("proxy_set_header {} {};", name, quote(value)));
+            }
+        }
+        for (name, value) in headers {
+            directives.push(format!("proxy_set_header {} {};", name, value));
+        }
+    }
+    if rp.require_client_certificate {
+        directives.push("if ($ssl_client_verify != SUCCESS) { return 403; }".into());
+    }
+    for rewrite in &rp.redirect_rewrites {
+        directives.push(format!(
+            "proxy_redirect {} {};",
+            quote(&rewrite.from),
+            quote(&rewrite.to)
+        ));
+    }
+    for (directive, rewrites) in &[
+        ("proxy_cookie_path", &rp.cookie_paths),
+        ("proxy_cookie_domain", &rp.cookie_domains),
+    ] {
+        for rewrite in rewrites.iter() {
+            directives.push(format!(
+                "{} {} {};",
+                directive,
+                quote(&rewrite.from),
+                quote(&rewrite.to)
+            ));
+        }
+    }
+    if !rp.access_log {
+        directives.push("access_log off;".into());
+    } else if let Some(ref format) = rp.log_format {
+        directives.extend(app_config.log.access_log_directive(Some(format)));
+    }
+    if rp.require_header.is_some() {
+        let name = generated_name("require_header", rp);
+        directives.push(format!("if (${} = 401) {{ return 401; }}", name));
+        directives.push(format!("if (${} = 403) {{ return 403; }}", name));
+    }
+    if let Some(ref basic_auth) = rp.basic_auth {
+        directives.push("auth_basic \"Restricted\";".into());
+        directives.push(format!("auth_basic_user_file {};", basic_auth.display()));
+    }
+    if !rp.allowed_methods.is_empty() {
+        let mut methods = rp.allowed_methods.clone();
+        if methods.iter().any(|method| method == "GET") && !methods.iter().any(|m| m == "HEAD") {
+            methods.push("HEAD".into());
+        }
+        directives.push(format!(
+            "if ($request_method !~ ^({})$) {{ return 405; }}",
+            methods.join("|")
+        ));
+    }
+    if let Some(ref lua) = rp.access_by_lua {
+        directives.push(lua_block("access_by_lua_block", lua));
+    }
+    if let Some(ref function) = rp.js_header_filter {
+        directives.push(format!("js_header_filter {};", function));
+    }
+    let mut mirror_location = String::new();
+    if let Some(ref mirror_url) = rp.mirror_url {
+        let name = generated_name("mirror", rp);
+        let upstream = defined_upstream(app_config, mirror_url).unwrap_or(&name);
+        directives.push(format!("mirror /_{};", name));
+        mirror_location = format!(
+            r#"
+    location = /_{} {{
+        internal;
+        proxy_pass {}://{}$request_uri;
+    }}
+"#,
+            name,
+            mirror_url.scheme(),
+            upstream
+        );
+    }
+
+    let directives = directives
+        .iter()
+        .map(|directive| format!("        {}", directive))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let source = rp
+        .source
+        .as_ref()
+        .map(|source| format!("    # {}\n", source))
+        .unwrap_or_default();
+    match rp.root {
+        Some(ref root) => {
+            let name = generated_name("@backend", rp);
+            format!(
+                r#"
+{}    location {} {{
+        root {};
+        try_files $uri {};
+    }}
+
+    location {} {{
+{}
+    }}
+{}"#,
+                source,
+                path,
+                root.display(),
+                name,
+                name,
+                directives,
+                mirror_location
+            )
+        }
+        None => format!(
+            r#"
+{}    location {} {{
+{}
+    }}
+{}"#,
+            source, path, directives, mirror_location
+        ),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{cert, error};
+
+    #[test]
+    fn render_nginx_conf_plain() {
+        let app_config = conf::AppConfig {
+            reverse_proxy: vec![
+                conf::ReverseProxyMapping::parse("/foo:http://localhost:3000/foo").unwrap(),
+            ],
+            ..Default::default()
+        };
+        let rendered = render_nginx_conf(&app_config);
+        assert!(rendered.contains("    listen 0.0.0.0:10080;\n    server_name localhost;\n\n"));
+        assert!(rendered.contains("        proxy_pass http://localhost:3000/foo;\n"));
+        assert!(!rendered.contains("ssl"));
+    }
+
+    #[test]
+    fn render_banner_and_strip() {
+        let mut rp = conf::ReverseProxyMapping::parse("/foo:http://localhost:3000/foo").unwrap();
+        rp.source = Some("conf/sites/foo.yaml".into());
+        let app_config = conf::AppConfig {
+            reverse_proxy: vec![rp],
+            sources: vec![PathBuf::from("conf/sites/foo.yaml")],
+            ..Default::default()
+        };
+        let conf = render_nginx_conf(&app_config);
+        assert!(conf.contains("\n    # conf/sites/foo.yaml\n    location /foo {\n"));
+
+        assert_eq!(conf_sha256(&conf), render_sha256(&app_config));
+        let banner = render_banner(
+            &app_config,
+            &conf_sha256(&conf),
+            SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_554_076_800),
+        );
+        assert!(banner.starts_with("# Generated by generate-simple-reverse-proxy-conf-to-nginx "));
+        assert!(banner.contains(" at 2019-04-01T00:00:00Z. "));
+        assert!(banner.contains("#   conf/sites/foo.yaml\n"));
+        assert!(banner.lines().all(|line| line.starts_with('#')));
+        assert_eq!(conf, strip_banner(&format!("{}{}", banner, conf)));
+        assert_eq!(conf, strip_banner(&conf));
+    }
+
+    #[test]
+    fn render_nginx_conf_tls() {
+        let app_config = conf::AppConfig {
+            tls: Some(conf::TlsConfig::new(cert::dev_cert_paths(
+                "app.localhost",
+                Path::new("/certs"),
+            ))),
+            ..Default::default()
+        };
+        let rendered = render_nginx_conf(&app_config);
+        assert!(rendered.contains("    listen 0.0.0.0:10080 ssl;\n"));
+        assert!(rendered.contains("    ssl_certificate /certs/app.localhost.crt;\n"));
+        assert!(rendered.contains("    ssl_certificate_key /certs/app.localhost.key;\n"));
+    }
+
+    #[test]
+    fn render_location_upstream_mtls() {
+        let mut rp = conf::ReverseProxyMapping::parse("/api:https://backend:8443/api").unwrap();
+        rp.proxy_ssl_certificate = Some(PathBuf::from("/certs/client.crt"));
+        rp.proxy_ssl_certificate_key = Some(PathBuf::from("/certs/client.key"));
+        rp.proxy_ssl_trusted_certificate = Some(PathBuf::from("/certs/ca.crt"));
+        rp.proxy_ssl_verify = Some(true);
+        assert_eq!(
+            r#"
+    location /api {
+        proxy_pass https://backend:8443/api;
+        proxy_ssl_server_name on;
+        proxy_ssl_certificate /certs/client.crt;
+        proxy_ssl_certificate_key /certs/client.key;
+        proxy_ssl_trusted_certificate /certs/ca.crt;
+        proxy_ssl_verify on;
+    }
+"#,
+            render_location(&conf::AppConfig::default(), &rp)
+        );
+    }
+
+    #[test]
+    fn render_location_https_upstream() {
+        let mut rp = conf::ReverseProxyMapping::parse("/api:https://api.example.com/").unwrap();
+        rp.proxy_ssl_protocols = vec!["TLSv1.2".into(), "TLSv1.3".into()];
+        let rendered = render_location(&conf::AppConfig::default(), &rp);
+        assert!(rendered.contains("        proxy_ssl_server_name on;\n"));
+        assert!(rendered.contains("        proxy_ssl_protocols TLSv1.2 TLSv1.3;\n"));
+
+        let rp = conf::ReverseProxyMapping::parse("/api:http://api:3000/").unwrap();
+        assert!(
+            !render_location(&conf::AppConfig::default(), &rp).contains("proxy_ssl_server_name")
+        );
+    }
+
+    #[test]
+    fn render_tls_hardening_defaults() {
+        let rendered = render_tls_hardening(&conf::TlsHardening::default());
+        assert!(rendered.contains("    ssl_protocols TLSv1.2 TLSv1.3;\n"));
+        assert!(rendered.contains("    ssl_ciphers ECDHE-ECDSA-AES128-GCM-SHA256:ECDHE-RSA-"));
+        assert!(rendered.contains("    ssl_stapling on;\n"));
+        assert!(rendered
+            .contains("    add_header Strict-Transport-Security \"max-age=63072000\" always;\n"));
+
+        let rendered = render_tls_hardening(&conf::TlsHardening {
+            ocsp_stapling: false,
+            hsts_max_age: 0,
+            ..Default::default()
+        });
+        assert!(!rendered.contains("ssl_stapling"));
+        assert!(!rendered.contains("Strict-Transport-Security"));
+    }
+
+    #[test]
+    fn render_location_quotes_header_values() {
+        let mut rp = conf::ReverseProxyMapping::parse("/api:http://api:3000/api").unwrap();
+        rp.proxy_headers
+            .insert("X-Foo".into(), "1; return 302 http://evil".into());
+        rp.add_headers.insert("X-Bar".into(), "a b".into());
+        rp.add_headers.insert("X-Baz".into(), "a\\".into());
+        rp.validate().unwrap();
+        let rendered = render_location(&conf::AppConfig::default(), &rp);
+        assert!(rendered.contains("        add_header X-Baz \"a\\\\\" always;\n"));
+        assert!(
+            rendered.contains("        proxy_set_header X-Foo \"1; return 302 http://evil\";\n")
+        );
+        assert!(rendered.contains("        add_header X-Bar \"a b\" always;\n"));
+        let app_config = conf::AppConfig {
+            default_headers: vec![("X-Env".into(), "a; return 302 http://evil".into())],
+            reverse_proxy: vec![rp.clone()],
+            ..Default::default()
+        };
+        let rendered = render_nginx_conf(&app_config);
+        assert!(rendered.contains("    proxy_set_header X-Env \"a; return 302 http://evil\";\n"));
+        assert!(
+            rendered.contains("        proxy_set_header X-Env \"a; return 302 http://evil\";\n")
+        );
+
+        rp.proxy_headers.insert(
+            "X-Foo 1; return 302 http://evil; proxy_set_header X-Baz".into(),
+            "1".into(),
+        );
+        assert!(rp.validate().is_err());
+        rp.proxy_headers.clear();
+        rp.add_headers.insert(
+            "X-A always; return 302 http://evil; add_header X-B".into(),
+            "1".into(),
+        );
+        assert!(rp.validate().is_err());
+    }
+
+    #[test]
+    fn render_location_sse() {
+        let mut rp = conf::ReverseProxyMapping::parse("/events:http://app:3000/events").unwrap();
+        rp.sse = true;
+        assert_eq!(
+            r#"
+    location /events {
+        proxy_pass http://app:3000/events;
+        proxy_http_version 1.1;
+        proxy_buffering off;
+        proxy_cache off;
+        add_header X-Accel-Buffering no;
+        proxy_read_timeout 24h;
+    }
+"#,
+            render_location(&conf::AppConfig::default(), &rp)
+        );
+    }
+
+    #[test]
+    fn render_location_preset() {
+        let rp = conf::ReverseProxyMapping::parse(
+            "/upload:http://app:3000/upload?preset=uploads&timeout=60",
+        )
+        .unwrap();
+        assert_eq!(
+            r#"
+    location /upload {
+        proxy_pass http://app:3000/upload;
+        proxy_read_timeout 60s;
+        proxy_send_timeout 60s;
+        client_max_body_size 1g;
+        client_body_timeout 300s;
+        proxy_request_buffering off;
+    }
+"#,
+            render_location(&conf::AppConfig::default(), &rp)
+        );
+
+        let mut rp =
+            conf::ReverseProxyMapping::parse("/events:http://app:3000/events?preset=long-poll")
+                .unwrap();
+        rp.sse = true;
+        let rendered = render_location(&conf::AppConfig::default(), &rp);
+        assert_eq!(1, rendered.matches("proxy_buffering off;").count());
+        assert!(rendered.contains("proxy_read_timeout 24h;"));
+        assert!(rendered.contains("proxy_send_timeout 1h;"));
+        assert!(conf::ReverseProxyMapping::parse("/:http://app:3000?preset=bulk").is_err());
+    }
+
+    #[test]
+    fn render_location_mapping_options() {
+        let rp = conf::ReverseProxyMapping::parse(
+            "/ws:http://app:3000/socket?websocket&strip_prefix&timeout=300",
+        )
+        .unwrap();
+        assert_eq!(
+            r#"
+    location /ws {
+        rewrite ^/ws/?(.*)$ /socket/$1 break;
+        proxy_pass http://app:3000;
+        proxy_http_version 1.1;
+        proxy_read_timeout 300s;
+        proxy_send_timeout 300s;
+        proxy_set_header Host $host;
+        proxy_set_header X-Real-IP $remote_addr;
+        proxy_set_header X-Forwarded-Host $http_host;
+        proxy_set_header X-Forwarded-Server $host;
+        proxy_set_header X-Forwarded-For $proxy_add_x_forwarded_for;
+        proxy_set_header Upgrade $http_upgrade;
+        proxy_set_header Connection "upgrade";
+    }
+"#,
+            render_location(&conf::AppConfig::default(), &rp)
+        );
+    }
+
+    #[test]
+    fn render_default_headers_overrides() {
+        let mut overrides = std::collections::HashMap::new();
+        overrides.insert("host".to_string(), "$proxy_host".to_string());
+        overrides.insert("x-real-ip".to_string(), "".to_string());
+        overrides.insert("x-forwarded-proto".to_string(), "$scheme".to_string());
+        let app_config = conf::AppConfig {
+            default_headers: conf::DefaultHeaders::Overrides(overrides).resolve(),
+            reverse_proxy: vec![
+                conf::ReverseProxyMapping::parse("/ws:http://app:3000?websocket").unwrap(),
+            ],
+            ..Default::default()
+        };
+        let rendered = render_nginx_conf(&app_config);
+        assert!(rendered.contains("    proxy_set_header Host $proxy_host;\n"));
+        assert!(rendered.contains("        proxy_set_header Host $proxy_host;\n"));
+        assert!(rendered.contains("    proxy_set_header x-forwarded-proto $scheme;\n"));
+        assert!(!rendered.contains("X-Real-IP"));
+
+        let app_config = conf::AppConfig {
+            default_headers: conf::DefaultHeaders::Switch(false).resolve(),
+            ..Default::default()
+        };
+        assert!(!render_nginx_conf(&app_config).contains("proxy_set_header"));
+    }
+
+    #[test]
+    fn render_location_upstream_host() {
+        let rp = conf::ReverseProxyMapping::parse(
+            "/api:https://api.example.com/?upstream_host=api.example.com",
+        )
+        .unwrap();
+        let rendered = render_location(&conf::AppConfig::default(), &rp);
+        assert!(rendered.contains("        proxy_set_header Host api.example.com;\n"));
+        assert!(!rendered.contains("proxy_set_header Host $host;"));
+        assert!(rendered.contains("        proxy_set_header X-Real-IP $remote_addr;\n"));
+    }
+
+    #[test]
+    fn render_location_pass_mode() {
+        let app_config = conf::AppConfig::default();
+        let rp = conf::ReverseProxyMapping::parse("/api:http://app:3000/v1?pass_mode=keep-path")
+            .unwrap();
+        let rendered = render_location(&app_config, &rp);
+        assert!(rendered.contains("    location /api {\n        proxy_pass http://app:3000;\n"));
+
+        let rp = conf::ReverseProxyMapping::parse("/api:http://app:3000/v1?pass_mode=append-slash")
+            .unwrap();
+        let rendered = render_location(&app_config, &rp);
+        assert!(
+            rendered.contains("    location /api/ {\n        proxy_pass http://app:3000/v1/;\n")
+        );
+
+        let rp = conf::ReverseProxyMapping::parse("/api/:http://app:3000/?pass_mode=append-slash")
+            .unwrap();
+        let rendered = render_location(&app_config, &rp);
+        assert!(rendered.contains("    location /api/ {\n        proxy_pass http://app:3000/;\n"));
+
+        assert!(conf::ReverseProxyMapping::parse("/api:http://app:3000/?pass_mode=merge").is_err());
+    }
+
+    #[test]
+    fn render_nginx_conf_subdomains() {
+        use structopt::StructOpt;
+        let cli_args: &[&str] = &["test", "--config-dir", "./tests/conf_subdomains_dir"];
+        let args = conf::Args::from_iter_safe(cli_args.iter()).unwrap();
+        let app_config = conf::AppConfig::from_args_and_config(args).unwrap();
+        let rendered = render_nginx_conf(&app_config);
+        assert_eq!(2, rendered.matches("server {").count());
+        assert!(rendered.contains(
+            "    server_name api.example.com;\n\n    ssl_certificate /certs/api.example.com/fullchain.pem;\n"
+        ));
+        assert!(rendered.contains("    location / {\n        proxy_pass http://api:3000/;\n"));
+        assert!(rendered.contains("    server_name *.example.com;\n"));
+        assert!(rendered.contains("        proxy_set_header Upgrade $http_upgrade;\n"));
+
+        let cli_args: &[&str] = &[
+            "test",
+            "--config-dir",
+            "./tests/conf_subdomains_dir",
+            "-r",
+            "/:http://app:3000/",
+            "--subdomain",
+            "admin.example.com->http://admin:3000/",
+        ];
+        let args = conf::Args::from_iter_safe(cli_args.iter()).unwrap();
+        let app_config = conf::AppConfig::from_args_and_config(args).unwrap();
+        let rendered = render_nginx_conf(&app_config);
+        assert_eq!(4, rendered.matches("server {").count());
+        assert!(rendered.contains("    server_name admin.example.com;\n"));
+    }
+
+    #[test]
+    fn render_location_retries() {
+        let rp = conf::ReverseProxyMapping::parse(
+            "/api:http://api:3000/?retries=3&retry_on=error,timeout,http_502&retry_timeout=10",
+        )
+        .unwrap();
+        rp.validate().unwrap();
+        let rendered = render_location(&conf::AppConfig::default(), &rp);
+        assert!(rendered.contains(
+            "        proxy_next_upstream error timeout http_502;\n        proxy_next_upstream_tries 3;\n        proxy_next_upstream_timeout 10s;\n"
+        ));
+
+        let rp =
+            conf::ReverseProxyMapping::parse("/api:http://api:3000/?retry_on=http_418").unwrap();
+        assert!(matches!(rp.validate(), Err(error::Error::Parse { .. })));
+    }
+
+    #[test]
+    fn render_nginx_conf_weighted_upstream() {
+        use structopt::StructOpt;
+        let cli_args: &[&str] = &["test", "--config-dir", "./tests/conf_upstreams_dir"];
+        let args = conf::Args::from_iter_safe(cli_args.iter()).unwrap();
+        let app_config = conf::AppConfig::from_args_and_config(args).unwrap();
+        let rendered = render_nginx_conf(&app_config);
+        assert!(rendered.starts_with(
+            "\nupstream app {\n    server app-v1:3000 weight=9;\n    server app-v2:3000;\n}\n"
+        ));
+        assert!(rendered.contains("        proxy_pass http://app/;\n"));
+
+        let app_config = conf::AppConfig {
+            upstreams: vec![conf::Upstream {
+                name: "app".into(),
+                servers: vec![conf::UpstreamServer {
+                    address: "app:3000".into(),
+                    weight: Some(0),
+                }],
+                sticky: None,
+                sticky_cookie: None,
+            }],
+            ..Default::default()
+        };
+        assert!(app_config.validate().is_err());
+    }
+
+    #[test]
+    fn render_upstream_sticky() {
+        let mut upstream = conf::Upstream {
+            name: "app".into(),
+            servers: vec![conf::UpstreamServer {
+                address: "app:3000".into(),
+                weight: None,
+            }],
+            sticky: Some(conf::Sticky::IpHash),
+            sticky_cookie: None,
+        };
+        let mut app_config = conf::AppConfig::default();
+        assert_eq!(
+            "\nupstream app {\n    ip_hash;\n    server app:3000;\n}\n",
+            render_upstream(&app_config, &upstream)
+        );
+
+        upstream.sticky = Some(conf::Sticky::Cookie);
+        app_config.upstreams = vec![upstream.clone()];
+        assert!(app_config.validate().is_err());
+        app_config.nginx_plus = true;
+        app_config.validate().unwrap();
+        assert!(render_upstream(&app_config, &upstream).contains("    sticky cookie rp_app;\n"));
+
+        upstream.sticky_cookie = Some("JSESSIONID".into());
+        app_config.nginx_plus = false;
+        assert!(render_upstream(&app_config, &upstream)
+            .contains("    hash $cookie_JSESSIONID consistent;\n"));
+    }
+
+    #[test]
+    fn render_nginx_conf_canary() {
+        let rp = conf::ReverseProxyMapping::parse(
+            "/api:http://api:3000/v1?canary_url=http://api-canary:3000/v1&canary_percentage=10",
+        )
+        .unwrap();
+        rp.validate().unwrap();
+        let name = generated_name("canary", &rp);
+        assert!(name.starts_with("canary_api_"));
+        let app_config = conf::AppConfig {
+            reverse_proxy: vec![rp],
+            ..Default::default()
+        };
+        let rendered = render_nginx_conf(&app_config);
+        assert!(rendered.contains(&format!(
+            "\nupstream {}_stable {{\n    server api:3000;\n}}\n",
+            name
+        )));
+        assert!(rendered.contains(&format!(
+            "\nupstream {}_canary {{\n    server api-canary:3000;\n}}\n",
+            name
+        )));
+        assert!(rendered.contains(&format!(
+            "split_clients \"${{remote_addr}}${{http_user_agent}}\" ${} {{\n    10% {}_canary;\n    * {}_stable;\n}}\n",
+            name, name, name
+        )));
+        assert!(rendered.contains(&format!(
+            "        rewrite ^/api(.*)$ /v1$1 break;\n        proxy_pass http://${};\n",
+            name
+        )));
+
+        let rp = conf::ReverseProxyMapping::parse(
+            "/api:http://api:3000/v1?canary_url=http://api-canary:3000/v2&canary_percentage=10",
+        )
+        .unwrap();
+        assert!(rp.validate().is_err());
+        for option in &["", "&canary_percentage=0", "&canary_percentage=101"] {
+            let rp = conf::ReverseProxyMapping::parse(&format!(
+                "/api:http://api:3000/v1?canary_url=http://api-canary:3000/v1{}",
+                option
+            ))
+            .unwrap();
+            assert!(rp.validate().is_err());
+        }
+    }
+
+    #[test]
+    fn render_nginx_conf_routes() {
+        let mut rp = conf::ReverseProxyMapping::parse("/api:http://api:3000/v1").unwrap();
+        rp.routes = vec![
+            conf::ReverseProxyMapping::parse(
+                "/api:http://api-acme:3000/v1?match_header=X-Tenant:acme",
+            )
+            .unwrap(),
+            conf::ReverseProxyMapping::parse(
+                "/api:http://api-beta:3000/v1?match_cookie=channel=~^beta",
+            )
+            .unwrap(),
+        ];
+        rp.validate().unwrap();
+        let name = generated_name("route", &rp);
+        let app_config = conf::AppConfig {
+            reverse_proxy: vec![rp],
+            ..Default::default()
+        };
+        let rendered = render_nginx_conf(&app_config);
+        assert!(rendered.contains(&format!(
+            "\nupstream {}_default {{\n    server api:3000;\n}}\n",
+            name
+        )));
+        assert!(rendered.contains(&format!(
+            "\nmap $http_x_tenant ${} {{\n    \"acme\" {}_0;\n    default ${}_next1;\n}}\n",
+            name, name, name
+        )));
+        assert!(rendered.contains(&format!(
+            "\nmap $cookie_channel ${}_next1 {{\n    ~^beta {}_1;\n    default {}_default;\n}}\n",
+            name, name, name
+        )));
+        assert!(rendered.contains(&format!(
+            "        rewrite ^/api(.*)$ /v1$1 break;\n        proxy_pass http://${};\n",
+            name
+        )));
+        assert_eq!(3, routing_table(&app_config).len());
+    }
+
+    #[test]
+    fn render_nginx_conf_mirror() {
+        let rp = conf::ReverseProxyMapping::parse(
+            "/api:http://api:3000/api?mirror_url=http://shadow:3000",
+        )
+        .unwrap();
+        rp.validate().unwrap();
+        let name = generated_name("mirror", &rp);
+        let app_config = conf::AppConfig {
+            reverse_proxy: vec![rp],
+            ..Default::default()
+        };
+        let rendered = render_nginx_conf(&app_config);
+        assert!(rendered.contains(&format!(
+            "\nupstream {} {{\n    server shadow:3000;\n}}\n",
+            name
+        )));
+        assert!(rendered.contains(&format!("        mirror /_{};\n", name)));
+        assert!(rendered.contains(&format!(
+            "    location = /_{} {{\n        internal;\n        proxy_pass http://{}$request_uri;\n    }}\n",
+            name, name
+        )));
+
+        let rp = conf::ReverseProxyMapping::parse(
+            "/api:http://api:3000/api?mirror_url=http://shadow:3000/api",
+        )
+        .unwrap();
+        assert!(rp.validate().is_err());
+    }
+
+    #[test]
+    fn render_nginx_conf_maps() {
+        use structopt::StructOpt;
+        let cli_args: &[&str] = &["test", "--config-dir", "./tests/conf_maps_dir"];
+        let args = conf::Args::from_iter_safe(cli_args.iter()).unwrap();
+        let app_config = conf::AppConfig::from_args_and_config(args).unwrap();
+        let rendered = render_nginx_conf(&app_config);
+        assert!(rendered.contains(
+            "\nmap $http_origin $cors_origin {\n    default \"\";\n    \"~^https://(www\\\\.)?example\\\\.com$\" $http_origin;\n}\n"
+        ));
+        assert!(rendered
+            .contains("\nmap $cookie_beta $backend {\n    default app;\n    1 app-beta;\n}\n"));
+        assert!(rendered.contains("        proxy_pass http://$backend/;\n"));
+        assert!(rendered
+            .contains("        add_header access-control-allow-origin $cors_origin always;\n"));
+        assert!(rendered.contains("        proxy_set_header x-origin $cors_origin;\n"));
+    }
+
+    #[test]
+    fn render_nginx_conf_tracing() {
+        use structopt::StructOpt;
+        let cli_args: &[&str] = &["test", "--config-dir", "./tests/conf_tracing_dir"];
+        let args = conf::Args::from_iter_safe(cli_args.iter()).unwrap();
+        let app_config = conf::AppConfig::from_args_and_config(args).unwrap();
+        let rendered = render_nginx_conf(&app_config);
+        assert!(rendered.starts_with(
+            "\nmap $http_x_request_id $rp_request_id {\n    \"\" $request_id;\n    default $http_x_request_id;\n}\n"
+        ));
+        assert!(
+            rendered.contains("    underscores_in_headers on;\n    proxy_set_header Host $host;\n")
+        );
+        assert!(rendered.contains("    proxy_set_header X-Request-ID $rp_request_id;\n"));
+        // repeated in the location setting its own headers
+        assert!(rendered.contains("        proxy_set_header traceparent $http_traceparent;\n"));
+        assert!(rendered.contains("        proxy_set_header b3 $http_b3;\n"));
+    }
+
+    #[test]
+    fn render_location_lua() {
+        let mut rp = conf::ReverseProxyMapping::parse("/hello:http://app:3000/").unwrap();
+        rp.access_by_lua =
+            Some("if ngx.var.arg_token ~= \"secret\" then\n    ngx.exit(403)\nend\n".into());
+        rp.content_by_lua = Some("ngx.say(\"hello\")".into());
+        let mut app_config = conf::AppConfig {
+            reverse_proxy: vec![rp.clone()],
+            ..conf::AppConfig::default()
+        };
+        assert!(app_config.validate().is_err());
+        app_config.openresty = true;
+        app_config.validate().unwrap();
+        let rendered = render_location(&app_config, &rp);
+        assert!(rendered.contains(
+            "        content_by_lua_block {\n            ngx.say(\"hello\")\n        }\n"
+        ));
+        assert!(rendered.contains(
+            "        access_by_lua_block {\n            if ngx.var.arg_token ~= \"secret\" then\n                ngx.exit(403)\n            end\n        }\n"
+        ));
+        assert!(!rendered.contains("proxy_pass"));
+    }
+
+    #[test]
+    fn render_nginx_conf_njs() {
+        let mut rp = conf::ReverseProxyMapping::parse("/hello:http://app:3000/").unwrap();
+        rp.js_content = Some("main.hello".into());
+        rp.js_header_filter = Some("headers.strip".into());
+        let mut app_config = conf::AppConfig {
+            reverse_proxy: vec![rp],
+            js_import: vec!["main from njs/main.js".into()],
+            ..conf::AppConfig::default()
+        };
+        assert!(app_config.validate().is_err());
+        app_config.js_import.push("njs/headers.js".into());
+        app_config.validate().unwrap();
+        let rendered = render_nginx_conf(&app_config);
+        assert!(
+            rendered.starts_with("js_import main from njs/main.js;\njs_import njs/headers.js;\n")
+        );
+        assert!(rendered.contains("        js_content main.hello;\n"));
+        assert!(rendered.contains("        js_header_filter headers.strip;\n"));
+        assert!(!rendered.contains("proxy_pass"));
+    }
+
+    #[test]
+    fn render_location_basic_auth() {
+        let rp = conf::ReverseProxyMapping::parse(
+            "/admin:http://admin:3000/?basic_auth=/etc/nginx/htpasswd",
+        )
+        .unwrap();
+        let rendered = render_location(&conf::AppConfig::default(), &rp);
+        assert!(rendered.contains(
+            "        auth_basic \"Restricted\";\n        auth_basic_user_file /etc/nginx/htpasswd;\n"
+        ));
+    }
+
+    #[test]
+    fn render_nginx_conf_require_header() {
+        let rp = conf::ReverseProxyMapping::parse(
+            "/hook:http://hook:3000/hook?require_header=X-Webhook-Token&require_header_value=~s3cret",
+        )
+        .unwrap();
+        rp.validate().unwrap();
+        let name = generated_name("require_header", &rp);
+        let app_config = conf::AppConfig {
+            reverse_proxy: vec![rp],
+            ..Default::default()
+        };
+        let rendered = render_nginx_conf(&app_config);
+        assert!(rendered.contains(&format!(
+            "\nmap $http_x_webhook_token ${} {{\n    \"\" 401;\n    \"\\\\~s3cret\" 0;\n    default 403;\n}}\n",
+            name
+        )));
+        assert!(rendered.contains(&format!(
+            "        if (${} = 401) {{ return 401; }}\n        if (${} = 403) {{ return 403; }}\n",
+            name, name
+        )));
+    }
+
+    #[test]
+    fn render_server_hides_headers() {
+        let app_config = conf::AppConfig {
+            hide_upstream_headers: conf::HideHeaders::Preset(true).resolve(),
+            server_tokens: false,
+            ..conf::AppConfig::default()
+        };
+        let rendered = render_server(&app_config, None, &[], true);
+        assert!(rendered.contains(
+            "    server_tokens off;\n    proxy_hide_header X-Powered-By;\n    proxy_hide_header X-AspNet-Version;\n"
+        ));
+        assert!(!rendered.contains("more_clear_headers"));
+
+        let app_config = conf::AppConfig {
+            hide_upstream_headers: conf::HideHeaders::Names(vec!["X-Backend".into()]).resolve(),
+            server_tokens: false,
+            openresty: true,
+            ..conf::AppConfig::default()
+        };
+        let rendered = render_server(&app_config, None, &[], true);
+        assert!(rendered.contains(
+            "    server_tokens off;\n    more_clear_headers Server;\n    proxy_hide_header X-Backend;\n"
+        ));
+    }
+
+    #[test]
+    fn render_location_sub_filters() {
+        let mut rp = conf::ReverseProxyMapping::parse(
+            "/app:http://app:3000?sub_filter_types=application/javascript",
+        )
+        .unwrap();
+        rp.sub_filters = vec![conf::SubFilter {
+            pattern: "href=\"/".into(),
+            replacement: "href=\"/app/".into(),
+        }];
+        rp.validate().unwrap();
+        let rendered = render_location(&conf::AppConfig::default(), &rp);
+        assert!(rendered.contains(
+            "        sub_filter \"href=\\\"/\" \"href=\\\"/app/\";\n        sub_filter_once off;\n        sub_filter_types application/javascript;\n"
+        ));
+        assert!(rendered.contains("        proxy_set_header Accept-Encoding \"\";\n"));
+        assert!(rendered.contains("        proxy_set_header Host $host;\n"));
+    }
+
+    #[test]
+    fn render_location_redirect_rewrites() {
+        let mut rp = conf::ReverseProxyMapping::parse("/app:http://app:3000/").unwrap();
+        rp.redirect_rewrites = vec![
+            conf::Rewrite {
+                from: "http://app:3000/".into(),
+                to: "/app/".into(),
+            },
+            conf::Rewrite {
+                from: "~^https?://[^/]+/(.*)$".into(),
+                to: "/app/$1".into(),
+            },
+        ];
+        let rendered = render_location(&conf::AppConfig::default(), &rp);
+        assert!(rendered.contains(
+            "        proxy_redirect http://app:3000/ /app/;\n        proxy_redirect ~^https?://[^/]+/(.*)$ /app/$1;\n"
+        ));
+    }
+
+    #[test]
+    fn render_location_cookie_rewrites() {
+        let mut rp = conf::ReverseProxyMapping::parse("/app:http://app:3000/").unwrap();
+        rp.cookie_paths = vec![conf::Rewrite {
+            from: "/".into(),
+            to: "/app/".into(),
+        }];
+        rp.cookie_domains = vec![conf::Rewrite {
+            from: "app.internal".into(),
+            to: "$host".into(),
+        }];
+        let rendered = render_location(&conf::AppConfig::default(), &rp);
+        assert!(rendered.contains(
+            "        proxy_cookie_path / /app/;\n        proxy_cookie_domain app.internal $host;\n"
+        ));
+    }
+
+    #[test]
+    fn render_nginx_conf_statics() {
+        let app_config = conf::AppConfig {
+            statics: vec![conf::StaticMapping {
+                path: "/assets".into(),
+                root: PathBuf::from("/srv/www"),
+                gzip_static: true,
+                brotli_static: true,
+                spa: false,
+                spa_exclude: Vec::new(),
+            }],
+            ..conf::AppConfig::default()
+        };
+        app_config.validate().unwrap();
+        let rendered = render_nginx_conf(&app_config);
+        assert!(rendered.contains(
+            "    location /assets {\n        root /srv/www;\n        gzip_static on;\n        brotli_static on;\n    }\n"
+        ));
+    }
+
+    #[test]
+    fn render_static_location_spa() {
+        let mut st = conf::StaticMapping {
+            path: "/app/".into(),
+            root: PathBuf::from("/srv/www"),
+            gzip_static: false,
+            brotli_static: false,
+            spa: true,
+            spa_exclude: vec!["/app/api".into()],
+        };
+        assert_eq!(
+            "\n    location /app/ {\n        root /srv/www;\n        try_files $uri $uri/ /app/index.html;\n        location ^~ /app/api { try_files $uri =404; }\n    }\n",
+            render_static_location(&st)
+        );
+        st.spa_exclude = vec!["/api".into()];
+        let app_config = conf::AppConfig {
+            statics: vec![st],
+            ..conf::AppConfig::default()
+        };
+        assert!(app_config.validate().is_err());
+    }
+
+    #[test]
+    fn validate_static_path_and_root() {
+        let st = conf::StaticMapping {
+            path: "/s".into(),
+            root: PathBuf::from("/srv/www"),
+            gzip_static: false,
+            brotli_static: false,
+            spa: false,
+            spa_exclude: Vec::new(),
+        };
+        let validate = |st: conf::StaticMapping| {
+            conf::AppConfig {
+                statics: vec![st],
+                ..conf::AppConfig::default()
+            }
+            .validate()
+        };
+        validate(st.clone()).unwrap();
+        assert!(validate(conf::StaticMapping {
+            path: "/s; include /etc/passwd;".into(),
+            ..st.clone()
+        })
+        .is_err());
+        assert!(validate(conf::StaticMapping {
+            root: PathBuf::from("/srv/www; include /etc/passwd"),
+            ..st.clone()
+        })
+        .is_err());
+        assert!(validate(conf::StaticMapping {
+            spa: true,
+            spa_exclude: vec!["/s/api { } location /evil".into()],
+            ..st
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn render_location_root_with_backend_fallback() {
+        let rp =
+            conf::ReverseProxyMapping::parse("/app:http://app:8000/v1?root=/srv/static").unwrap();
+        let name = generated_name("@backend", &rp);
+        assert_eq!(
+            format!(
+                r#"
+    location /app {{
+        root /srv/static;
+        try_files $uri {};
+    }}
+
+    location {} {{
+        rewrite ^/app(.*)$ /v1$1 break;
+        proxy_pass http://app:8000;
+    }}
+"#,
+                name, name
+            ),
+            render_location(&conf::AppConfig::default(), &rp)
+        );
+        assert!(name.starts_with("@backend_app_"));
+
+        let mut rp = rp;
+        rp.basic_auth = Some(PathBuf::from("/etc/nginx/htpasswd"));
+        assert!(rp.validate().is_err());
+    }
+
+    #[test]
+    fn render_nginx_conf_health_checks() {
+        let app_config = conf::AppConfig {
+            reverse_proxy: vec![conf::ReverseProxyMapping::parse(
+                "/ping:http://app:3000/ping?access_log=off",
+            )
+            .unwrap()],
+            healthz: Some("/healthz".into()),
+            ..conf::AppConfig::default()
+        };
+        app_config.validate().unwrap();
+        let rendered = render_nginx_conf(&app_config);
+        assert!(rendered
+            .contains("        proxy_pass http://app:3000/ping;\n        access_log off;\n"));
+        assert!(rendered.contains(
+            "    location = /healthz {\n        access_log off;\n        default_type text/plain;\n        return 200 \"ok\\n\";\n    }\n"
+        ));
+
+        let app_config = conf::AppConfig {
+            healthz: Some("/hz { return 200; } location /evil".into()),
+            ..app_config
+        };
+        assert!(app_config.validate().is_err());
+    }
+
+    #[test]
+    fn render_response_location_robots() {
+        let response = conf::Response {
+            path: "/robots.txt".into(),
+            status: 200,
+            content_type: Some("text/plain".into()),
+            body: Some("User-agent: *\nDisallow: /".into()),
+            headers: vec![("Cache-Control".to_string(), "max-age=86400".to_string())]
+                .into_iter()
+                .collect(),
+        };
+        assert_eq!(
+            "\n    location = /robots.txt {\n        default_type text/plain;\n        add_header Cache-Control max-age=86400 always;\n        return 200 \"User-agent: *\nDisallow: /\";\n    }\n",
+            render_response_location(&response)
+        );
+        let app_config = conf::AppConfig {
+            responses: vec![conf::Response {
+                status: 1000,
+                ..response
+            }],
+            ..conf::AppConfig::default()
+        };
+        assert!(app_config.validate().is_err());
+
+        let injected = |response: conf::Response| {
+            conf::AppConfig {
+                responses: vec![response],
+                ..conf::AppConfig::default()
+            }
+            .validate()
+            .is_err()
+        };
+        let response = conf::Response {
+            path: "/ok".into(),
+            status: 200,
+            content_type: None,
+            body: None,
+            headers: Default::default(),
+        };
+        assert!(injected(conf::Response {
+            path: "/ok { return 302 http://evil; } location /x".into(),
+            ..response.clone()
+        }));
+        assert!(injected(conf::Response {
+            headers: vec![(
+                "X-A always; return 302 http://evil; add_header X-B".to_string(),
+                "1".to_string()
+            )]
+            .into_iter()
+            .collect(),
+            ..response
+        }));
+    }
+
+    #[test]
+    fn validate_rejects_injected_values() {
+        let injected = |app_config: conf::AppConfig| app_config.validate().is_err();
+        assert!(injected(conf::AppConfig {
+            real_ip_from: vec!["10.0.0.0/8; return 302 http://evil".into()],
+            ..conf::AppConfig::default()
+        }));
+        assert!(injected(conf::AppConfig {
+            default_headers: vec![(
+                "X-A $host; return 302 http://evil; proxy_set_header X-B".into(),
+                "1".into()
+            )],
+            ..conf::AppConfig::default()
+        }));
+        for option in ["root=/srv/www", "basic_auth=/etc/htpasswd"] {
+            conf::ReverseProxyMapping::parse(&format!("/app:http://app:3000?{}", option))
+                .unwrap()
+                .validate()
+                .unwrap();
+        }
+        let rp =
+            conf::ReverseProxyMapping::parse("/app:http://app:3000?root=/srv/www;include").unwrap();
+        assert!(rp.validate().is_err());
+        let rp = conf::ReverseProxyMapping::parse(
+            "/app:http://app:3000?basic_auth=/etc/htpasswd;include",
+        )
+        .unwrap();
+        assert!(rp.validate().is_err());
+
+        assert!(injected(conf::AppConfig {
+            default_domain: "x; include /etc/passwd".into(),
+            ..conf::AppConfig::default()
+        }));
+        assert!(injected(conf::AppConfig {
+            host: "0.0.0.0:80; include /etc/passwd; listen 127.0.0.1".into(),
+            ..conf::AppConfig::default()
+        }));
+        assert!(injected(conf::AppConfig {
+            listen: conf::ListenOptions {
+                so_keepalive: Some("on; include /etc/passwd".into()),
+                ..Default::default()
+            },
+            ..conf::AppConfig::default()
+        }));
+        assert!(injected(conf::AppConfig {
+            hide_upstream_headers: vec!["X-A; include /etc/passwd".into()],
+            ..conf::AppConfig::default()
+        }));
+        let map = conf::Map {
+            source: "$http_origin".into(),
+            variable: "$cors_origin".into(),
+            default: None,
+            values: Vec::new(),
+        };
+        assert!(injected(conf::AppConfig {
+            maps: vec![conf::Map {
+                source: "$a { } include /etc/passwd; map $b".into(),
+                ..map.clone()
+            }],
+            ..conf::AppConfig::default()
+        }));
+        assert!(injected(conf::AppConfig {
+            maps: vec![conf::Map {
+                variable: "$v { } include /etc/passwd; map $b $c".into(),
+                ..map
+            }],
+            ..conf::AppConfig::default()
+        }));
+        assert!(injected(conf::AppConfig {
+            upstreams: vec![conf::Upstream {
+                sticky: Some(conf::Sticky::Cookie),
+                sticky_cookie: Some("id consistent; include /etc/passwd".into()),
+                name: "app".into(),
+                servers: vec![conf::UpstreamServer {
+                    address: "app:3000".into(),
+                    weight: None,
+                }],
+            }],
+            ..conf::AppConfig::default()
+        }));
+        conf::AppConfig {
+            js_import: vec!["main from njs/main.js".into(), "njs/util.js".into()],
+            ..conf::AppConfig::default()
+        }
+        .validate()
+        .unwrap();
+        assert!(injected(conf::AppConfig {
+            js_import: vec!["main from njs/main.js; include /etc/passwd".into()],
+            ..conf::AppConfig::default()
+        }));
+        let tls = conf::TlsConfig::new(conf::CertificatePair {
+            certificate: PathBuf::from("/certs/a.crt"),
+            certificate_key: PathBuf::from("/certs/a.key"),
+        });
+        assert!(injected(conf::AppConfig {
+            tls: Some(conf::TlsConfig {
+                certificate: Some(PathBuf::from("/certs/a.crt; include /etc/passwd")),
+                ..tls.clone()
+            }),
+            ..conf::AppConfig::default()
+        }));
+        assert!(injected(conf::AppConfig {
+            tls: Some(conf::TlsConfig {
+                client_certificate: Some(PathBuf::from("/certs/ca.crt; include /etc/passwd")),
+                ..tls.clone()
+            }),
+            ..conf::AppConfig::default()
+        }));
+        assert!(injected(conf::AppConfig {
+            tls: Some(conf::TlsConfig {
+                hardening: Some(conf::TlsHardening {
+                    ciphers: "HIGH; include /etc/passwd".into(),
+                    ..Default::default()
+                }),
+                ..tls
+            }),
+            ..conf::AppConfig::default()
+        }));
+        for option in [
+            "proxy_ssl_protocols=TLSv1.2;include",
+            "sub_filter_types=text/css;include",
+        ] {
+            let rp = conf::ReverseProxyMapping::parse(&format!("/app:http://app:3000?{}", option))
+                .unwrap();
+            assert!(rp.validate().is_err(), "{}", option);
+        }
+        let mut rp = conf::ReverseProxyMapping::parse("/app:http://app:3000").unwrap();
+        rp.js_content = Some("main.hello; include /etc/passwd".into());
+        assert!(rp.validate().is_err());
+        rp.js_content = None;
+        rp.js_header_filter = Some("main.strip; include /etc/passwd".into());
+        assert!(rp.validate().is_err());
+    }
+
+    #[test]
+    fn render_server_proxy_protocol() {
+        let app_config = conf::AppConfig {
+            proxy_protocol: true,
+            real_ip_from: vec!["10.0.0.0/8".into()],
+            ..conf::AppConfig::default()
+        };
+        let rendered = render_server(&app_config, None, &[], true);
+        assert!(rendered.contains("    listen 0.0.0.0:10080 proxy_protocol;\n"));
+        assert!(rendered
+            .contains("    set_real_ip_from 10.0.0.0/8;\n    real_ip_header proxy_protocol;\n"));
+
+        let app_config = conf::AppConfig {
+            proxy_protocol: false,
+            ..app_config
+        };
+        let rendered = render_server(&app_config, None, &[], true);
+        assert!(rendered.contains("    listen 0.0.0.0:10080;\n"));
+        assert!(rendered.contains("    real_ip_header X-Forwarded-For;\n"));
+    }
+
+    #[test]
+    fn render_nginx_conf_listen_options_once() {
+        let app_config = conf::AppConfig {
+            listen: conf::ListenOptions {
+                backlog: Some(4096),
+                reuseport: true,
+                so_keepalive: Some("30m::10".into()),
+            },
+            subdomains: vec![
+                conf::parse_subdomain_mapping("a.example.com->http://a:3000").unwrap(),
+                conf::parse_subdomain_mapping("b.example.com->http://b:3000").unwrap(),
+            ],
+            ..conf::AppConfig::default()
+        };
+        let rendered = render_nginx_conf(&app_config);
+        assert!(rendered.contains(
+            "    listen 0.0.0.0:10080 backlog=4096 reuseport so_keepalive=30m::10;\n    server_name a.example.com;\n"
+        ));
+        assert!(rendered.contains("    listen 0.0.0.0:10080;\n    server_name b.example.com;\n"));
+    }
+
+    #[test]
+    fn render_nginx_conf_server_timeouts() {
+        let app_config = conf::AppConfig {
+            timeouts: conf::ServerTimeouts {
+                keepalive_timeout: Some("30s".into()),
+                client_body_timeout: Some("1m".into()),
+                ..Default::default()
+            },
+            reverse_proxy: vec![conf::ReverseProxyMapping::parse("/:http://app:3000").unwrap()],
+            subdomains: vec![
+                conf::parse_subdomain_mapping("a.example.com->http://a:3000").unwrap(),
+            ],
+            ..conf::AppConfig::default()
+        };
+        app_config.validate().unwrap();
+        let rendered = render_nginx_conf(&app_config);
+        assert_eq!(
+            2,
+            rendered
+                .matches("    keepalive_timeout 30s;\n    client_body_timeout 1m;\n")
+                .count()
+        );
+        assert!(!rendered.contains("send_timeout"));
+
+        let app_config = conf::AppConfig {
+            timeouts: conf::ServerTimeouts {
+                send_timeout: Some("10s; deny all".into()),
+                ..Default::default()
+            },
+            ..conf::AppConfig::default()
+        };
+        assert!(app_config.validate().is_err());
+    }
+
+    #[test]
+    fn render_nginx_conf_performance() {
+        let mut app_config = conf::AppConfig {
+            reverse_proxy: vec![conf::ReverseProxyMapping::parse("/:http://app:3000").unwrap()],
+            ..conf::AppConfig::default()
+        };
+        assert!(!render_nginx_conf(&app_config).contains("sendfile"));
+        app_config.performance = true;
+        let rendered = render_nginx_conf(&app_config);
+        assert!(rendered.contains("    sendfile on;\n    tcp_nopush on;\n    tcp_nodelay on;\n"));
+        assert!(rendered.contains("    open_file_cache max=10000 inactive=60s;\n"));
+    }
+
+    #[test]
+    fn render_nginx_conf_charset() {
+        let app_config = conf::AppConfig {
+            reverse_proxy: vec![conf::ReverseProxyMapping::parse("/:http://app:3000").unwrap()],
+            charset: Some("utf-8".into()),
+            default_type: Some("application/json".into()),
+            ..conf::AppConfig::default()
+        };
+        app_config.validate().unwrap();
+        let rendered = render_nginx_conf(&app_config);
+        assert!(rendered.contains("    charset utf-8;\n"));
+        assert!(rendered.contains(" application/rss+xml application/json;\n"));
+        assert!(rendered.contains("    default_type application/json;\n"));
+
+        let app_config = conf::AppConfig {
+            charset: Some("utf-8; deny all".into()),
+            ..conf::AppConfig::default()
+        };
+        assert!(app_config.validate().is_err());
+    }
+
+    #[test]
+    fn render_nginx_conf_deny() {
+        let app_config = conf::AppConfig {
+            reverse_proxy: vec![conf::ReverseProxyMapping::parse("/:http://app:3000").unwrap()],
+            deny: conf::DenyPaths {
+                hidden: true,
+                backups: true,
+                ..Default::default()
+            },
+            ..conf::AppConfig::default()
+        };
+        let rendered = render_nginx_conf(&app_config);
+        assert!(
+            rendered.contains("\n    location ~ /\\.(?!well-known) {\n        deny all;\n    }\n")
+        );
+        assert!(rendered.contains("\n    location ~ (~|\\.(bak|old|orig|save|swp))$ {\n"));
+        assert!(!rendered.contains(".git"));
+        assert_eq!("", render_deny_locations(&conf::DenyPaths::default()));
+    }
+
+    #[test]
+    fn render_nginx_conf_syslog() {
+        let app_config = conf::AppConfig {
+            reverse_proxy: vec![conf::ReverseProxyMapping::parse("/:http://app:3000").unwrap()],
+            log: conf::LogConfig {
+                access_log: Some("syslog:server=10.0.0.1:514,tag=nginx".into()),
+                error_log: Some("syslog:server=unix:/dev/log".into()),
+                error_log_level: Some("warn".into()),
+                ..Default::default()
+            },
+            ..conf::AppConfig::default()
+        };
+        app_config.validate().unwrap();
+        let rendered = render_nginx_conf(&app_config);
+        assert!(rendered.contains("    access_log syslog:server=10.0.0.1:514,tag=nginx;\n"));
+        assert!(rendered.contains("    error_log syslog:server=unix:/dev/log warn;\n"));
+
+        let mut app_config = app_config;
+        app_config.log.error_log_level = Some("verbose".into());
+        assert!(app_config.validate().is_err());
+        app_config.log.error_log_level = None;
+        app_config.log.access_log = Some("syslog:10.0.0.1".into());
+        assert!(app_config.validate().is_err());
+    }
+
+    #[test]
+    fn render_nginx_conf_log_condition() {
+        let mut app_config = conf::AppConfig {
+            reverse_proxy: vec![conf::ReverseProxyMapping::parse("/:http://app:3000").unwrap()],
+            log: conf::LogConfig {
+                skip_status: vec!["2xx".into(), "304".into()],
+                ..Default::default()
+            },
+            ..conf::AppConfig::default()
+        };
+        app_config.validate().unwrap();
+        let rendered = render_nginx_conf(&app_config);
+        assert!(rendered.contains(
+            "\nmap $status $rp_log_status {\n    ~^2 0;\n    304 0;\n    default 1;\n}\n"
+        ));
+        assert!(
+            rendered.contains("\nmap $rp_log_status $rp_loggable {\n    0 0;\n    default 1;\n}\n")
+        );
+        assert!(rendered
+            .contains("    access_log /var/log/nginx/access.log combined if=$rp_loggable;\n"));
+
+        app_config.log.slower_than = Some(1.25);
+        let rendered = render_nginx_conf(&app_config);
+        assert!(rendered.contains(
+            "\nmap $request_time $rp_log_slow {\n    \"~^([1-9]\\\\d{1,}|2|[3-9])\\\\.|^1\\\\.(250|[3-9]\\\\d{2}|2[6-9]\\\\d{1}|25[1-9])$\" 1;\n    default 0;\n}\n"
+        ));
+        assert!(rendered.contains("\nmap $rp_log_status$rp_log_slow $rp_loggable {\n    00 0;\n"));
+
+        app_config.log.skip_status = vec!["2x".into()];
+        assert!(app_config.validate().is_err());
+        assert_eq!(vec!["09", "[1-9]\\d{1}"], digits_at_least("09"));
+    }
+
+    #[test]
+    fn render_nginx_conf_log_formats() {
+        let mut formats = std::collections::BTreeMap::new();
+        formats.insert(
+            "timing".to_owned(),
+            conf::LogFormat::Variables(vec![
+                "$remote_addr".into(),
+                "request".into(),
+                "$request_time".into(),
+            ]),
+        );
+        formats.insert(
+            "upstream".to_owned(),
+            conf::LogFormat::Raw("$upstream_addr $upstream_status".into()),
+        );
+        let mut app_config = conf::AppConfig {
+            reverse_proxy: vec![
+                conf::ReverseProxyMapping::parse("/:http://app:3000").unwrap(),
+                conf::ReverseProxyMapping::parse("/api:http://api:3000?log_format=upstream")
+                    .unwrap(),
+            ],
+            log: conf::LogConfig {
+                formats,
+                format: Some("timing".into()),
+                ..Default::default()
+            },
+            ..conf::AppConfig::default()
+        };
+        app_config.validate().unwrap();
+        let rendered = render_nginx_conf(&app_config);
+        assert!(rendered.contains("log_format timing \"$remote_addr $request $request_time\";\n"));
+        assert!(rendered.contains("log_format upstream \"$upstream_addr $upstream_status\";\n"));
+        assert!(rendered.contains("    access_log /var/log/nginx/access.log timing;\n"));
+        assert!(rendered.contains(
+            "        proxy_pass http://api:3000/;\n        access_log /var/log/nginx/access.log upstream;\n"
+        ));
+
+        app_config.reverse_proxy[1].log_format = Some("json".into());
+        assert!(app_config.validate().is_err());
+    }
+
+    #[test]
+    fn render_nginx_conf_full_conf() {
+        let app_config = conf::AppConfig {
+            full_conf: true,
+            workers: conf::Workers {
+                processes: 4,
+                connections: 4096,
+                rlimit_nofile: 8192,
+                multi_accept: true,
+            },
+            ..conf::AppConfig::default()
+        };
+        let rendered = render_nginx_conf(&app_config);
+        assert!(
+            rendered.starts_with("user nginx;\nworker_processes 4;\nworker_rlimit_nofile 8192;\n")
+        );
+        assert!(
+            rendered.contains("events {\n    worker_connections 4096;\n    multi_accept on;\n}\n")
+        );
+        assert!(rendered.contains("\nserver {\n    listen 0.0.0.0:10080;\n"));
+        assert!(rendered.ends_with("}\n}\n"));
+    }
+
+    #[test]
+    fn replace_managed_region_keeps_the_rest() {
+        let conf = "server_tokens off;\n# BEGIN docker-rp\nold\n# END docker-rp\n# handwritten\n";
+        assert_eq!(Some("old\n"), managed_region(conf));
+        assert_eq!(
+            "server_tokens off;\n# BEGIN docker-rp\nnew\n# END docker-rp\n# handwritten\n",
+            replace_managed_region(conf, "new\n")
+        );
+        assert_eq!(None, managed_region("server_tokens off;"));
+        assert_eq!(
+            "server_tokens off;\n# BEGIN docker-rp\nnew\n# END docker-rp\n",
+            replace_managed_region("server_tokens off;", "new\n")
+        );
+    }
+
+    #[test]
+    fn render_source_map_of_locations() {
+        use structopt::StructOpt;
+        let cli_args: &[&str] = &[
+            "test",
+            "--config-dir",
+            "./tests/conf_subdomains_dir",
+            "-r",
+            "/admin:http://admin:3000/",
+        ];
+        let args = conf::Args::from_iter_safe(cli_args.iter()).unwrap();
+        let app_config = conf::AppConfig::from_args_and_config(args).unwrap();
+        let map = render_source_map(&app_config);
+        assert_eq!("example.com", map["servers"][0]["server_name"]);
+        assert_eq!("/admin", map["servers"][0]["locations"][0]["path"]);
+        assert_eq!("command line", map["servers"][0]["locations"][0]["source"]);
+        assert_eq!("api.example.com", map["servers"][1]["server_name"]);
+        assert!(map["servers"][1]["source"]
+            .as_str()
+            .unwrap()
+            .ends_with("conf_subdomains_dir/conf.yaml"));
+        assert_eq!(
+            PathBuf::from("/etc/nginx/conf.d/default.conf.map.json"),
+            source_map_path(Path::new("/etc/nginx/conf.d/default.conf"))
+        );
+    }
+
+    #[test]
+    fn render_model_as_json_and_yaml() {
+        let app_config = conf::AppConfig {
+            reverse_proxy: vec![conf::ReverseProxyMapping::parse(
+                "/api:http://api:3000/?websocket",
+            )
+            .unwrap()],
+            ..conf::AppConfig::default()
+        };
+        let model = render_model(&app_config);
+        assert_eq!("localhost", model["servers"][0]["server_name"]);
+        assert_eq!("/api", model["servers"][0]["locations"][0]["path"]);
+        assert_eq!(
+            "http://api:3000/",
+            model["servers"][0]["locations"][0]["url"]
+        );
+        assert_eq!(true, model["servers"][0]["locations"][0]["websocket"]);
+        assert_eq!(10080, model["listen"]["port"]);
+
+        let json = format_model(&model, Path::new("model.json")).unwrap();
+        assert_eq!(
+            model,
+            serde_json::from_str::<serde_json::Value>(&json).unwrap()
+        );
+        let yaml = format_model(&model, Path::new("model.yaml")).unwrap();
+        assert!(yaml.contains("server_name: localhost\n"));
+    }
+
+    #[test]
+    fn apply_tf_external_query() {
+        use structopt::StructOpt;
+
+        let mut args = conf::Args::from_iter_safe(&["rp"]).unwrap();
+        let query = vec![
+            ("domain", "example.com"),
+            ("port", "8080"),
+            ("reverse_proxy", "/a:http://a:3000\n/b:http://b:3000\n"),
+        ]
+        .into_iter()
+        .map(|(key, value)| (key.to_owned(), value.to_owned()))
+        .collect();
+        args.apply_query(query).unwrap();
+        assert_eq!(args.domain.as_deref(), Some("example.com"));
+        assert_eq!(args.port, Some(8080));
+        assert_eq!(args.reverse_proxy.len(), 2);
+        let unknown = vec![("nginx".to_owned(), "x".to_owned())]
+            .into_iter()
+            .collect();
+        assert!(args.apply_query(unknown).is_err());
+        assert_eq!(
+            render_tf_external("")["sha256"],
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+    }
+
+    #[test]
+    fn render_catch_all_default_domain() {
+        let mut app_config = conf::AppConfig {
+            reverse_proxy: vec![conf::ReverseProxyMapping::parse("/:http://web:3000").unwrap()],
+            ..Default::default()
+        };
+        assert!(render_nginx_conf(&app_config).contains("    server_name localhost;\n"));
+        app_config.default_domain = "_".into();
+        let conf = render_nginx_conf(&app_config);
+        assert!(conf.contains("    listen 0.0.0.0:10080 default_server;\n    server_name _;\n"));
+        app_config.domain = Some("example.com".into());
+        assert!(!render_nginx_conf(&app_config).contains("default_server"));
+    }
+
+    #[test]
+    fn first_difference_of_lines() {
+        assert_eq!(None, first_difference("a\nb\n", "a\nb\n"));
+        assert_eq!(
+            Some("line 2: -listen 80; +listen 8080;".to_owned()),
+            first_difference(
+                "server {\n    listen 80;\n}\n",
+                "server {\n    listen 8080;\n}\n"
+            )
+        );
+        assert_eq!(
+            Some("line 2: -b +(end)".to_owned()),
+            first_difference("a\nb\n", "a\n")
+        );
+        assert!(first_difference("a\n", "a").is_some());
+    }
+
+    #[test]
+    fn list_routing_table() {
+        let mut app_config = conf::AppConfig {
+            domain: Some("example.com".into()),
+            ..Default::default()
+        };
+        let mut rp =
+            conf::ReverseProxyMapping::parse("/ws:http://app:3000?websocket&timeout=300").unwrap();
+        rp.source = Some("conf/sites/app.yaml".into());
+        app_config.reverse_proxy = vec![
+            conf::ReverseProxyMapping::parse("/:http://web:3000").unwrap(),
+            rp,
+        ];
+        let routes = routing_table(&app_config);
+        assert_eq!(2, routes.len());
+        assert_eq!(serde_json::json!(300), routes[1].options["timeout"]);
+        assert_eq!(
+            "DOMAIN       PATH  UPSTREAM          OPTIONS                SOURCE\n\
+             example.com  /     http://web:3000/  -                      -\n\
+             example.com  /ws   http://app:3000/  timeout=300,websocket  conf/sites/app.yaml\n",
+            format_routing_table(&routes)
+        );
+    }
+
+    #[test]
+    fn render_configmap_manifest() {
+        let manifest =
+            render_configmap("server {\n}\n", "rp", Some("edge"), "default.conf").unwrap();
+        assert!(manifest.starts_with("apiVersion: v1\n"));
+        assert!(manifest.contains("kind: ConfigMap\n"));
+        assert!(manifest.contains("  name: rp\n  namespace: edge\n"));
+        assert!(manifest.contains("  default.conf: |\n    server {\n    }\n"));
+        assert!(!render_configmap("", "rp", None, "default.conf")
+            .unwrap()
+            .contains("namespace"));
+    }
+
+    #[test]
+    fn render_compose_services() {
+        let mut tls =
+            conf::TlsConfig::new(cert::dev_cert_paths("app.localhost", Path::new("/certs")));
+        tls.client_certificate = Some("/certs/ca/ca.pem".into());
+        let app_config = conf::AppConfig {
+            port: 8443,
+            nginx_conf: "/etc/nginx/conf.d/rp.conf".into(),
+            tls: Some(tls),
+            ..Default::default()
+        };
+        let compose = render_compose(&app_config, Path::new("./rp")).unwrap();
+        let compose: serde_json::Value = serde_yaml::from_str(&compose).unwrap();
+        let nginx = &compose["services"]["nginx"];
+        assert_eq!(nginx["ports"], serde_json::json!(["8443:8443"]));
+        assert_eq!(
+            nginx["volumes"],
+            serde_json::json!(["nginx-conf:/etc/nginx/conf.d:ro", "/certs:/certs:ro"])
+        );
+        let rp = &compose["services"]["rp"];
+        assert_eq!(rp["command"][3], "/etc/nginx/conf.d/rp.conf");
+        assert_eq!(rp["volumes"][0], "./rp:/conf:ro");
+        assert!(compose["volumes"]["nginx-conf"].is_object());
+    }
+
+    #[test]
+    fn render_location_allowed_methods() {
+        let rp =
+            conf::ReverseProxyMapping::parse("/docs:http://docs:3000/?allowed_methods=get,options")
+                .unwrap();
+        rp.validate().unwrap();
+        let rendered = render_location(&conf::AppConfig::default(), &rp);
+        assert!(rendered
+            .contains("        if ($request_method !~ ^(GET|OPTIONS|HEAD)$) { return 405; }\n"));
+
+        let mut rp = conf::ReverseProxyMapping::parse("/docs:http://docs:3000/").unwrap();
+        rp.allowed_methods = vec!["GET|POST".into()];
+        assert!(rp.validate().is_err());
+    }
+
+    #[test]
+    fn render_nginx_conf_client_certificate() {
+        let mut tls =
+            conf::TlsConfig::new(cert::dev_cert_paths("app.localhost", Path::new("/certs")));
+        tls.client_certificate = Some(PathBuf::from("/certs/clients-ca.crt"));
+        tls.verify_client = Some(conf::VerifyClient::Optional);
+        let mut rp = conf::ReverseProxyMapping::parse("/hook:http://app:3000/hook").unwrap();
+        rp.require_client_certificate = true;
+        let app_config = conf::AppConfig {
+            tls: Some(tls),
+            reverse_proxy: vec![rp],
+            ..Default::default()
+        };
+        app_config.validate().unwrap();
+        let rendered = render_nginx_conf(&app_config);
+        assert!(rendered.contains("    ssl_client_certificate /certs/clients-ca.crt;\n"));
+        assert!(rendered.contains("    ssl_verify_client optional;\n"));
+        assert!(rendered.contains("        if ($ssl_client_verify != SUCCESS) { return 403; }\n"));
+    }
+
+    #[test]
+    fn validate_client_certificate_without_ca() {
+        let mut rp = conf::ReverseProxyMapping::parse("/hook:http://app:3000/hook").unwrap();
+        rp.require_client_certificate = true;
+        let app_config = conf::AppConfig {
+            reverse_proxy: vec![rp],
+            ..Default::default()
+        };
+        assert!(matches!(
+            app_config.validate(),
+            Err(error::Error::Render(_))
+        ));
+    }
+
+    #[test]
+    fn validate_requires_certificate_key_pair() {
+        let mut rp = conf::ReverseProxyMapping::parse("/api:https://backend:8443/api").unwrap();
+        rp.proxy_ssl_certificate = Some(PathBuf::from("/certs/client.crt"));
+        assert!(rp.validate().is_err());
+    }
+}
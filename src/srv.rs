@@ -0,0 +1,245 @@
+use crate::conf::{Upstream, UpstreamServer};
+use crate::error::{Error, Result};
+use std::fs;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const TYPE_SRV: u16 = 33;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    pub priority: u16,
+    pub weight: u16,
+    pub port: u16,
+    pub target: String,
+}
+
+/// The first `nameserver` of `/etc/resolv.conf`, as docker sets it up.
+fn nameserver() -> SocketAddr {
+    fs::read_to_string("/etc/resolv.conf")
+        .ok()
+        .and_then(|resolv| {
+            resolv.lines().find_map(|line| {
+                let mut words = line.split_whitespace();
+                match (words.next(), words.next()) {
+                    (Some("nameserver"), Some(address)) => address.parse().ok(),
+                    _ => None,
+                }
+            })
+        })
+        .map(|ip| SocketAddr::new(ip, 53))
+        .unwrap_or_else(|| ([127, 0, 0, 1], 53).into())
+}
+
+fn query(id: u16, name: &str) -> Vec<u8> {
+    // recursion desired, one question
+    let mut packet = vec![0; 12];
+    packet[..2].copy_from_slice(&id.to_be_bytes());
+    packet[2] = 0x01;
+    packet[5] = 1;
+    for label in name.trim_end_matches('.').split('.') {
+        packet.push(label.len() as u8);
+        packet.extend_from_slice(label.as_bytes());
+    }
+    packet.push(0);
+    packet.extend_from_slice(&TYPE_SRV.to_be_bytes());
+    packet.extend_from_slice(&1u16.to_be_bytes());
+    packet
+}
+
+fn invalid(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, what.to_owned())
+}
+
+fn u16_at(packet: &[u8], pos: usize) -> io::Result<u16> {
+    packet
+        .get(pos..pos + 2)
+        .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+        .ok_or_else(|| invalid("truncated answer"))
+}
+
+/// A possibly compressed name at `pos`, and where it ends.
+fn name_at(packet: &[u8], mut pos: usize) -> io::Result<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    // a pointer per label at most, so that a loop of pointers ends
+    for _ in 0..packet.len() {
+        let len = *packet.get(pos).ok_or_else(|| invalid("truncated name"))? as usize;
+        if len == 0 {
+            return Ok((labels.join("."), end.unwrap_or(pos + 1)));
+        }
+        if len & 0xc0 == 0xc0 {
+            end.get_or_insert(pos + 2);
+            pos = (u16_at(packet, pos)? & 0x3fff) as usize;
+            continue;
+        }
+        let label = packet
+            .get(pos + 1..pos + 1 + len)
+            .ok_or_else(|| invalid("truncated name"))?;
+        labels.push(String::from_utf8_lossy(label).into_owned());
+        pos += 1 + len;
+    }
+    Err(invalid("a loop of name pointers"))
+}
+
+fn parse(id: u16, packet: &[u8]) -> io::Result<Vec<Record>> {
+    if u16_at(packet, 0)? != id {
+        return Err(invalid("an answer to another query"));
+    }
+    let flags = u16_at(packet, 2)?;
+    if flags & 0x0200 != 0 {
+        return Err(invalid("truncated answer, too many records for UDP"));
+    }
+    match flags & 0x000f {
+        0 => {}
+        3 => return Err(io::Error::new(io::ErrorKind::NotFound, "no such name")),
+        rcode => return Err(invalid(&format!("DNS error {}", rcode))),
+    }
+    let mut pos = 12;
+    for _ in 0..u16_at(packet, 4)? {
+        pos = name_at(packet, pos)?.1 + 4;
+    }
+    let mut records = Vec::new();
+    for _ in 0..u16_at(packet, 6)? {
+        pos = name_at(packet, pos)?.1;
+        let rtype = u16_at(packet, pos)?;
+        let rdlength = u16_at(packet, pos + 8)? as usize;
+        let rdata = pos + 10;
+        if rtype == TYPE_SRV {
+            records.push(Record {
+                priority: u16_at(packet, rdata)?,
+                weight: u16_at(packet, rdata + 2)?,
+                port: u16_at(packet, rdata + 4)?,
+                target: name_at(packet, rdata + 6)?.0,
+            });
+        }
+        pos = rdata + rdlength;
+    }
+    Ok(records)
+}
+
+/// The SRV records of `name` from the nameserver of `/etc/resolv.conf`.
+pub fn lookup(name: &str) -> Result<Vec<Record>> {
+    let context = format!("SRV {}", name);
+    let lookup = || -> io::Result<Vec<Record>> {
+        let socket = UdpSocket::bind(("0.0.0.0", 0))?;
+        socket.set_read_timeout(Some(Duration::from_secs(5)))?;
+        socket.connect(nameserver())?;
+        let id = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.subsec_nanos() as u16)
+            .unwrap_or(0);
+        socket.send(&query(id, name))?;
+        let mut packet = [0; 4096];
+        let len = socket.recv(&mut packet)?;
+        parse(id, &packet[..len])
+    };
+    let records = lookup().map_err(|err| Error::io(&context, err))?;
+    if records.is_empty() {
+        return Err(Error::io(
+            &context,
+            io::Error::new(io::ErrorKind::NotFound, "no records"),
+        ));
+    }
+    Ok(records)
+}
+
+/// `srv_` and the labels of `name`, eg. `srv_http_tcp_api_service_consul`.
+pub fn upstream_name(name: &str) -> String {
+    let labels: Vec<&str> = name
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|label| !label.is_empty())
+        .collect();
+    format!("srv_{}", labels.join("_"))
+}
+
+/// An upstream of the records of the lowest priority, which are the ones to use,
+/// weighted as they are.
+pub fn upstream(name: &str, records: &[Record]) -> Upstream {
+    let priority = records.iter().map(|r| r.priority).min().unwrap_or(0);
+    Upstream {
+        name: upstream_name(name),
+        servers: records
+            .iter()
+            .filter(|r| r.priority == priority)
+            .map(|r| UpstreamServer {
+                address: format!("{}:{}", r.target.trim_end_matches('.'), r.port),
+                weight: Some(u32::from(r.weight.max(1))),
+            })
+            .collect(),
+        sticky: None,
+        sticky_cookie: None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// An answer to `query(7, "_http._tcp.api")` with two records, the second target
+    /// compressed to point into the first.
+    fn answer() -> Vec<u8> {
+        let mut packet = query(7, "_http._tcp.api");
+        packet[2] = 0x81;
+        packet[3] = 0x80;
+        packet[7] = 2;
+        let mut record = |priority: u16, weight: u16, port: u16, target: &[u8]| {
+            packet.extend_from_slice(&[0xc0, 12]);
+            packet.extend_from_slice(&TYPE_SRV.to_be_bytes());
+            packet.extend_from_slice(&[0, 1, 0, 0, 0, 60]);
+            packet.extend_from_slice(&((6 + target.len()) as u16).to_be_bytes());
+            for n in &[priority, weight, port] {
+                packet.extend_from_slice(&n.to_be_bytes());
+            }
+            packet.extend_from_slice(target);
+        };
+        record(10, 5, 8080, b"\x04api1\x04node\x00");
+        let pointer = 32 + 2 + 10 + 6 + 5;
+        record(20, 0, 8081, &[4, b'a', b'p', b'i', b'2', 0xc0, pointer]);
+        packet
+    }
+
+    #[test]
+    fn parse_srv_answer() {
+        let records = parse(7, &answer()).unwrap();
+        assert_eq!(
+            vec![
+                Record {
+                    priority: 10,
+                    weight: 5,
+                    port: 8080,
+                    target: "api1.node".into(),
+                },
+                Record {
+                    priority: 20,
+                    weight: 0,
+                    port: 8081,
+                    target: "api2.node".into(),
+                },
+            ],
+            records
+        );
+        assert!(parse(8, &answer()).is_err());
+        let mut nxdomain = answer();
+        nxdomain[3] = 0x83;
+        assert_eq!(
+            io::ErrorKind::NotFound,
+            parse(7, &nxdomain).unwrap_err().kind()
+        );
+    }
+
+    #[test]
+    fn upstream_of_lowest_priority() {
+        let records = parse(7, &answer()).unwrap();
+        let upstream = upstream("_http._tcp.api", &records);
+        assert_eq!("srv_http_tcp_api", upstream.name);
+        assert_eq!(
+            vec![UpstreamServer {
+                address: "api1.node:8080".into(),
+                weight: Some(5),
+            }],
+            upstream.servers
+        );
+    }
+}
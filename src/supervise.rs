@@ -0,0 +1,279 @@
+use crate::error::{Error, Result};
+use glob::glob;
+use std::fs;
+use std::io;
+use std::io::{BufRead, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus};
+use std::sync::atomic::{AtomicI32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+static SIGNAL: AtomicI32 = AtomicI32::new(0);
+
+extern "C" fn record(signal: libc::c_int) {
+    SIGNAL.store(signal, Ordering::SeqCst);
+}
+
+/// Keeps SIGTERM, SIGINT, SIGQUIT and SIGHUP for `take_signal` instead of dying of them.
+pub fn install_signal_handlers() {
+    for &signal in &[libc::SIGTERM, libc::SIGINT, libc::SIGQUIT, libc::SIGHUP] {
+        unsafe { libc::signal(signal, record as *const () as libc::sighandler_t) };
+    }
+}
+
+/// The last signal received since the previous call.
+pub fn take_signal() -> Option<i32> {
+    match SIGNAL.swap(0, Ordering::SeqCst) {
+        0 => None,
+        signal => Some(signal),
+    }
+}
+
+/// `nginx -g 'daemon off;'`, staying in the foreground as our child.
+pub fn spawn_nginx(nginx: &str) -> Result<Child> {
+    Command::new(nginx)
+        .args(["-g", "daemon off;"])
+        .spawn()
+        .map_err(|err| Error::io(nginx, err))
+}
+
+pub fn send(child: &Child, signal: i32) -> Result<()> {
+    let ret = unsafe { libc::kill(child.id() as libc::pid_t, signal) };
+    if ret != 0 {
+        return Err(Error::io(
+            format!("Failed to signal pid {}", child.id()),
+            io::Error::last_os_error(),
+        ));
+    }
+    Ok(())
+}
+
+/// As a shell reports it: the exit code, or 128 + the signal which killed it.
+pub fn exit_code(status: ExitStatus) -> i32 {
+    status
+        .code()
+        .unwrap_or_else(|| 128 + status.signal().unwrap_or(0))
+}
+
+/// Counters of `run`, served as `/metrics` by `serve_admin`, and how the last cycle went
+/// for `/healthz`.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    pub renders: AtomicU64,
+    pub render_errors: AtomicU64,
+    pub reloads: AtomicU64,
+    pub reload_failures: AtomicU64,
+    /// Unix time of the last render which was written out and reloaded if it had to be
+    pub last_success: AtomicU64,
+    pub last_reload: AtomicU64,
+    /// Why the last render and reload failed, `None` once one succeeded
+    pub last_error: Mutex<Option<String>>,
+}
+
+impl Metrics {
+    pub fn count(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn stamp(timestamp: &AtomicU64) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        timestamp.store(now, Ordering::Relaxed);
+    }
+
+    pub fn set_last_error(&self, error: Option<String>) {
+        *self
+            .last_error
+            .lock()
+            .unwrap_or_else(|err| err.into_inner()) = error;
+    }
+
+    /// The Prometheus text format.
+    pub fn render(&self) -> String {
+        let metrics: &[(&str, &str, &str, &AtomicU64)] = &[
+            (
+                "rp_renders_total",
+                "counter",
+                "Renders of the config",
+                &self.renders,
+            ),
+            (
+                "rp_render_errors_total",
+                "counter",
+                "Renders which failed to load the config",
+                &self.render_errors,
+            ),
+            (
+                "rp_reloads_total",
+                "counter",
+                "Reloads of nginx",
+                &self.reloads,
+            ),
+            (
+                "rp_reload_failures_total",
+                "counter",
+                "Reloads which failed and were rolled back",
+                &self.reload_failures,
+            ),
+            (
+                "rp_last_success_timestamp_seconds",
+                "gauge",
+                "Unix time of the last successful render",
+                &self.last_success,
+            ),
+            (
+                "rp_last_reload_timestamp_seconds",
+                "gauge",
+                "Unix time of the last reload",
+                &self.last_reload,
+            ),
+        ];
+        let mut text = String::new();
+        for (name, kind, help, value) in metrics {
+            text.push_str(&format!(
+                "# HELP {0} {1}\n# TYPE {0} {2}\n{0} {3}\n",
+                name,
+                help,
+                kind,
+                value.load(Ordering::Relaxed)
+            ));
+        }
+        text
+    }
+}
+
+/// Serves `GET /metrics` and `GET /healthz` on `addr` from a thread,
+/// returning the address bound.
+pub fn serve_admin(addr: &str, metrics: Arc<Metrics>) -> Result<SocketAddr> {
+    let listener = TcpListener::bind(addr).map_err(|err| Error::io(addr, err))?;
+    let local_addr = listener.local_addr().map_err(|err| Error::io(addr, err))?;
+    thread::spawn(move || {
+        for stream in listener.incoming().filter_map(|stream| stream.ok()) {
+            if let Err(err) = answer(stream, &metrics) {
+                debug!("admin: {}", err);
+            }
+        }
+    });
+    Ok(local_addr)
+}
+
+fn answer(stream: TcpStream, metrics: &Metrics) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut request_line = String::new();
+    io::BufReader::new(&stream).read_line(&mut request_line)?;
+    let path = request_line.split_whitespace().nth(1).unwrap_or("");
+    let (status, content_type, body) = match path {
+        "/metrics" => ("200 OK", "text/plain; version=0.0.4", metrics.render()),
+        "/healthz" => match *metrics
+            .last_error
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+        {
+            None => ("200 OK", "text/plain", "ok\n".to_owned()),
+            Some(ref error) => (
+                "503 Service Unavailable",
+                "text/plain",
+                format!("{}\n", error),
+            ),
+        },
+        _ => ("404 Not Found", "text/plain", "not found\n".to_owned()),
+    };
+    let response = format!(
+        "HTTP/1.0 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    (&stream).write_all(response.as_bytes())
+}
+
+/// Modification times of the files in `config_dir` and of `config_files`,
+/// which differ once any of them is edited, added or removed.
+pub fn input_stamp(
+    config_dir: &Path,
+    config_files: &[PathBuf],
+) -> Vec<(PathBuf, Option<SystemTime>)> {
+    let mut paths: Vec<PathBuf> = glob(&format!("{}/**/*", config_dir.display()))
+        .map(|paths| paths.filter_map(|path| path.ok()).collect())
+        .unwrap_or_default();
+    for file in config_files {
+        if !paths.contains(file) {
+            paths.push(file.clone());
+        }
+    }
+    files_stamp(&paths)
+}
+
+/// Modification times of `paths`, eg. of certificates replaced by a renewal.
+pub fn files_stamp(paths: &[PathBuf]) -> Vec<(PathBuf, Option<SystemTime>)> {
+    paths
+        .iter()
+        .map(|path| {
+            let modified = fs::metadata(path).and_then(|m| m.modified()).ok();
+            (path.clone(), modified)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn input_stamp_changes_with_files() {
+        let dir = std::env::temp_dir().join(format!("rp-supervise-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("sites")).unwrap();
+        fs::write(dir.join("conf.yaml"), "port: 80\n").unwrap();
+        let stamp = input_stamp(&dir, &[]);
+        assert_eq!(2, stamp.len());
+        assert_eq!(stamp, input_stamp(&dir, &[]));
+        assert_eq!(stamp, input_stamp(&dir, &[dir.join("conf.yaml")]));
+        fs::write(dir.join("sites").join("app.yaml"), "").unwrap();
+        assert_ne!(stamp, input_stamp(&dir, &[]));
+
+        let certificate = dir.join("server.crt");
+        let stamp = files_stamp(std::slice::from_ref(&certificate));
+        assert_eq!(vec![(certificate.clone(), None)], stamp);
+        fs::write(&certificate, "renewed").unwrap();
+        assert_ne!(stamp, files_stamp(&[certificate]));
+    }
+
+    #[test]
+    fn admin_serves_metrics() {
+        use std::io::Read;
+        let metrics = Arc::new(Metrics::default());
+        Metrics::count(&metrics.renders);
+        let addr = serve_admin("127.0.0.1:0", metrics.clone()).unwrap();
+        let get = |path: &str| {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            write!(stream, "GET {} HTTP/1.0\r\n\r\n", path).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+        let response = get("/metrics");
+        assert!(response.starts_with("HTTP/1.0 200 OK\r\n"));
+        assert!(response.contains("\n# TYPE rp_renders_total counter\nrp_renders_total 1\n"));
+        assert!(get("/healthz").ends_with("\r\n\r\nok\n"));
+        metrics.set_last_error(Some("Failed to load config".into()));
+        let response = get("/healthz");
+        assert!(response.starts_with("HTTP/1.0 503"));
+        assert!(response.ends_with("\r\n\r\nFailed to load config\n"));
+        assert!(get("/").starts_with("HTTP/1.0 404"));
+    }
+
+    #[test]
+    fn exit_code_of_signal() {
+        let mut child = Command::new("sleep").arg("10").spawn().unwrap();
+        send(&child, libc::SIGTERM).unwrap();
+        assert_eq!(128 + libc::SIGTERM, exit_code(child.wait().unwrap()));
+    }
+}